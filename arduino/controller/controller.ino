@@ -4,6 +4,7 @@
 #include <ArduinoJson.h>
 #include <Streaming.h>
 #include <DHT22.h>
+#include <ArduinoBearSSL.h>
 
 #define SERVER_IP "35.198.240.174"
 #define SERVER_PORT 4000
@@ -13,18 +14,45 @@
 #define COOLER_PWM 3
 #define COOLER_ENABLE 4
 #define WATER_PUMP_ENABLE 5
+#define FIRMWARE_VERSION "0.2.0"
+#define PROTOCOL_VERSION_MIN 1
+#define PROTOCOL_VERSION_MAX 4
+#define CAPABILITY_CAMERA (1 << 0)
+#define CAPABILITY_COOLER (1 << 1)
+#define CAPABILITY_WATER_PUMP (1 << 2)
 
 enum class ClientPacket : uint32_t {
   ReportId = 0,
   ReportSensors,
   ImageFrame,
+  Hello,
+  ImageChunk,
+  AuthResponse,
+  Ping,
+  Pong,
+  Ack,
+  Nack,
 };
 
 enum class ServerPacket : uint32_t {
   UpdateCooler = 0,
   WaterPulse,
+  Welcome,
+  Disconnect,
+  AuthChallenge,
+  Authenticated,
+  Ping,
+  Pong,
+  Command,
 };
 
+enum class DeviceCommand : uint8_t {
+  Cooler = 0,
+  WaterPulse,
+};
+
+// Last command executed, retransmissions of it are acknowledged without running it again
+uint32_t last_command_sequence = 0;
 
 enum class NetworkStatus {
   WiFiError,
@@ -237,6 +265,7 @@ struct SaveData {
   char wifi_password[64];
   bool device;
   char device_id[64];
+  char device_secret[64];
 };
 
 const char* deviceServiceUuid = "f901b2a6-02a1-40ab-8b44-6471bd5886af";
@@ -312,22 +341,112 @@ void send_packet(ClientPacket id, uint32_t len, const uint8_t* buffer) {
   client.write(buffer, len);
 }
 
+void send_hello() {
+  BufferWriter writer;
+  writer.writeU16(PROTOCOL_VERSION_MIN);
+  writer.writeU16(PROTOCOL_VERSION_MAX);
+  writer.writeString(FIRMWARE_VERSION);
+  writer.writeU32(CAPABILITY_CAMERA | CAPABILITY_COOLER | CAPABILITY_WATER_PUMP);
+  send_packet(ClientPacket::Hello, writer.getSize(), writer.getBuffer());
+}
+
+void send_auth_response(BufferReader& reader) {
+  uint8_t nonce[32];
+  if (!reader.readBytes(nonce, sizeof(nonce))) return;
+  SHA256.beginHmac((const uint8_t*)&data.device_secret, sizeof(data.device_secret));
+  SHA256.write(nonce, sizeof(nonce));
+  SHA256.write((const uint8_t*)&data.device_id, sizeof(data.device_id));
+  SHA256.endHmac();
+  uint8_t response[32];
+  for (size_t i = 0; i < sizeof(response) && SHA256.available(); i++) {
+    response[i] = SHA256.read();
+  }
+  send_packet(ClientPacket::AuthResponse, sizeof(response), response);
+}
+
+void set_cooler(bool status) {
+  analogWrite(COOLER_PWM, 255);
+  if (status) {
+    digitalWrite(COOLER_ENABLE, HIGH);
+  } else {
+    digitalWrite(COOLER_ENABLE,  LOW);
+  }
+}
+
+void water_pulse() {
+  analogWrite(WATER_PUMP_ENABLE, HIGH);
+  delay(1500);
+  analogWrite(WATER_PUMP_ENABLE, HIGH);
+}
+
+void process_command(BufferReader& reader) {
+  uint32_t sequence = 0;
+  uint8_t kind = 0;
+  bool status = false;
+  if (!reader.readU32(sequence) || !reader.readU8(kind)) return;
+  BufferWriter writer;
+  writer.writeU32(sequence);
+  if (sequence == last_command_sequence) {
+    send_packet(ClientPacket::Ack, writer.getSize(), writer.getBuffer());
+    return;
+  }
+  switch (static_cast<DeviceCommand>(kind)) {
+    case DeviceCommand::Cooler:
+      reader.readBool(status);
+      set_cooler(status);
+      break;
+    case DeviceCommand::WaterPulse:
+      water_pulse();
+      break;
+    default:
+      writer.writeString("Unknown command");
+      send_packet(ClientPacket::Nack, writer.getSize(), writer.getBuffer());
+      return;
+  }
+  last_command_sequence = sequence;
+  send_packet(ClientPacket::Ack, writer.getSize(), writer.getBuffer());
+}
+
 void process_server_packet(ServerPacket id, BufferReader reader) {
   bool value = false;
+  uint16_t protocol_version = 0;
+  String reason;
+  uint32_t sequence = 0;
   switch (id) {
     case ServerPacket::UpdateCooler:
       reader.readBool(value);
-      analogWrite(COOLER_PWM, 255);
-      if (value) {
-        digitalWrite(COOLER_ENABLE, HIGH);
-      } else {
-        digitalWrite(COOLER_ENABLE,  LOW);
-      }
+      set_cooler(value);
       break;
     case ServerPacket::WaterPulse:
-      analogWrite(WATER_PUMP_ENABLE, HIGH);
-      delay(1500);
-      analogWrite(WATER_PUMP_ENABLE, HIGH);
+      water_pulse();
+      break;
+    case ServerPacket::Welcome:
+      reader.readU16(protocol_version);
+      last_command_sequence = 0;
+      Serial << "Server accepted protocol version " << protocol_version << endl;
+      break;
+    case ServerPacket::Disconnect:
+      reader.readString(reason);
+      Serial << "Server closed the connection: " << reason << endl;
+      client.stop();
+      break;
+    case ServerPacket::AuthChallenge:
+      send_auth_response(reader);
+      break;
+    case ServerPacket::Authenticated:
+      Serial << "Authenticated with the server" << endl;
+      break;
+    case ServerPacket::Ping: {
+      reader.readU32(sequence);
+      BufferWriter writer(4);
+      writer.writeU32(sequence);
+      send_packet(ClientPacket::Pong, writer.getSize(), writer.getBuffer());
+      break;
+    }
+    case ServerPacket::Pong:
+      break;
+    case ServerPacket::Command:
+      process_command(reader);
       break;
   }
 }
@@ -379,6 +498,7 @@ NetworkStatus setupNetwork() {
     return NetworkStatus::ServerError;
   }
 
+  send_hello();
   if (data.device) {
     BufferWriter writer;
     writer.writeBytes((const uint8_t*)&data.device_id, 64);
@@ -473,6 +593,10 @@ void loop() {
             data.device = true;
             strncpy((char*)&data.device_id, id.c_str(), sizeof(data.device_id));
             EEPROM.put(0, data);
+          } else if (request.equals("device-secret-set")) {
+            String secret = doc["device_secret"];
+            strncpy((char*)&data.device_secret, secret.c_str(), sizeof(data.device_secret));
+            EEPROM.put(0, data);
           } else if (request.equals("device-get")) {
             JsonDocument doc;
             doc["device_id"] = data.device_id;
@@ -489,6 +613,7 @@ void loop() {
     if (!client.connected()) {
       if (client.connect(SERVER_IP, SERVER_PORT)) {
         Serial << "Connection Successful" << endl;
+        send_hello();
         if (data.device) {
           BufferWriter writer;
           writer.writeBytes((const uint8_t*)&data.device_id, 64);
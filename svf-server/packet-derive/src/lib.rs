@@ -0,0 +1,204 @@
+//! `#[derive(Packet)]` for the farm device protocol
+//!
+//! Deriving `Packet` on an enum of packets generates the matching id enum, its
+//! `TryFrom<u32>` and `From<&Packet>` conversions, and a `PacketId` impl whose
+//! encoder and decoder read and write every field in declaration order through
+//! `PacketField`.
+//!
+//! ```ignore
+//! #[derive(Packet)]
+//! #[packet(id = ClientPacketId)]
+//! pub enum ClientPacket {
+//!     ReportId { id: [char; 64] },
+//!     #[packet(since = 2)]
+//!     ImageChunk { data: Vec<u8> },
+//! }
+//! ```
+//!
+//! Ids are assigned from `0` in declaration order, so new packets must be added
+//! at the end. `since` is the first protocol version carrying the packet and
+//! defaults to `1`. The generated code expects `PacketId`, `PacketField`,
+//! `PacketError`, `BufferReader` and `BufferWriter` to be in scope.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{parse_macro_input, spanned::Spanned, Data, DeriveInput, Error, Fields, Ident, LitInt};
+
+#[proc_macro_derive(Packet, attributes(packet))]
+pub fn derive_packet(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+struct Variant {
+    name: Ident,
+    fields: Vec<Ident>,
+    since: u16,
+}
+
+fn id_name(input: &DeriveInput) -> syn::Result<Ident> {
+    let mut id = None;
+    for attr in input.attrs.iter().filter(|e| e.path().is_ident("packet")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("id") {
+                id = Some(meta.value()?.parse::<Ident>()?);
+                Ok(())
+            } else {
+                Err(meta.error("expected `id = IdEnum`"))
+            }
+        })?;
+    }
+    id.ok_or_else(|| {
+        Error::new(
+            input.ident.span(),
+            "missing `#[packet(id = IdEnum)]` on the packet enum",
+        )
+    })
+}
+
+fn parse_variant(variant: &syn::Variant) -> syn::Result<Variant> {
+    let mut since = 1;
+    for attr in variant.attrs.iter().filter(|e| e.path().is_ident("packet")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("since") {
+                since = meta.value()?.parse::<LitInt>()?.base10_parse()?;
+                Ok(())
+            } else {
+                Err(meta.error("expected `since = VERSION`"))
+            }
+        })?;
+    }
+    let fields = match &variant.fields {
+        Fields::Named(fields) => fields
+            .named
+            .iter()
+            .map(|field| field.ident.clone().unwrap())
+            .collect(),
+        Fields::Unit => Vec::new(),
+        Fields::Unnamed(fields) => {
+            return Err(Error::new(
+                fields.span(),
+                "packets must use named fields or no fields",
+            ))
+        }
+    };
+    if variant.discriminant.is_some() {
+        return Err(Error::new(
+            variant.span(),
+            "packet ids are assigned in declaration order",
+        ));
+    }
+    Ok(Variant {
+        name: variant.ident.clone(),
+        fields,
+        since,
+    })
+}
+
+fn expand(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let data = match &input.data {
+        Data::Enum(data) => data,
+        _ => {
+            return Err(Error::new(
+                input.ident.span(),
+                "`Packet` can only be derived for enums",
+            ))
+        }
+    };
+    let id = id_name(&input)?;
+    let packet = &input.ident;
+    let vis = &input.vis;
+    let variants = data
+        .variants
+        .iter()
+        .map(parse_variant)
+        .collect::<syn::Result<Vec<_>>>()?;
+
+    let names = variants.iter().map(|e| &e.name).collect::<Vec<_>>();
+    let numbers = (0..variants.len() as u32).collect::<Vec<_>>();
+    let since = variants.iter().map(|e| e.since);
+    let decoders = variants.iter().map(|variant| {
+        let name = &variant.name;
+        let fields = &variant.fields;
+        if fields.is_empty() {
+            quote!(Self::#name => Ok(#packet::#name))
+        } else {
+            quote! {
+                Self::#name => Ok(#packet::#name {
+                    #(#fields: PacketField::read(&mut buffer)?,)*
+                })
+            }
+        }
+    });
+    let encoders = variants.iter().map(|variant| {
+        let name = &variant.name;
+        let fields = &variant.fields;
+        if fields.is_empty() {
+            quote!(#packet::#name => {})
+        } else {
+            quote! {
+                #packet::#name { #(#fields),* } => {
+                    #(PacketField::write(&#fields, &mut buffer);)*
+                }
+            }
+        }
+    });
+
+    Ok(quote! {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        #[repr(u32)]
+        #vis enum #id {
+            #(#names = #numbers,)*
+        }
+
+        impl TryFrom<u32> for #id {
+            type Error = PacketError;
+
+            fn try_from(value: u32) -> Result<Self, PacketError> {
+                match value {
+                    #(#numbers => Ok(Self::#names),)*
+                    _ => Err(PacketError::InvalidPacketId),
+                }
+            }
+        }
+
+        impl From<&#packet> for #id {
+            fn from(value: &#packet) -> Self {
+                match value {
+                    #(#packet::#names { .. } => Self::#names,)*
+                }
+            }
+        }
+
+        impl PacketId for #id {
+            type Packet = #packet;
+
+            #[allow(unused_mut)]
+            fn decode(&self, mut buffer: BufferReader) -> Result<#packet, PacketError> {
+                match self {
+                    #(#decoders,)*
+                }
+            }
+
+            #[allow(unused_mut)]
+            fn encode(mut buffer: BufferWriter, packet: #packet) {
+                match packet {
+                    #(#encoders)*
+                }
+            }
+
+            fn id(&self) -> u32 {
+                *self as u32
+            }
+
+            fn since_version(&self) -> u16 {
+                match self {
+                    #(Self::#names => #since,)*
+                }
+            }
+        }
+    })
+}
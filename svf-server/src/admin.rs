@@ -0,0 +1,370 @@
+use std::{collections::HashMap, path::PathBuf, sync::Arc};
+
+use axum::{
+    body::Bytes,
+    extract::{DefaultBodyLimit, Path, Query, Request, State},
+    http::{header::AUTHORIZATION, HeaderMap, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post, put},
+    Extension, Json, Router,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    service::{
+        db_service::{DBServiceHandle, DBServiceRequest, DBServiceResponse},
+        farm_service::{
+            parse_sha256, Firmware, FirmwareError, Rule, ServiceError, ServiceRequest,
+            ServiceResponse, MAX_FIRMWARE_SIZE,
+        },
+    },
+    web_server::BackendResponse,
+    ServiceHandles,
+};
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct RolloutRequest {
+    version: String,
+    /// Every device when missing
+    devices: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct ExplainRequest {
+    /// The stored rules of the farm when missing
+    rules: Option<Vec<Rule>>,
+}
+
+/// Where uploaded firmware is written, only kept in memory when unset
+#[derive(Clone)]
+pub struct FirmwareDir(Option<PathBuf>);
+
+/// Firmware, rollout, device secret and automation rule management, every route needs
+/// `Authorization: Bearer <token>`
+pub fn router(token: String, firmware_dir: Option<PathBuf>) -> Router<Arc<ServiceHandles>> {
+    Router::new()
+        .route("/admin/firmware", get(list_firmware))
+        .route(
+            "/admin/firmware/:version",
+            post(upload_firmware).layer(DefaultBodyLimit::max(MAX_FIRMWARE_SIZE)),
+        )
+        .route("/admin/rollout", get(rollout_status).post(start_rollout))
+        .route("/admin/rollout/abort", post(abort_rollout))
+        .route("/admin/farms/:id/secret", post(rotate_secret))
+        .route("/admin/farms/:id/rules", put(set_rules))
+        .route("/admin/farms/:id/rules/explain", post(explain_rules))
+        .route_layer(middleware::from_fn_with_state(
+            Arc::<str>::from(token),
+            require_token,
+        ))
+        .layer(Extension(FirmwareDir(firmware_dir)))
+}
+
+/// Compares every byte so the time taken doesn't tell how much of the token matched
+fn token_matches(expected: &str, given: &str) -> bool {
+    expected.len() == given.len()
+        && expected
+            .bytes()
+            .zip(given.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+async fn require_token(
+    State(token): State<Arc<str>>,
+    headers: HeaderMap,
+    request: Request,
+    next: Next,
+) -> Response {
+    let given = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match given {
+        Some(given) if token_matches(&token, given) => next.run(request).await,
+        _ => (
+            StatusCode::UNAUTHORIZED,
+            Json(BackendResponse::Error("invalid_admin_token".to_string())),
+        )
+            .into_response(),
+    }
+}
+
+fn bad_request(message: &str) -> (StatusCode, Json<BackendResponse>) {
+    (
+        StatusCode::BAD_REQUEST,
+        Json(BackendResponse::Error(message.to_string())),
+    )
+}
+
+pub async fn list_firmware(State(services): State<Arc<ServiceHandles>>) -> impl IntoResponse {
+    let firmware = match match services
+        .farm_service
+        .request(ServiceRequest::ListFirmware)
+        .await
+    {
+        Ok(firmware) => firmware,
+        Err(err) => return (err.clone().into(), err.into()),
+    } {
+        ServiceResponse::Firmware(firmware) => firmware,
+        _ => unreachable!(),
+    };
+    (StatusCode::OK, Json(BackendResponse::Firmware(firmware)))
+}
+
+/// The raw image is the body, its hex SHA-256 goes in the `sha256` query parameter
+pub async fn upload_firmware(
+    State(services): State<Arc<ServiceHandles>>,
+    Extension(FirmwareDir(dir)): Extension<FirmwareDir>,
+    Path(version): Path<String>,
+    Query(query): Query<HashMap<String, String>>,
+    body: Bytes,
+) -> impl IntoResponse {
+    let Some(sha256) = query.get("sha256").and_then(|hex| parse_sha256(hex)) else {
+        return bad_request("The sha256 parameter must be a hex SHA-256 hash.");
+    };
+    // Hashing and writing a few MiB would hold up every device in the farm service
+    let checked = tokio::task::spawn_blocking(move || {
+        let firmware = Firmware::verify(version, sha256, body.to_vec())?;
+        if let Some(dir) = dir {
+            firmware.store(&dir)?;
+        }
+        Ok::<_, FirmwareError>(firmware)
+    })
+    .await
+    .expect("Checking the firmware panicked");
+    let firmware = match checked {
+        Ok(firmware) => firmware,
+        Err(err) => {
+            let err = ServiceError::InvalidFirmware(err.to_string());
+            return (err.clone().into(), err.into());
+        }
+    };
+    let info = match match services
+        .farm_service
+        .request(ServiceRequest::UploadFirmware { firmware })
+        .await
+    {
+        Ok(info) => info,
+        Err(err) => return (err.clone().into(), err.into()),
+    } {
+        ServiceResponse::FirmwareStored(info) => info,
+        _ => unreachable!(),
+    };
+    (StatusCode::OK, Json(BackendResponse::Firmware(vec![info])))
+}
+
+pub async fn start_rollout(
+    State(services): State<Arc<ServiceHandles>>,
+    Json(data): Json<RolloutRequest>,
+) -> impl IntoResponse {
+    let devices = match data.devices {
+        Some(devices) => {
+            let devices = devices
+                .iter()
+                .map(|id| device_id(id))
+                .collect::<Option<Vec<[char; 64]>>>();
+            match devices {
+                Some(devices) => Some(devices),
+                None => return bad_request("Device ids are 64 characters long."),
+            }
+        }
+        None => None,
+    };
+    rollout_response(
+        &services,
+        ServiceRequest::StartRollout {
+            version: data.version,
+            devices,
+        },
+    )
+    .await
+}
+
+pub async fn rollout_status(State(services): State<Arc<ServiceHandles>>) -> impl IntoResponse {
+    rollout_response(&services, ServiceRequest::RolloutStatus).await
+}
+
+pub async fn abort_rollout(State(services): State<Arc<ServiceHandles>>) -> impl IntoResponse {
+    rollout_response(&services, ServiceRequest::AbortRollout).await
+}
+
+async fn rollout_response(
+    services: &ServiceHandles,
+    request: ServiceRequest,
+) -> (StatusCode, Json<BackendResponse>) {
+    let status = match match services.farm_service.request(request).await {
+        Ok(status) => status,
+        Err(err) => return (err.clone().into(), err.into()),
+    } {
+        ServiceResponse::Rollout(status) => status,
+        _ => unreachable!(),
+    };
+    (StatusCode::OK, Json(BackendResponse::Rollout(status)))
+}
+
+fn device_id(id: &str) -> Option<[char; 64]> {
+    id.chars().collect::<Vec<char>>().try_into().ok()
+}
+
+/// Gives the farm a new device secret and returns it, the old one stops working at once.
+/// Farms created before devices had to authenticate get their first secret this way
+pub async fn rotate_secret(
+    State(services): State<Arc<ServiceHandles>>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    new_secret(&services.db_service, &id).await
+}
+
+async fn new_secret(db: &DBServiceHandle, id: &str) -> (StatusCode, Json<BackendResponse>) {
+    let Some(device_id) = device_id(id) else {
+        return bad_request("Device ids are 64 characters long.");
+    };
+    match db
+        .request(DBServiceRequest::RotateDeviceSecret { id: device_id })
+        .await
+    {
+        Ok(DBServiceResponse::DeviceSecret(secret)) => (
+            StatusCode::OK,
+            Json(BackendResponse::Device {
+                id: id.to_string(),
+                secret: secret.iter().collect(),
+            }),
+        ),
+        Ok(..) => unreachable!(),
+        Err(..) => (
+            StatusCode::NOT_FOUND,
+            Json(BackendResponse::Error("Unknown device.".to_string())),
+        ),
+    }
+}
+
+/// The body is the JSON list of rules replacing the stored ones
+pub async fn set_rules(
+    State(services): State<Arc<ServiceHandles>>,
+    Path(id): Path<String>,
+    Json(rules): Json<Vec<Rule>>,
+) -> impl IntoResponse {
+    let Some(device_id) = device_id(&id) else {
+        return bad_request("Device ids are 64 characters long.");
+    };
+    match services
+        .farm_service
+        .request(ServiceRequest::SetRules { device_id, rules })
+        .await
+    {
+        Ok(..) => (StatusCode::OK, Json(BackendResponse::Ok)),
+        Err(err) => (err.clone().into(), err.into()),
+    }
+}
+
+/// Dry run of the given or stored rules on the last report of the farm
+pub async fn explain_rules(
+    State(services): State<Arc<ServiceHandles>>,
+    Path(id): Path<String>,
+    Json(data): Json<ExplainRequest>,
+) -> impl IntoResponse {
+    let Some(device_id) = device_id(&id) else {
+        return bad_request("Device ids are 64 characters long.");
+    };
+    let traces = match match services
+        .farm_service
+        .request(ServiceRequest::ExplainRules {
+            device_id,
+            rules: data.rules,
+        })
+        .await
+    {
+        Ok(traces) => traces,
+        Err(err) => return (err.clone().into(), err.into()),
+    } {
+        ServiceResponse::RuleTraces(traces) => traces,
+        _ => unreachable!(),
+    };
+    (StatusCode::OK, Json(BackendResponse::Rules(traces)))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use tokio::sync::mpsc::channel;
+    use tower::ServiceExt;
+
+    use crate::service::{db_service::DBServiceError, ServiceHandle};
+
+    use super::*;
+
+    const TOKEN: &str = "0123456789abcdef0123456789abcdef";
+
+    fn guarded() -> Router {
+        Router::new()
+            .route("/admin/ping", get(|| async { "pong" }))
+            .route_layer(middleware::from_fn_with_state(
+                Arc::<str>::from(TOKEN),
+                require_token,
+            ))
+    }
+
+    async fn status(authorization: Option<&str>) -> StatusCode {
+        let mut request = Request::builder().uri("/admin/ping");
+        if let Some(authorization) = authorization {
+            request = request.header(AUTHORIZATION, authorization);
+        }
+        guarded()
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+            .status()
+    }
+
+    #[tokio::test]
+    async fn admin_routes_need_the_token() {
+        assert_eq!(status(None).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status(Some(TOKEN)).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            status(Some("Bearer 0123456789abcdef0123456789abcdeF")).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            status(Some(&format!("Bearer {TOKEN}"))).await,
+            StatusCode::OK
+        );
+    }
+
+    /// A database that only knows `farm` and hands out `secret` for it
+    fn database(farm: [char; 64], secret: [char; 64]) -> DBServiceHandle {
+        let (sender, mut receiver) = channel::<crate::service::ServiceRequest<_, _>>(16);
+        tokio::spawn(async move {
+            while let Some(request) = receiver.recv().await {
+                let response = match request.data {
+                    DBServiceRequest::RotateDeviceSecret { id } if id == farm => {
+                        Ok(DBServiceResponse::DeviceSecret(secret))
+                    }
+                    _ => Err(DBServiceError::UnregisterdDevice),
+                };
+                request.result_sender.send(response).await.ok();
+            }
+        });
+        ServiceHandle::new(sender)
+    }
+
+    #[tokio::test]
+    async fn new_secrets_are_returned_for_known_farms() {
+        let farm = ['f'; 64];
+        let db = database(farm, ['s'; 64]);
+        let id = farm.iter().collect::<String>();
+        let (status, Json(response)) = new_secret(&db, &id).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(matches!(
+            response,
+            BackendResponse::Device { id: ref given, ref secret }
+                if *given == id && *secret == "s".repeat(64)
+        ));
+        assert_eq!(
+            new_secret(&db, &"g".repeat(64)).await.0,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(new_secret(&db, "short").await.0, StatusCode::BAD_REQUEST);
+    }
+}
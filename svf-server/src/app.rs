@@ -1,18 +1,11 @@
 use std::sync::Arc;
 
-use axum::{
-    extract::State,
-    response::{IntoResponse, Response},
-    Json,
-};
-use reqwest::{header, StatusCode};
+use axum::{extract::State, response::IntoResponse, Json};
+use reqwest::StatusCode;
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    service::{
-        db_service::{DBServiceRequest, DBServiceResponse},
-        farm_service::{self, ServiceHandle},
-    },
+    service::db_service::{DBServiceRequest, DBServiceResponse},
     web_server::BackendResponse,
     ServiceHandles,
 };
@@ -26,19 +19,30 @@ pub async fn request_id(
     State(services): State<Arc<ServiceHandles>>,
     Json(data): Json<IdRequest>,
 ) -> impl IntoResponse {
-    let id = match services
+    let (id, secret) = match services
         .db_service
         .request(DBServiceRequest::CreateNewDevice {
             region: data.region,
         })
         .await
     {
-        Ok(DBServiceResponse::DeviceId(id)) => id,
+        Ok(DBServiceResponse::Device { id, secret }) => (id, secret),
         Ok(..) => unreachable!(),
-        Err(err) => todo!(),
+        Err(err) => {
+            println!("Failed to create a device: {err:?}");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(BackendResponse::Error(
+                    "The device could not be created.".to_string(),
+                )),
+            );
+        }
     };
     (
         StatusCode::OK,
-        Json(BackendResponse::DeviceId(id.iter().collect::<String>())),
+        Json(BackendResponse::Device {
+            id: id.iter().collect::<String>(),
+            secret: secret.iter().collect::<String>(),
+        }),
     )
 }
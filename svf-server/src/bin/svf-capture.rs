@@ -0,0 +1,305 @@
+//! Inspects and replays device sessions recorded with `DEVICE_CAPTURE_DIR`
+//!
+//! ```text
+//! svf-capture print <CAPTURE> [--hex]
+//! svf-capture replay <CAPTURE> [--server <ADDR>] [--secret <SECRET>] [--fast]
+//! ```
+//!
+//! `print` lists every frame with its offset into the session, direction, packet id
+//! and the decoded packet. Frames that fail to decode are shown with the error.
+//!
+//! `replay` plays the device side of a capture against a server with the original
+//! timing, printing what the server answers. The recorded authentication response
+//! only matches the recorded challenge, so pass the device secret with `--secret`
+//! to sign the challenge of the new session instead.
+
+use std::{
+    env,
+    fs::File,
+    net::SocketAddr,
+    path::PathBuf,
+    process::exit,
+    time::{Duration, Instant},
+};
+
+use futures::{SinkExt, StreamExt};
+use local_ip_address::local_ip;
+use svf_server::service::farm_service::protocol::{
+    read_capture, sign, CaptureRecord, ClientPacket, ClientPacketId, Direction, Frame, PacketCodec,
+    PacketError, ServerPacket, ServerPacketId, NONCE_LENGTH,
+};
+use tokio::{net::TcpStream, time::sleep_until};
+use tokio_util::codec::Framed;
+
+enum Command {
+    Print {
+        hex: bool,
+    },
+    Replay {
+        server: SocketAddr,
+        secret: Option<Box<[char; 64]>>,
+        fast: bool,
+    },
+}
+
+fn describe(record: &CaptureRecord) -> String {
+    let mut frame = record.frame.clone();
+    let decoded = match record.direction {
+        Direction::FromDevice => frame.decode::<ClientPacketId>().map(|e| format!("{e:?}")),
+        Direction::ToDevice => frame.decode::<ServerPacketId>().map(|e| format!("{e:?}")),
+    };
+    decoded.unwrap_or_else(|err| format!("<{err}>"))
+}
+
+fn hex_dump(payload: &[u8]) -> String {
+    payload
+        .chunks(16)
+        .enumerate()
+        .map(|(line, bytes)| {
+            let bytes = bytes.iter().map(|e| format!("{e:02x}")).collect::<Vec<_>>();
+            format!("    {:04x}  {}\n", line * 16, bytes.join(" "))
+        })
+        .collect()
+}
+
+fn print(records: &[CaptureRecord], hex: bool) {
+    let start = records.first().map(|e| e.timestamp).unwrap_or_default();
+    for record in records {
+        let header = record.frame.header();
+        println!(
+            "{:>10.3}s {} id {:>2} len {:>5} {}",
+            record.timestamp.saturating_sub(start).as_secs_f64(),
+            match record.direction {
+                Direction::FromDevice => "device -> server",
+                Direction::ToDevice => "server -> device",
+            },
+            header.id(),
+            header.length(),
+            describe(record)
+        );
+        if hex {
+            print!("{}", hex_dump(record.frame.payload()));
+        }
+    }
+}
+
+/// The device id announced in the capture, needed to sign a fresh challenge
+fn device_id(records: &[CaptureRecord]) -> Option<[char; 64]> {
+    records
+        .iter()
+        .filter(|e| e.direction == Direction::FromDevice)
+        .find_map(|e| match e.frame.clone().decode::<ClientPacketId>() {
+            Ok(ClientPacket::ReportId { id }) => Some(id),
+            _ => None,
+        })
+}
+
+struct Replay {
+    framed: Framed<TcpStream, PacketCodec>,
+    nonce: Option<[u8; NONCE_LENGTH]>,
+}
+
+impl Replay {
+    /// Print server frames until `deadline`, returns `false` once the server hung up
+    async fn receive_until(&mut self, deadline: Instant) -> Result<bool, PacketError> {
+        loop {
+            tokio::select! {
+                frame = self.framed.next() => {
+                    let Some(frame) = frame else { return Ok(false) };
+                    let mut frame = frame?;
+                    match frame.decode::<ServerPacketId>() {
+                        Ok(packet) => {
+                            println!("server -> device {packet:?}");
+                            if let ServerPacket::AuthChallenge { nonce } = packet {
+                                self.nonce = Some(nonce);
+                            }
+                        }
+                        Err(err) => println!("server -> device <{err}>"),
+                    }
+                }
+                _ = sleep_until(deadline.into()) => return Ok(true),
+            }
+        }
+    }
+
+    /// Wait for the challenge of this session and sign it
+    async fn sign_challenge(
+        &mut self,
+        secret: &[char; 64],
+        id: &[char; 64],
+    ) -> Result<Option<Frame>, PacketError> {
+        while self.nonce.is_none() {
+            if !self
+                .receive_until(Instant::now() + Duration::from_secs(1))
+                .await?
+            {
+                return Ok(None);
+            }
+        }
+        let response = sign(secret, &self.nonce.take().unwrap(), id);
+        Frame::from_packet::<ClientPacketId>(ClientPacket::AuthResponse { response }).map(Some)
+    }
+}
+
+async fn replay(
+    records: &[CaptureRecord],
+    server: SocketAddr,
+    secret: Option<([char; 64], [char; 64])>,
+    fast: bool,
+) -> Result<(), PacketError> {
+    let stream = TcpStream::connect(server).await?;
+    let mut replay = Replay {
+        framed: Framed::new(stream, PacketCodec::new()),
+        nonce: None,
+    };
+    let start = Instant::now();
+    let first = records.first().map(|e| e.timestamp).unwrap_or_default();
+    for record in records
+        .iter()
+        .filter(|e| e.direction == Direction::FromDevice)
+    {
+        let due = if fast {
+            Instant::now()
+        } else {
+            start + record.timestamp.saturating_sub(first)
+        };
+        if !replay.receive_until(due).await? {
+            println!("Server closed the connection");
+            return Ok(());
+        }
+        let mut frame = record.frame.clone();
+        if let (Some((id, secret)), Ok(ClientPacketId::AuthResponse)) =
+            (&secret, ClientPacketId::try_from(frame.header().id()))
+        {
+            match replay.sign_challenge(secret, id).await? {
+                Some(signed) => frame = signed,
+                None => {
+                    println!("Server closed the connection");
+                    return Ok(());
+                }
+            }
+        }
+        println!(
+            "device -> server {}",
+            describe(&CaptureRecord {
+                frame: frame.clone(),
+                ..record.clone()
+            })
+        );
+        replay.framed.send(frame).await?;
+    }
+    // Give the server a moment to answer the last frames
+    replay
+        .receive_until(Instant::now() + Duration::from_secs(2))
+        .await?;
+    Ok(())
+}
+
+fn usage(error: &str) -> ! {
+    eprintln!("{error}");
+    eprintln!(
+        "Usage: svf-capture print <CAPTURE> [--hex]\n       \
+         svf-capture replay <CAPTURE> [--server <ADDR>] [--secret <SECRET>] [--fast]"
+    );
+    exit(2)
+}
+
+fn parse_options() -> (PathBuf, Command) {
+    let mut args = env::args().skip(1);
+    let command = args.next().unwrap_or_else(|| usage("Missing command"));
+    let path = PathBuf::from(args.next().unwrap_or_else(|| usage("Missing capture file")));
+    let mut hex = false;
+    let mut server = None;
+    let mut secret = None;
+    let mut fast = false;
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .unwrap_or_else(|| usage(&format!("Missing value for {arg}")))
+        };
+        match arg.as_str() {
+            "--hex" => hex = true,
+            "--server" => {
+                server = Some(value().parse().unwrap_or_else(|_| usage("Invalid address")))
+            }
+            "--secret" => {
+                secret = Some(Box::new(
+                    value()
+                        .chars()
+                        .collect::<Vec<_>>()
+                        .try_into()
+                        .unwrap_or_else(|_| usage("Device secrets are 64 characters")),
+                ))
+            }
+            "--fast" => fast = true,
+            _ => usage(&format!("Unknown argument {arg}")),
+        }
+    }
+    let command = match command.as_str() {
+        "print" => Command::Print { hex },
+        "replay" => Command::Replay {
+            server: server
+                .unwrap_or_else(|| SocketAddr::new(local_ip().expect("Cannot get local ip"), 4000)),
+            secret,
+            fast,
+        },
+        _ => usage(&format!("Unknown command {command}")),
+    };
+    (path, command)
+}
+
+#[tokio::main]
+async fn main() {
+    let (path, command) = parse_options();
+    let records = File::open(&path)
+        .and_then(read_capture)
+        .unwrap_or_else(|err| usage(&format!("Cannot read {}: {err}", path.display())));
+    match command {
+        Command::Print { hex } => print(&records, hex),
+        Command::Replay {
+            server,
+            secret,
+            fast,
+        } => {
+            let secret = secret.map(|secret| {
+                let id = device_id(&records)
+                    .unwrap_or_else(|| usage("The capture has no ReportId to sign with"));
+                (id, *secret)
+            });
+            if let Err(err) = replay(&records, server, secret, fast).await {
+                eprintln!("Replay failed: {err}");
+                exit(1);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn undecodable_frames_are_described() {
+        let record = |direction, frame| CaptureRecord {
+            timestamp: Duration::ZERO,
+            direction,
+            frame,
+        };
+        let ping =
+            Frame::from_packet::<ServerPacketId>(ServerPacket::Ping { sequence: 7 }).unwrap();
+        assert_eq!(
+            describe(&record(Direction::ToDevice, ping)),
+            "Ping { sequence: 7 }"
+        );
+        assert!(
+            describe(&record(Direction::FromDevice, Frame::new(999, Vec::new()))).starts_with('<')
+        );
+    }
+
+    #[test]
+    fn hex_dump_breaks_lines() {
+        let dump = hex_dump(&(0..20).collect::<Vec<u8>>());
+        assert_eq!(dump.lines().count(), 2);
+        assert!(dump.lines().nth(1).unwrap().starts_with("    0010  10 11"));
+    }
+}
@@ -0,0 +1,363 @@
+//! Emulates BerryBotics controllers so the farm service can be exercised without hardware
+//!
+//! ```text
+//! svf-sim --device <ID>:<SECRET> [--device <ID>:<SECRET> ...]
+//!         [--server <ADDR>] [--interval <SECONDS>] [--image <JPEG>] [--script <CSV>]
+//! ```
+//!
+//! Every `--device` runs as its own simulated farm. Sensor values follow a random
+//! walk unless `--script` names a CSV file of `soil_moisture,air_temperature,light_sensor`
+//! rows, which are replayed in a loop. The cooler and the water pump react to
+//! `UpdateCooler`, `WaterPulse` and `Command` packets by changing the simulated farm.
+
+use std::{env, net::SocketAddr, path::PathBuf, process::exit, time::Duration};
+
+use futures::{SinkExt, StreamExt};
+use local_ip_address::local_ip;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use svf_server::service::farm_service::protocol::{
+    sign, Capabilities, ClientPacket, ClientPacketId, DeviceCommand, Frame, PacketCodec,
+    PacketError, ServerPacket, ServerPacketId,
+};
+use tokio::{net::TcpStream, time::interval};
+use tokio_util::codec::Framed;
+
+const FIRMWARE_VERSION: &str = "svf-sim 0.1.0";
+/// Protocol versions the real controller firmware announces
+const PROTOCOL_VERSIONS: (u16, u16) = (1, 4);
+const FRAME_SIZE: usize = 128;
+
+struct Options {
+    server: SocketAddr,
+    devices: Vec<([char; 64], [char; 64])>,
+    interval: Duration,
+    image: Vec<u8>,
+    script: Option<Vec<Reading>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Reading {
+    soil_moisture: f32,
+    air_temperature: f32,
+    light_sensor: f32,
+}
+
+/// A greenhouse bed whose sensors drift and react to the actuators
+struct Environment {
+    reading: Reading,
+    cooler: bool,
+    script: Option<(Vec<Reading>, usize)>,
+    rng: StdRng,
+}
+
+impl Environment {
+    fn new(script: Option<Vec<Reading>>) -> Self {
+        Self {
+            reading: Reading {
+                soil_moisture: 450.0,
+                air_temperature: 25.0,
+                light_sensor: 300.0,
+            },
+            cooler: false,
+            script: script.map(|e| (e, 0)),
+            rng: StdRng::from_entropy(),
+        }
+    }
+
+    /// Advance the simulation by one report interval
+    fn step(&mut self) -> Reading {
+        if let Some((script, index)) = &mut self.script {
+            self.reading = script[*index % script.len()];
+            *index += 1;
+            return self.reading;
+        }
+        let reading = &mut self.reading;
+        // Soil dries out, the sun heats the air unless the cooler runs
+        reading.soil_moisture += 4.0 + self.rng.gen_range(-3.0..3.0);
+        reading.air_temperature += if self.cooler { -0.6 } else { 0.3 };
+        reading.air_temperature += self.rng.gen_range(-0.2..0.2);
+        reading.light_sensor += self.rng.gen_range(-20.0..20.0);
+
+        reading.soil_moisture = reading.soil_moisture.clamp(0.0, 1023.0);
+        reading.air_temperature = reading.air_temperature.clamp(0.0, 60.0);
+        reading.light_sensor = reading.light_sensor.clamp(0.0, 1023.0);
+        *reading
+    }
+
+    fn apply(&mut self, command: DeviceCommand) {
+        match command {
+            DeviceCommand::Cooler { status } => self.cooler = status,
+            DeviceCommand::WaterPulse => {
+                self.reading.soil_moisture = (self.reading.soil_moisture - 150.0).max(0.0)
+            }
+            // Only sent to devices speaking protocol 6 or later, which the simulator doesn't announce
+            DeviceCommand::Configure(..)
+            | DeviceCommand::Reboot
+            | DeviceCommand::FactoryReset
+            | DeviceCommand::CoolerDuty { .. }
+            | DeviceCommand::Water { .. } => {}
+        }
+    }
+}
+
+struct Simulator {
+    id: [char; 64],
+    secret: [char; 64],
+    framed: Framed<TcpStream, PacketCodec>,
+    environment: Environment,
+    authenticated: bool,
+}
+
+impl Simulator {
+    async fn send(&mut self, packet: ClientPacket) -> Result<(), PacketError> {
+        self.framed
+            .send(Frame::from_packet::<ClientPacketId>(packet)?)
+            .await
+    }
+
+    async fn report(&mut self, image: &[u8]) -> Result<(), PacketError> {
+        let reading = self.environment.step();
+        self.send(ClientPacket::ReportSensors {
+            soil_moisture: reading.soil_moisture as u16,
+            air_temperature: reading.air_temperature as u16,
+            light_sensor: reading.light_sensor as u16,
+            image_size: image.len(),
+        })
+        .await?;
+        for chunk in image.chunks(FRAME_SIZE) {
+            let mut frame = [0; FRAME_SIZE];
+            frame[..chunk.len()].copy_from_slice(chunk);
+            self.send(ClientPacket::ImageFrame {
+                frame_size: chunk.len(),
+                frame,
+            })
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Answer a server packet, returns `false` once the server ends the session
+    async fn handle(&mut self, packet: ServerPacket) -> Result<bool, PacketError> {
+        match packet {
+            ServerPacket::Welcome {
+                protocol_version, ..
+            } => println!("{}: speaking protocol {protocol_version}", self.name()),
+            ServerPacket::AuthChallenge { nonce } => {
+                let response = sign(&self.secret, &nonce, &self.id);
+                self.send(ClientPacket::AuthResponse { response }).await?;
+            }
+            ServerPacket::Authenticated => {
+                println!("{}: authenticated", self.name());
+                self.authenticated = true;
+            }
+            ServerPacket::Disconnect { reason } => {
+                println!("{}: disconnected by the server: {reason}", self.name());
+                return Ok(false);
+            }
+            ServerPacket::UpdateCooler { status } => {
+                self.environment.apply(DeviceCommand::Cooler { status })
+            }
+            ServerPacket::WaterPulse => self.environment.apply(DeviceCommand::WaterPulse),
+            ServerPacket::Command { sequence, command } => {
+                self.environment.apply(command);
+                self.send(ClientPacket::Ack { sequence }).await?;
+            }
+            ServerPacket::Ping { sequence } => self.send(ClientPacket::Pong { sequence }).await?,
+            _ => {}
+        }
+        Ok(true)
+    }
+
+    fn name(&self) -> String {
+        self.id.iter().take(8).collect()
+    }
+
+    async fn run(&mut self, options: &Options) -> Result<(), PacketError> {
+        self.send(ClientPacket::Hello {
+            min_protocol_version: PROTOCOL_VERSIONS.0,
+            max_protocol_version: PROTOCOL_VERSIONS.1,
+            firmware_version: FIRMWARE_VERSION.to_string(),
+            capabilities: Capabilities::SUPPORTED.bits(),
+        })
+        .await?;
+        self.send(ClientPacket::ReportId { id: self.id }).await?;
+        let mut report = interval(options.interval);
+        loop {
+            tokio::select! {
+                frame = self.framed.next() => {
+                    let Some(frame) = frame else { return Ok(()) };
+                    if !self.handle(frame?.decode::<ServerPacketId>()?).await? {
+                        return Ok(());
+                    }
+                }
+                _ = report.tick(), if self.authenticated => self.report(&options.image).await?,
+            }
+        }
+    }
+}
+
+/// Keep one simulated farm connected, reconnecting like the controller does
+async fn simulate(options: &Options, id: [char; 64], secret: [char; 64]) {
+    let mut environment = Environment::new(options.script.clone());
+    loop {
+        match TcpStream::connect(options.server).await {
+            Ok(stream) => {
+                let mut simulator = Simulator {
+                    id,
+                    secret,
+                    framed: Framed::new(stream, PacketCodec::new()),
+                    environment,
+                    authenticated: false,
+                };
+                if let Err(err) = simulator.run(options).await {
+                    println!("{}: {err}", simulator.name());
+                }
+                environment = simulator.environment;
+            }
+            Err(err) => println!("Cannot connect to {}: {err}", options.server),
+        }
+        tokio::time::sleep(Duration::from_secs(3)).await;
+    }
+}
+
+fn parse_device(value: &str) -> Option<([char; 64], [char; 64])> {
+    let (id, secret) = value.split_once(':')?;
+    let id = id.chars().collect::<Vec<_>>().try_into().ok()?;
+    let secret = secret.chars().collect::<Vec<_>>().try_into().ok()?;
+    Some((id, secret))
+}
+
+fn parse_script(content: &str) -> Option<Vec<Reading>> {
+    let readings = content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let mut values = line.split(',').map(|e| e.trim().parse::<f32>());
+            Some(Reading {
+                soil_moisture: values.next()?.ok()?,
+                air_temperature: values.next()?.ok()?,
+                light_sensor: values.next()?.ok()?,
+            })
+        })
+        .collect::<Option<Vec<_>>>()?;
+    (!readings.is_empty()).then_some(readings)
+}
+
+/// A stand-in JPEG, only the markers are real
+fn placeholder_image() -> Vec<u8> {
+    let mut image = vec![0xFF, 0xD8];
+    image.extend((0..4096).map(|i| (i % 251) as u8));
+    image.extend([0xFF, 0xD9]);
+    image
+}
+
+fn usage(error: &str) -> ! {
+    eprintln!("{error}");
+    eprintln!(
+        "Usage: svf-sim --device <ID>:<SECRET> [--server <ADDR>] [--interval <SECONDS>] \
+         [--image <JPEG>] [--script <CSV>]"
+    );
+    exit(2)
+}
+
+fn parse_options() -> Options {
+    let mut server = None;
+    let mut devices = Vec::new();
+    let mut interval = Duration::from_secs(5);
+    let mut image = None;
+    let mut script = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .unwrap_or_else(|| usage(&format!("Missing value for {arg}")))
+        };
+        match arg.as_str() {
+            "--server" => {
+                server = Some(value().parse().unwrap_or_else(|_| usage("Invalid address")))
+            }
+            "--device" => devices.push(parse_device(&value()).unwrap_or_else(|| {
+                usage("Device ids and secrets are 64 characters, separated by ':'")
+            })),
+            "--interval" => {
+                interval = value()
+                    .parse()
+                    .ok()
+                    .and_then(|secs| Duration::try_from_secs_f32(secs).ok())
+                    .filter(|interval| !interval.is_zero())
+                    .unwrap_or_else(|| usage("Invalid interval"))
+            }
+            "--image" => image = Some(PathBuf::from(value())),
+            "--script" => script = Some(PathBuf::from(value())),
+            _ => usage(&format!("Unknown argument {arg}")),
+        }
+    }
+    if devices.is_empty() {
+        usage("At least one --device is required");
+    }
+    Options {
+        server: server
+            .unwrap_or_else(|| SocketAddr::new(local_ip().expect("Cannot get local ip"), 4000)),
+        devices,
+        interval,
+        image: match image {
+            Some(path) => std::fs::read(&path)
+                .unwrap_or_else(|err| usage(&format!("Cannot read {}: {err}", path.display()))),
+            None => placeholder_image(),
+        },
+        script: script.map(|path| {
+            let content = std::fs::read_to_string(&path)
+                .unwrap_or_else(|err| usage(&format!("Cannot read {}: {err}", path.display())));
+            parse_script(&content).unwrap_or_else(|| usage("Invalid script"))
+        }),
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let options: &'static Options = Box::leak(Box::new(parse_options()));
+    println!(
+        "Simulating {} farms against {}",
+        options.devices.len(),
+        options.server
+    );
+    let tasks = options
+        .devices
+        .iter()
+        .map(|(id, secret)| tokio::spawn(simulate(options, *id, *secret)))
+        .collect::<Vec<_>>();
+    for task in tasks {
+        task.await.unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn actuators_change_the_environment() {
+        let mut environment = Environment::new(None);
+        let dry = environment.reading.soil_moisture;
+        environment.apply(DeviceCommand::WaterPulse);
+        assert_eq!(environment.reading.soil_moisture, dry - 150.0);
+
+        environment.apply(DeviceCommand::Cooler { status: true });
+        let warm = environment.reading.air_temperature;
+        for _ in 0..10 {
+            environment.step();
+        }
+        assert!(environment.reading.air_temperature < warm);
+    }
+
+    #[test]
+    fn script_is_replayed_in_a_loop() {
+        let script = parse_script("# soil,temperature,light\n500,30,200\n100, 20, 50\n").unwrap();
+        let mut environment = Environment::new(Some(script.clone()));
+        assert_eq!(environment.step(), script[0]);
+        assert_eq!(environment.step(), script[1]);
+        assert_eq!(environment.step(), script[0]);
+        assert!(parse_script("500,thirty,200").is_none());
+    }
+}
@@ -0,0 +1,682 @@
+//! Server configuration, read from a TOML file and overridden from the environment
+//!
+//! The file is `svf.toml` in the working directory, or whatever `SVF_CONFIG` points
+//! at. Every key can be overridden with `SVF_<SECTION>__<KEY>`, for example
+//! `SVF_DEVICES__IDLE_TIMEOUT=120`. The variables used before the config file
+//! existed (`PROD`, `DB_IP`, `CERT_PATH`, ...) are still honoured.
+//! See `svf.example.toml` for every option.
+
+use std::{
+    env,
+    fmt::Display,
+    fs, io,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use axum::http::HeaderValue;
+use local_ip_address::local_ip;
+use serde::Deserialize;
+use toml::{Table, Value};
+
+use crate::service::farm_service::{
+    ClockConfig, CommandConfig, ControlConfig, FarmConfig, MqttConfig, SensorDefinition,
+    SensorRegistry, SessionConfig,
+};
+
+const DEFAULT_PATH: &str = "svf.toml";
+const ENV_PREFIX: &str = "SVF_";
+const MIN_ADMIN_TOKEN_LENGTH: usize = 32;
+
+/// Environment variables from before the config file, and the key each one sets
+const LEGACY_ENV: &[(&str, &str)] = &[
+    ("PROD", "production"),
+    ("CERT_PATH", "tls.cert_path"),
+    ("KEY_PATH", "tls.key_path"),
+    ("DB_IP", "db.host"),
+    ("DB_USERNAME", "db.username"),
+    ("DB_PASSWORD", "db.password"),
+    ("DEVICE_TLS", "devices.tls"),
+    ("DEVICE_HEARTBEAT_INTERVAL", "devices.heartbeat_interval"),
+    ("DEVICE_IDLE_TIMEOUT", "devices.idle_timeout"),
+    ("DEVICE_CAPTURE_DIR", "devices.capture_dir"),
+    ("MQTT_BROKER", "mqtt.broker"),
+    ("MQTT_CLIENT_ID", "mqtt.client_id"),
+    ("MQTT_USERNAME", "mqtt.username"),
+    ("MQTT_PASSWORD", "mqtt.password"),
+    ("MQTT_TELEMETRY_TOPIC", "mqtt.telemetry_topic"),
+    ("MQTT_COMMAND_TOPIC", "mqtt.command_topic"),
+];
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read { path: PathBuf, error: io::Error },
+    Parse(toml::de::Error),
+    Override { name: String, error: String },
+    Invalid(Vec<String>),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Read { path, error } => write!(f, "Cannot read {}: {error}", path.display()),
+            Self::Parse(error) => write!(f, "Invalid configuration: {error}"),
+            Self::Override { name, error } => write!(f, "Invalid value in {name}: {error}"),
+            Self::Invalid(problems) => {
+                write!(f, "Invalid configuration:")?;
+                for problem in problems {
+                    write!(f, "\n  - {problem}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Serve the web server on port 443 with TLS and the production CORS origin
+    pub production: bool,
+    pub web: WebConfig,
+    pub tls: TlsConfig,
+    pub db: DbConfig,
+    pub cors: CorsConfig,
+    pub devices: DeviceConfig,
+    pub control: ControlSection,
+    pub storage: StorageConfig,
+    pub mqtt: Option<MqttSection>,
+    pub admin: AdminConfig,
+    /// Site specific sensor types, on top of the built-in ones
+    pub sensors: Vec<SensorDefinition>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebConfig {
+    /// Defaults to the local ip on port 443 in production and 127.0.0.1:3000 otherwise
+    pub listen: Option<SocketAddr>,
+}
+
+/// Certificate and key shared by the web server and the device listener
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert_path: Option<PathBuf>,
+    pub key_path: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DbConfig {
+    pub host: String,
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    /// Defaults to the hosted web app in production and the local dev server otherwise
+    pub origins: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeviceConfig {
+    /// Defaults to the local ip on port 4000
+    pub listen: Option<SocketAddr>,
+    /// Require TLS on the device listener
+    pub tls: bool,
+    /// Seconds between heartbeats
+    pub heartbeat_interval: u64,
+    /// Seconds without traffic before a device is disconnected
+    pub idle_timeout: u64,
+    /// Record every device session into this directory
+    pub capture_dir: Option<PathBuf>,
+    /// Seconds to wait for a command to be acknowledged
+    pub ack_timeout: u64,
+    /// Times a command is sent before it times out
+    pub max_attempts: u32,
+    /// Seconds between `TimeSync`s sent to devices
+    pub time_sync_interval: u64,
+    /// Seconds a report may be stamped ahead of the server clock
+    pub max_clock_skew: u64,
+    /// Seconds a report may have waited on the device before its stamp is distrusted
+    pub max_report_age: u64,
+}
+
+impl Default for DeviceConfig {
+    fn default() -> Self {
+        let session = SessionConfig::default();
+        let commands = CommandConfig::default();
+        let clock = ClockConfig::default();
+        Self {
+            listen: None,
+            tls: false,
+            heartbeat_interval: session.heartbeat_interval.as_secs(),
+            idle_timeout: session.idle_timeout.as_secs(),
+            capture_dir: None,
+            ack_timeout: commands.ack_timeout.as_secs(),
+            max_attempts: commands.max_attempts,
+            time_sync_interval: clock.sync_interval.as_secs(),
+            max_clock_skew: clock.max_skew.as_secs(),
+            max_report_age: clock.max_report_age.as_secs(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ControlSection {
+    /// Soil moisture readings above this start the water pump
+    pub soil_moisture_threshold: u16,
+}
+
+impl Default for ControlSection {
+    fn default() -> Self {
+        Self {
+            soil_moisture_threshold: ControlConfig::default().soil_moisture_threshold,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    /// Keep reported images in `<image_dir>/<device id>/`, images are dropped when unset
+    pub image_dir: Option<PathBuf>,
+    /// Keep uploaded firmware in `<firmware_dir>/<version>.bin`, only in memory when unset
+    pub firmware_dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    /// Bearer token of the `/admin` API, which is disabled when unset
+    pub token: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MqttSection {
+    /// `host[:port]`, the port defaults to 1883
+    pub broker: String,
+    pub client_id: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub telemetry_topic: Option<String>,
+    pub command_topic: Option<String>,
+    /// Seconds without telemetry before a device is disconnected
+    pub idle_timeout: Option<u64>,
+}
+
+impl MqttSection {
+    fn broker(&self) -> Option<(String, u16)> {
+        match self.broker.rsplit_once(':') {
+            Some((host, port)) => Some((host.to_string(), port.parse().ok()?)),
+            None => Some((self.broker.clone(), 1883)),
+        }
+        .filter(|(host, _)| !host.is_empty())
+    }
+}
+
+/// Read an override as a TOML value such as `30`, `true` or `["a", "b"]`
+fn parse_value(value: &str) -> Option<Value> {
+    format!("value = {value}")
+        .parse::<Table>()
+        .ok()?
+        .remove("value")
+}
+
+fn deserialize(table: &Table) -> Result<Config, toml::de::Error> {
+    Value::Table(table.clone()).try_into()
+}
+
+/// The value an override stands for, checked against its key on its own
+///
+/// `DB_PASSWORD=1234` parses as a number but is meant as a string, so the raw
+/// text is tried as well before giving up.
+fn override_value(key: &str, value: &str) -> Result<Value, String> {
+    let mut error = "not a key of the configuration".to_string();
+    for value in [parse_value(value), Some(Value::String(value.to_string()))]
+        .into_iter()
+        .flatten()
+    {
+        let mut table = Table::new();
+        set(&mut table, key, value.clone());
+        match deserialize(&table) {
+            Ok(_) => return Ok(value),
+            Err(err) => error = err.message().to_string(),
+        }
+    }
+    Err(error)
+}
+
+/// Set `key`, a dotted path, creating the tables on the way
+fn set(table: &mut Table, key: &str, value: Value) -> Option<()> {
+    let mut keys = key.split('.').collect::<Vec<_>>();
+    let last = keys.pop()?;
+    let mut table = table;
+    for key in keys {
+        table = table
+            .entry(key)
+            .or_insert_with(|| Value::Table(Table::new()))
+            .as_table_mut()?;
+    }
+    table.insert(last.to_string(), value);
+    Some(())
+}
+
+impl Config {
+    /// Load the config file and the environment, then validate the result
+    pub fn load() -> Result<Self, ConfigError> {
+        let path = env::var("SVF_CONFIG").map(PathBuf::from).ok();
+        let content = match &path {
+            Some(path) => Some(fs::read_to_string(path).map_err(|error| ConfigError::Read {
+                path: path.clone(),
+                error,
+            })?),
+            // The default file is optional, the environment may be enough
+            None => fs::read_to_string(DEFAULT_PATH).ok(),
+        };
+        let config = Self::from_sources(content.as_deref(), env::vars())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Parse `content` and apply the overrides in `vars`, without validating
+    pub fn from_sources(
+        content: Option<&str>,
+        vars: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Self, ConfigError> {
+        let mut table = match content {
+            Some(content) => content.parse::<Table>().map_err(ConfigError::Parse)?,
+            None => Table::new(),
+        };
+        let mut overrides = Vec::new();
+        for (name, value) in vars {
+            if let Some((_, key)) = LEGACY_ENV.iter().find(|(legacy, _)| *legacy == name) {
+                // `PROD=1` predates the config file
+                let value = match (*key, value.as_str()) {
+                    ("production" | "devices.tls", "1") => "true".to_string(),
+                    ("production" | "devices.tls", _) => "false".to_string(),
+                    _ => value,
+                };
+                overrides.push((0, name, key.to_string(), value));
+            } else if let Some(key) = name.strip_prefix(ENV_PREFIX) {
+                if key == "CONFIG" {
+                    continue;
+                }
+                let key = key.to_lowercase().replace("__", ".");
+                overrides.push((1, name, key, value));
+            }
+        }
+        // Explicit `SVF_` variables win over the legacy names
+        overrides.sort_by_key(|(priority, ..)| *priority);
+        for (_, name, key, value) in overrides {
+            let value = override_value(&key, &value).map_err(|error| ConfigError::Override {
+                name: name.clone(),
+                error,
+            })?;
+            set(&mut table, &key, value).ok_or(ConfigError::Override {
+                name,
+                error: "the key is not inside a table".to_string(),
+            })?;
+        }
+        deserialize(&table).map_err(ConfigError::Parse)
+    }
+
+    /// Check everything that can be checked before binding sockets and connecting
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+        let mut check = |ok: bool, problem: &str| {
+            if !ok {
+                problems.push(problem.to_string());
+            }
+        };
+
+        for (name, value) in [
+            ("db.host", &self.db.host),
+            ("db.username", &self.db.username),
+            ("db.password", &self.db.password),
+        ] {
+            check(!value.is_empty(), &format!("`{name}` is required"));
+        }
+
+        if self.production || self.devices.tls {
+            let needed_by = if self.production {
+                "`production`"
+            } else {
+                "`devices.tls`"
+            };
+            for (name, path) in [
+                ("tls.cert_path", &self.tls.cert_path),
+                ("tls.key_path", &self.tls.key_path),
+            ] {
+                match path {
+                    Some(path) => check(
+                        path.is_file(),
+                        &format!("`{name}` {} is not a file", path.display()),
+                    ),
+                    None => check(false, &format!("`{name}` is required by {needed_by}")),
+                }
+            }
+        }
+
+        for origin in self.cors_origins() {
+            check(
+                (origin.starts_with("http://") || origin.starts_with("https://"))
+                    && origin.parse::<HeaderValue>().is_ok(),
+                &format!("`cors.origins` entry {origin:?} is not an http(s) origin"),
+            );
+        }
+
+        let devices = &self.devices;
+        check(
+            devices.heartbeat_interval > 0,
+            "`devices.heartbeat_interval` must be at least 1 second",
+        );
+        check(
+            devices.idle_timeout > devices.heartbeat_interval,
+            "`devices.idle_timeout` must be longer than `devices.heartbeat_interval`",
+        );
+        check(
+            devices.ack_timeout > 0,
+            "`devices.ack_timeout` must be at least 1 second",
+        );
+        check(
+            devices.max_attempts > 0,
+            "`devices.max_attempts` must be at least 1",
+        );
+        check(
+            devices.time_sync_interval > 0,
+            "`devices.time_sync_interval` must be at least 1 second",
+        );
+        check(
+            devices.max_report_age > devices.max_clock_skew,
+            "`devices.max_report_age` must be longer than `devices.max_clock_skew`",
+        );
+        for (name, dir) in [
+            ("devices.capture_dir", &devices.capture_dir),
+            ("storage.image_dir", &self.storage.image_dir),
+            ("storage.firmware_dir", &self.storage.firmware_dir),
+        ] {
+            if let Some(dir) = dir {
+                check(
+                    !dir.exists() || dir.is_dir(),
+                    &format!("`{name}` {} is not a directory", dir.display()),
+                );
+            }
+        }
+
+        check(
+            self.control.soil_moisture_threshold <= 1023,
+            "`control.soil_moisture_threshold` must be a 10 bit reading (0-1023)",
+        );
+
+        if let Some(token) = &self.admin.token {
+            check(
+                token.len() >= MIN_ADMIN_TOKEN_LENGTH,
+                &format!("`admin.token` must be at least {MIN_ADMIN_TOKEN_LENGTH} characters"),
+            );
+        }
+
+        if let Err(err) = SensorRegistry::new(&self.sensors) {
+            check(false, &format!("`sensors`: {err}"));
+        }
+
+        if let Some(mqtt) = &self.mqtt {
+            check(!mqtt.broker.is_empty(), "`mqtt.broker` is required");
+            check(
+                mqtt.broker.is_empty() || mqtt.broker().is_some(),
+                &format!("`mqtt.broker` {:?} is not host[:port]", mqtt.broker),
+            );
+            check(
+                mqtt.username.is_some() == mqtt.password.is_some(),
+                "`mqtt.username` and `mqtt.password` must be set together",
+            );
+            check(
+                mqtt.idle_timeout != Some(0),
+                "`mqtt.idle_timeout` must be at least 1 second",
+            );
+            for (name, topic) in [
+                ("mqtt.telemetry_topic", &mqtt.telemetry_topic),
+                ("mqtt.command_topic", &mqtt.command_topic),
+            ] {
+                if let Some(topic) = topic {
+                    check(
+                        topic.split('/').filter(|e| *e == "{id}").count() == 1,
+                        &format!("`{name}` must contain `{{id}}` as one whole level"),
+                    );
+                }
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid(problems))
+        }
+    }
+
+    fn local_ip() -> IpAddr {
+        local_ip().expect("Cannot get local ip")
+    }
+
+    pub fn web_listen(&self) -> SocketAddr {
+        self.web.listen.unwrap_or_else(|| {
+            if self.production {
+                SocketAddr::new(Self::local_ip(), 443)
+            } else {
+                SocketAddr::from(([127, 0, 0, 1], 3000))
+            }
+        })
+    }
+
+    pub fn cors_origins(&self) -> Vec<String> {
+        self.cors.origins.clone().unwrap_or_else(|| {
+            vec![if self.production {
+                "https://strawberryvisionfarm.web.app".to_string()
+            } else {
+                "http://localhost:5123".to_string()
+            }]
+        })
+    }
+
+    /// Paths of the certificate and key, present once validated if TLS is used anywhere
+    pub fn tls_paths(&self) -> Option<(&Path, &Path)> {
+        Some((
+            self.tls.cert_path.as_deref()?,
+            self.tls.key_path.as_deref()?,
+        ))
+    }
+
+    pub fn farm(&self) -> FarmConfig {
+        let devices = &self.devices;
+        let session = SessionConfig {
+            heartbeat_interval: Duration::from_secs(devices.heartbeat_interval),
+            idle_timeout: Duration::from_secs(devices.idle_timeout),
+            capture_dir: devices.capture_dir.clone(),
+            clock: ClockConfig {
+                sync_interval: Duration::from_secs(devices.time_sync_interval),
+                max_skew: Duration::from_secs(devices.max_clock_skew),
+                max_report_age: Duration::from_secs(devices.max_report_age),
+            },
+            ..SessionConfig::default()
+        };
+        FarmConfig {
+            device_listen: devices
+                .listen
+                .unwrap_or_else(|| SocketAddr::new(Self::local_ip(), 4000)),
+            session,
+            commands: CommandConfig {
+                ack_timeout: Duration::from_secs(devices.ack_timeout),
+                max_attempts: devices.max_attempts,
+            },
+            control: ControlConfig {
+                soil_moisture_threshold: self.control.soil_moisture_threshold,
+            },
+            mqtt: self.mqtt.as_ref().and_then(|mqtt| {
+                let (host, port) = mqtt.broker()?;
+                let mut config = MqttConfig::new(host, port);
+                if let Some(client_id) = &mqtt.client_id {
+                    config.client_id = client_id.clone();
+                }
+                if let (Some(username), Some(password)) = (&mqtt.username, &mqtt.password) {
+                    config.credentials = Some((username.clone(), password.clone()));
+                }
+                if let Some(topic) = &mqtt.telemetry_topic {
+                    config.telemetry_topic = topic.clone();
+                }
+                if let Some(topic) = &mqtt.command_topic {
+                    config.command_topic = topic.clone();
+                }
+                if let Some(idle_timeout) = mqtt.idle_timeout {
+                    config.idle_timeout = Duration::from_secs(idle_timeout);
+                }
+                Some(config)
+            }),
+            sensors: SensorRegistry::new(&self.sensors).expect("checked by validate"),
+            image_dir: self.storage.image_dir.clone(),
+            firmware_dir: self.storage.firmware_dir.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    const DB: &str = "
+        [db]
+        host = 'localhost'
+        username = 'svf'
+        password = 'secret'
+    ";
+
+    #[test]
+    fn defaults_match_the_old_hard_coded_values() {
+        let config = Config::from_sources(Some(DB), []).unwrap();
+        config.validate().unwrap();
+        assert_eq!(config.web_listen(), "127.0.0.1:3000".parse().unwrap());
+        assert_eq!(config.cors_origins(), ["http://localhost:5123"]);
+        let farm = config.farm();
+        assert_eq!(farm.device_listen.port(), 4000);
+        assert_eq!(farm.control.soil_moisture_threshold, 500);
+        assert_eq!(farm.session.idle_timeout, Duration::from_secs(60));
+        assert!(farm.mqtt.is_none());
+    }
+
+    #[test]
+    fn example_file_parses() {
+        let content = include_str!("../svf.example.toml");
+        let config = Config::from_sources(Some(content), []).unwrap();
+        assert_eq!(config.devices.heartbeat_interval, 15);
+    }
+
+    #[test]
+    fn environment_overrides_the_file() {
+        let content = format!("{DB}\n[devices]\nidle_timeout = 90\ncapture_dir = '/tmp'\n");
+        let config = Config::from_sources(
+            Some(&content),
+            vars(&[
+                ("SVF_DEVICES__IDLE_TIMEOUT", "120"),
+                ("DEVICE_IDLE_TIMEOUT", "30"),
+                ("DB_PASSWORD", "from-env"),
+                (
+                    "SVF_CORS__ORIGINS",
+                    r#"["https://a.example", "https://b.example"]"#,
+                ),
+                ("SVF_MQTT__BROKER", "broker.local:1884"),
+                ("PATH", "/usr/bin"),
+            ]),
+        )
+        .unwrap();
+        config.validate().unwrap();
+        assert_eq!(config.devices.idle_timeout, 120);
+        assert_eq!(config.db.password, "from-env");
+        assert_eq!(config.devices.capture_dir, Some(PathBuf::from("/tmp")));
+        assert_eq!(config.cors_origins().len(), 2);
+        let mqtt = config.farm().mqtt.unwrap();
+        assert_eq!((mqtt.host.as_str(), mqtt.port), ("broker.local", 1884));
+    }
+
+    #[test]
+    fn legacy_production_flag_is_a_bool() {
+        let config = Config::from_sources(Some(DB), vars(&[("PROD", "1")])).unwrap();
+        assert!(config.production);
+        assert_eq!(config.web_listen().port(), 443);
+        assert_eq!(
+            config.cors_origins(),
+            ["https://strawberryvisionfarm.web.app"]
+        );
+    }
+
+    #[test]
+    fn unknown_keys_and_wrong_types_are_rejected() {
+        let error = Config::from_sources(Some("[devices]\nidle_timeuot = 3\n"), []).unwrap_err();
+        assert!(error.to_string().contains("idle_timeuot"), "{error}");
+        let error = Config::from_sources(Some(DB), vars(&[("SVF_DEVICES__IDLE_TIMEOUT", "soon")]))
+            .unwrap_err();
+        assert!(
+            error.to_string().contains("SVF_DEVICES__IDLE_TIMEOUT"),
+            "{error}"
+        );
+        let config = Config::from_sources(Some(DB), vars(&[("DB_PASSWORD", "1234")])).unwrap();
+        assert_eq!(config.db.password, "1234");
+    }
+
+    #[test]
+    fn validation_lists_every_problem() {
+        let config = Config::from_sources(
+            Some(
+                "production = true
+                [devices]
+                heartbeat_interval = 60
+                idle_timeout = 30
+                [cors]
+                origins = ['localhost']
+                [mqtt]
+                broker = 'broker:port'
+                command_topic = 'svf/commands'
+                [admin]
+                token = 'secret'
+                [[sensors]]
+                id = 5
+                name = 'co2_outside'
+                unit = 'ppm'
+                min = 0
+                max = 5000",
+            ),
+            [],
+        )
+        .unwrap();
+        let Err(ConfigError::Invalid(problems)) = config.validate() else {
+            panic!("expected validation errors");
+        };
+        let problems = problems.join("\n");
+        for expected in [
+            "`db.host` is required",
+            "`tls.cert_path` is required by `production`",
+            "`devices.idle_timeout` must be longer",
+            "\"localhost\" is not an http(s) origin",
+            "`mqtt.broker`",
+            "`mqtt.command_topic` must contain `{id}`",
+            "`admin.token` must be at least",
+            "Sensor co2_outside must have an id of at least",
+        ] {
+            assert!(
+                problems.contains(expected),
+                "{expected} missing in\n{problems}"
+            );
+        }
+    }
+}
@@ -0,0 +1,17 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use axum::{
+    extract::{ws::WebSocketUpgrade, ConnectInfo, State},
+    response::Response,
+};
+
+use crate::ServiceHandles;
+
+/// Lets devices behind HTTPS-only firewalls speak the farm protocol over a WebSocket
+pub async fn websocket(
+    upgrade: WebSocketUpgrade,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    State(services): State<Arc<ServiceHandles>>,
+) -> Response {
+    services.device_gateway.clone().upgrade(upgrade, addr)
+}
@@ -1,4 +1,4 @@
-use std::{env, sync::Arc};
+use std::sync::Arc;
 
 use axum::{
     http::{HeaderValue, StatusCode},
@@ -6,6 +6,8 @@ use axum::{
     routing::{get, post},
     Router,
 };
+use axum_server::tls_rustls::RustlsConfig;
+use config::Config;
 use reqwest::{
     header::{AUTHORIZATION, CONTENT_TYPE},
     Method,
@@ -19,7 +21,10 @@ use tower::ServiceBuilder;
 use tower_http::cors::CorsLayer;
 use wait_pool::WaitPool;
 
+pub mod admin;
 pub mod app;
+pub mod config;
+pub mod device;
 pub mod login;
 pub mod service;
 pub mod signup;
@@ -27,44 +32,46 @@ pub mod utils;
 pub mod wait_pool;
 pub mod web_server;
 
-pub fn is_production() -> bool {
-    match env::var("PROD") {
-        Ok(prod) => prod == "1",
-        Err(_) => false,
-    }
-}
-
-pub fn build_cors() -> CorsLayer {
-    let base = CorsLayer::new()
+/// Origins come from [`Config::cors_origins`], which are checked by [`Config::validate`]
+pub fn build_cors(config: &Config) -> CorsLayer {
+    CorsLayer::new()
         .allow_methods([Method::GET, Method::POST])
         .allow_credentials(true)
-        .allow_headers([AUTHORIZATION, CONTENT_TYPE]);
-    if !is_production() {
-        base.allow_origin("http://localhost:5123".parse::<HeaderValue>().unwrap())
-    } else {
-        base.allow_origin(
-            "https://strawberryvisionfarm.web.app"
-                .parse::<HeaderValue>()
-                .unwrap(),
+        .allow_headers([AUTHORIZATION, CONTENT_TYPE])
+        .allow_origin(
+            config
+                .cors_origins()
+                .iter()
+                .map(|origin| origin.parse::<HeaderValue>().unwrap())
+                .collect::<Vec<_>>(),
         )
-    }
 }
 
 pub struct ServiceHandles {
     pub db_service: DBServiceHandle,
     pub auth_service: AuthenticationServiceHandle,
     pub farm_service: farm_service::ServiceHandle,
+    pub device_gateway: farm_service::DeviceGateway,
+    /// Certificate and key shared by the web server and the device listener, loaded
+    /// when either of them uses TLS
+    pub tls: Option<RustlsConfig>,
 }
 
-pub fn router() -> Router<Arc<ServiceHandles>> {
+pub fn router(config: &Config) -> Router<Arc<ServiceHandles>> {
+    let admin = match &config.admin.token {
+        Some(token) => admin::router(token.clone(), config.storage.firmware_dir.clone()),
+        None => Router::new(),
+    };
     Router::new()
+        .merge(admin)
         .route("/login/username", post(login::username))
         .route("/login/google", post(login::google))
         .route("/login/password-challenge", post(login::password_challenge))
         .route("/signup/username", post(signup::username))
         .route("/signup/google", post(signup::google))
         .route("/app/request-id", post(app::request_id))
-        .layer(ServiceBuilder::new().layer(build_cors()))
+        .route("/device/ws", get(device::websocket))
+        .layer(ServiceBuilder::new().layer(build_cors(config)))
         .fallback(notfound_handler)
 }
 
@@ -75,14 +82,22 @@ async fn notfound_handler() -> impl IntoResponse {
     )
 }
 
-pub async fn init_services(wait_pool: &mut WaitPool) -> ServiceHandles {
-    let db_service = DBService::new().await;
+pub async fn init_services(config: &Config, wait_pool: &mut WaitPool) -> ServiceHandles {
+    let db_service = DBService::new(&config.db).await;
     let auth_service = AuthenticationService::new(db_service.get());
-    let farm_service = farm_service::Service::new(db_service.get());
+    let tls = if config.production || config.devices.tls {
+        Some(web_server::tls_config(config).await)
+    } else {
+        None
+    };
+    let device_tls = tls.clone().filter(|_| config.devices.tls);
+    let farm_service = farm_service::Service::new(db_service.get(), device_tls, config.farm());
     let handles = ServiceHandles {
         db_service: db_service.get(),
         auth_service: auth_service.get(),
         farm_service: farm_service.get(),
+        device_gateway: farm_service.gateway(),
+        tls,
     };
     wait_pool.add(serve_service(db_service));
     wait_pool.add(serve_service(auth_service));
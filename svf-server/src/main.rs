@@ -1,11 +1,22 @@
+use std::process::exit;
+
 use dotenv::dotenv;
-use svf_server::{wait_pool::WaitPool, web_server};
+use svf_server::{config::Config, wait_pool::WaitPool, web_server};
 
 #[tokio::main]
 async fn main() {
     dotenv().ok();
+    let config = Config::load().unwrap_or_else(|err| {
+        eprintln!("{err}");
+        exit(1)
+    });
     let mut wait_pool = WaitPool::new();
-    let services = svf_server::init_services(&mut wait_pool).await;
-    web_server::serve(svf_server::router(), services, &mut wait_pool);
+    let services = svf_server::init_services(&config, &mut wait_pool).await;
+    web_server::serve(
+        svf_server::router(&config),
+        services,
+        &config,
+        &mut wait_pool,
+    );
     wait_pool.wait().await;
 }
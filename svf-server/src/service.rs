@@ -35,17 +35,23 @@ pub trait Service<T, R> {
 }
 
 pub struct ServiceRequest<T, R> {
-    result_sender: Sender<R>,
-    data: T,
+    pub(crate) result_sender: Sender<R>,
+    pub(crate) data: T,
 }
 
-#[derive(Clone)]
 pub struct ServiceHandle<T, R> {
     sender: Sender<ServiceRequest<T, R>>,
 }
 
+// Derived `Clone` would require `T: Clone` and `R: Clone`, which the handle doesn't need
+impl<T, R> Clone for ServiceHandle<T, R> {
+    fn clone(&self) -> Self {
+        Self::new(self.sender.clone())
+    }
+}
+
 impl<T, R> ServiceHandle<T, R> {
-    fn new(sender: Sender<ServiceRequest<T, R>>) -> Self {
+    pub(crate) fn new(sender: Sender<ServiceRequest<T, R>>) -> Self {
         Self { sender }
     }
 
@@ -64,41 +64,41 @@ pub enum AuthenticationServiceError {
     WrongPassword,
 }
 
-impl Into<Json<BackendResponse>> for AuthenticationServiceError {
-    fn into(self) -> Json<BackendResponse> {
+impl From<AuthenticationServiceError> for Json<BackendResponse> {
+    fn from(val: AuthenticationServiceError) -> Self {
         Json(BackendResponse::Error(
-            match self {
-                Self::UnregisteredAccount => "Trying to login into unregistered account.",
-                Self::UnregisteredDevice => "Trying to access into unregistered devices.",
-                Self::InvalidUsernameRegex => "Username can only contain lowercase and uppercase English letters, as well as the characters '-' and '_'.",
-                Self::InvalidUsernameLength => "Username length can only be in the range of 3 to 20 characters.",
-                Self::InvalidGoogleToken => "Invalid Google Token",
-                Self::InvalidPassword => "Invalid Password",
-                Self::InvalidAccessToken => "invalid_access_token",
-                Self::UsernameTaken => "The username has already been taken.",
-                Self::GoogleTaken => "This Google account has already been registered in the system.",
-                Self::AuthenticationMismatch => "This account has been registered with different authentication method.",
-                Self::WrongPassword => "Incorrect password.",
+            match val {
+                AuthenticationServiceError::UnregisteredAccount => "Trying to login into unregistered account.",
+                AuthenticationServiceError::UnregisteredDevice => "Trying to access into unregistered devices.",
+                AuthenticationServiceError::InvalidUsernameRegex => "Username can only contain lowercase and uppercase English letters, as well as the characters '-' and '_'.",
+                AuthenticationServiceError::InvalidUsernameLength => "Username length can only be in the range of 3 to 20 characters.",
+                AuthenticationServiceError::InvalidGoogleToken => "Invalid Google Token",
+                AuthenticationServiceError::InvalidPassword => "Invalid Password",
+                AuthenticationServiceError::InvalidAccessToken => "invalid_access_token",
+                AuthenticationServiceError::UsernameTaken => "The username has already been taken.",
+                AuthenticationServiceError::GoogleTaken => "This Google account has already been registered in the system.",
+                AuthenticationServiceError::AuthenticationMismatch => "This account has been registered with different authentication method.",
+                AuthenticationServiceError::WrongPassword => "Incorrect password.",
             }
             .to_string(),
         ))
     }
 }
 
-impl Into<StatusCode> for AuthenticationServiceError {
-    fn into(self) -> StatusCode {
-        match self {
-            Self::UnregisteredAccount
-            | Self::InvalidPassword
-            | Self::InvalidAccessToken
-            | Self::InvalidGoogleToken => StatusCode::UNAUTHORIZED,
-            Self::InvalidUsernameRegex
-            | Self::UsernameTaken
-            | Self::InvalidUsernameLength
-            | Self::GoogleTaken
-            | Self::AuthenticationMismatch
-            | Self::WrongPassword
-            | Self::UnregisteredDevice => StatusCode::BAD_REQUEST,
+impl From<AuthenticationServiceError> for StatusCode {
+    fn from(val: AuthenticationServiceError) -> Self {
+        match val {
+            AuthenticationServiceError::UnregisteredAccount
+            | AuthenticationServiceError::InvalidPassword
+            | AuthenticationServiceError::InvalidAccessToken
+            | AuthenticationServiceError::InvalidGoogleToken => StatusCode::UNAUTHORIZED,
+            AuthenticationServiceError::InvalidUsernameRegex
+            | AuthenticationServiceError::UsernameTaken
+            | AuthenticationServiceError::InvalidUsernameLength
+            | AuthenticationServiceError::GoogleTaken
+            | AuthenticationServiceError::AuthenticationMismatch
+            | AuthenticationServiceError::WrongPassword
+            | AuthenticationServiceError::UnregisteredDevice => StatusCode::BAD_REQUEST,
         }
     }
 }
@@ -110,11 +110,14 @@ impl From<DBServiceError> for AuthenticationServiceError {
             DBServiceError::UserAlreadyExists => Self::UsernameTaken,
             DBServiceError::GoogleTaken => Self::GoogleTaken,
             DBServiceError::AuthenticationMismatch => Self::AuthenticationMismatch,
-            DBServiceError::UnregisterdDevice => Self::UnregisteredDevice,
+            DBServiceError::UnregisterdDevice
+            | DBServiceError::UnknownRegion
+            | DBServiceError::QueryFailed(_) => Self::UnregisteredDevice,
         }
     }
 }
 
+#[allow(clippy::large_enum_variant)]
 pub enum AuthenticationServiceResponse {
     AccessToken([char; 128]),
     PasswordChallenge([char; 64]),
@@ -134,7 +137,7 @@ impl AuthenticationService {
 
     pub fn verify_username(username: &str) -> Result<(), AuthenticationServiceError> {
         let regex = Regex::new(r"^[a-zA-Z_-]+$").unwrap();
-        if !regex.is_match(&username) {
+        if !regex.is_match(username) {
             return Err(AuthenticationServiceError::InvalidUsernameRegex);
         }
         if username.len() < 3 || username.len() > 20 {
@@ -205,7 +208,7 @@ impl AuthenticationService {
             };
             return Ok(AuthenticationServiceResponse::AccessToken(token));
         }
-        return Err(AuthenticationServiceError::WrongPassword);
+        Err(AuthenticationServiceError::WrongPassword)
     }
 
     pub async fn password_challenge(
@@ -226,7 +229,7 @@ impl AuthenticationService {
                 challenge,
             })
             .await?;
-        return Ok(AuthenticationServiceResponse::PasswordChallenge(challenge));
+        Ok(AuthenticationServiceResponse::PasswordChallenge(challenge))
     }
 
     pub async fn google_login(
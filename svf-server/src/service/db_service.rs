@@ -1,19 +1,49 @@
-use std::env;
+use std::time::{Duration, SystemTime};
 
 use rand::{distributions::Alphanumeric, Rng};
-use sha2::{
-    digest::{DynDigest, Update},
-    Digest, Sha256,
-};
+use sha2::{Digest, Sha256};
 use tokio::sync::mpsc::{channel, Receiver, Sender};
-use tokio_postgres::{Client, GenericClient, NoTls};
+use tokio_postgres::{Client, NoTls};
+
+use crate::config::DbConfig;
 
-use super::{Service, ServiceHandle, ServiceRequest};
+use super::{
+    farm_service::{ControlSettings, DeviceSettings, IrrigationSettings, Rule, StoredReading},
+    Service, ServiceHandle, ServiceRequest,
+};
 
 pub type DBServiceHandle =
     ServiceHandle<DBServiceRequest, Result<DBServiceResponse, DBServiceError>>;
 type DBServiceChannel = ServiceRequest<DBServiceRequest, Result<DBServiceResponse, DBServiceError>>;
 
+/// Schema changes applied on startup, every statement must be idempotent
+const MIGRATIONS: &str = "
+    ALTER TABLE farms ADD COLUMN IF NOT EXISTS device_secret TEXT;
+    ALTER TABLE farms ADD COLUMN IF NOT EXISTS desired_config TEXT;
+    ALTER TABLE farms ADD COLUMN IF NOT EXISTS reported_config TEXT;
+    ALTER TABLE farms ADD COLUMN IF NOT EXISTS control_policy TEXT;
+    ALTER TABLE farms ADD COLUMN IF NOT EXISTS control_settings TEXT;
+    ALTER TABLE farms ADD COLUMN IF NOT EXISTS irrigation TEXT;
+    ALTER TABLE farms ADD COLUMN IF NOT EXISTS rules TEXT;
+    CREATE TABLE IF NOT EXISTS sensor_readings (
+        farm_id TEXT NOT NULL,
+        captured_at TIMESTAMPTZ NOT NULL,
+        sensor TEXT NOT NULL,
+        probe SMALLINT NOT NULL,
+        unit TEXT NOT NULL,
+        value INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS sensor_readings_farm_time
+        ON sensor_readings (farm_id, captured_at);
+    CREATE TABLE IF NOT EXISTS pump_runs (
+        farm_id TEXT NOT NULL,
+        started_at TIMESTAMPTZ NOT NULL,
+        duration_ms INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS pump_runs_farm_time
+        ON pump_runs (farm_id, started_at);
+";
+
 pub struct DBService {
     sender: Sender<DBServiceChannel>,
     receiver: Receiver<DBServiceChannel>,
@@ -38,6 +68,60 @@ pub enum DBServiceRequest {
     GetTemperature {
         id: [char; 64],
     },
+    GetDeviceSecret {
+        id: [char; 64],
+    },
+    /// Replaces the secret of an existing farm with a new random one, also how farms
+    /// created before devices authenticated get their first secret
+    RotateDeviceSecret {
+        id: [char; 64],
+    },
+    /// Control policy and tuning the farm is driven with
+    GetControl {
+        id: [char; 64],
+    },
+    /// Irrigation schedule and pump budget of the farm
+    GetIrrigation {
+        id: [char; 64],
+    },
+    /// Total time the pump of the farm ran for since `since`
+    GetPumpRuntime {
+        id: [char; 64],
+        since: SystemTime,
+    },
+    /// A pump run the device acknowledged
+    StorePumpRun {
+        id: [char; 64],
+        started_at: SystemTime,
+        runtime: Duration,
+    },
+    /// Automation rules of the farm, checked with `rules::validate` before they're stored
+    GetRules {
+        id: [char; 64],
+    },
+    SetRules {
+        id: [char; 64],
+        rules: Vec<Rule>,
+    },
+    /// Settings the device should run with, pushed to it on every connect
+    SetDesiredConfig {
+        id: [char; 64],
+        settings: DeviceSettings,
+    },
+    GetDesiredConfig {
+        id: [char; 64],
+    },
+    /// Settings the device said it runs with when it last connected
+    StoreReportedConfig {
+        id: [char; 64],
+        settings: DeviceSettings,
+    },
+    /// Readings already checked against the sensor registry
+    StoreReadings {
+        id: [char; 64],
+        captured_at: SystemTime,
+        readings: Vec<StoredReading>,
+    },
     CreateUserGoogle {
         username: String,
         google_id: String,
@@ -57,35 +141,67 @@ pub enum DBServiceError {
     UserAlreadyExists,
     GoogleTaken,
     AuthenticationMismatch,
+    UnknownRegion,
+    QueryFailed(tokio_postgres::Error),
 }
 
 pub enum DBServiceResponse {
     Empty,
     AccessToken([char; 128]),
     PasswordHashWithChallenge([char; 64]),
-    DeviceId([char; 64]),
+    Device {
+        id: [char; 64],
+        secret: [char; 64],
+    },
+    DeviceSecret([char; 64]),
+    /// `None` for farms on the default policy or settings
+    Control {
+        policy: Option<String>,
+        settings: Option<ControlSettings>,
+    },
     Temperature(i32),
+    /// `None` for farms without irrigation settings
+    Irrigation(Option<IrrigationSettings>),
+    PumpRuntime(Duration),
+    /// Empty for farms without rules
+    Rules(Vec<Rule>),
+    /// `None` when nothing was configured for the device yet
+    DesiredConfig(Option<DeviceSettings>),
 }
 
 impl DBService {
-    pub async fn new() -> Self {
+    pub async fn new(config: &DbConfig) -> Self {
         let (sender, receiver) = channel(16);
-        let (client, connection) = tokio_postgres::connect(
-            &format!(
-                "host={} user={} password={}",
-                env::var("DB_IP").expect("No db ip provided"),
-                env::var("DB_USERNAME").expect("No db username is provided"),
-                env::var("DB_PASSWORD").expect("No db password is provided")
-            ),
-            NoTls,
-        )
-        .await
-        .expect("Failed to connect to the databases");
+        let (client, connection) = tokio_postgres::Config::new()
+            .host(&config.host)
+            .user(&config.username)
+            .password(&config.password)
+            .connect(NoTls)
+            .await
+            .expect("Failed to connect to the databases");
         tokio::spawn(async move {
             if let Err(e) = connection.await {
                 eprintln!("db connection error: {}", e);
             }
         });
+        client
+            .batch_execute(MIGRATIONS)
+            .await
+            .expect("Failed to migrate the databases");
+        let unprovisioned: i64 = client
+            .query_one(
+                "SELECT COUNT(*) FROM farms WHERE device_secret IS NULL",
+                &[],
+            )
+            .await
+            .expect("Failed to count farms without a device secret")
+            .get(0);
+        if unprovisioned > 0 {
+            println!(
+                "{unprovisioned} farms have no device secret and can't connect, \
+                 provision them with POST /admin/farms/<id>/secret"
+            );
+        }
         Self {
             sender,
             receiver,
@@ -182,7 +298,7 @@ impl DBService {
             )
             .await
             .unwrap();
-        if let Some(user) = user_row.get(0) {
+        if let Some(user) = user_row.first() {
             match (
                 user.get::<_, Option<String>>("password_hash"),
                 user.get::<_, Option<String>>("password_challenge"),
@@ -210,7 +326,7 @@ impl DBService {
                 _ => return Err(DBServiceError::AuthenticationMismatch),
             }
         }
-        return Err(DBServiceError::UnregisterdAccount);
+        Err(DBServiceError::UnregisterdAccount)
     }
 
     async fn create_user_google(
@@ -276,34 +392,94 @@ WHERE username = $2::TEXT",
             )
             .await
             .unwrap();
-        return Ok(DBServiceResponse::Empty);
+        Ok(DBServiceResponse::Empty)
     }
 
-    async fn create_device(&mut self, region: String) -> Result<DBServiceResponse, DBServiceError> {
-        let id = TryInto::<[char; 64]>::try_into({
+    fn random_device_token() -> [char; 64] {
+        TryInto::<[char; 64]>::try_into({
             let mut rng = rand::thread_rng();
             (0..64)
                 .map(|_| rng.sample(Alphanumeric))
                 .map(char::from)
                 .collect::<Vec<char>>()
         })
-        .unwrap();
-        self.client
-            .query(
+        .unwrap()
+    }
+
+    async fn create_device(&mut self, region: String) -> Result<DBServiceResponse, DBServiceError> {
+        let id = Self::random_device_token();
+        let secret = Self::random_device_token();
+        let inserted = self
+            .client
+            .execute(
                 "
                 WITH temp AS (
                   SELECT temperature 
                   FROM region_temp 
                   WHERE region = $1::TEXT 
-                ) INSERT INTO farms (farm_id, ripe, unripe, temperature) 
-                SELECT $2::TEXT, 0, 0, temperature 
+                ) INSERT INTO farms (farm_id, ripe, unripe, temperature, device_secret) 
+                SELECT $2::TEXT, 0, 0, temperature, $3::TEXT 
                 FROM temp
             ",
-                &[&region, &id.iter().collect::<String>()],
+                &[
+                    &region,
+                    &id.iter().collect::<String>(),
+                    &secret.iter().collect::<String>(),
+                ],
+            )
+            .await
+            .map_err(DBServiceError::QueryFailed)?;
+        // Nothing is inserted when the region has no temperature entry
+        if inserted == 0 {
+            return Err(DBServiceError::UnknownRegion);
+        }
+        Ok(DBServiceResponse::Device { id, secret })
+    }
+
+    async fn get_device_secret(
+        &mut self,
+        id: [char; 64],
+    ) -> Result<DBServiceResponse, DBServiceError> {
+        let data = self
+            .client
+            .query_opt(
+                "SELECT device_secret FROM farms WHERE farm_id = $1::TEXT",
+                &[&id.iter().collect::<String>()],
+            )
+            .await
+            .unwrap();
+        match data.and_then(|farm| farm.get::<_, Option<String>>("device_secret")) {
+            Some(secret) => Ok(DBServiceResponse::DeviceSecret(
+                secret
+                    .chars()
+                    .collect::<Vec<char>>()
+                    .try_into()
+                    .map_err(|_| DBServiceError::UnregisterdDevice)?,
+            )),
+            None => Err(DBServiceError::UnregisterdDevice),
+        }
+    }
+
+    async fn rotate_device_secret(
+        &mut self,
+        id: [char; 64],
+    ) -> Result<DBServiceResponse, DBServiceError> {
+        let secret = Self::random_device_token();
+        let updated = self
+            .client
+            .execute(
+                "UPDATE farms SET device_secret = $2::TEXT WHERE farm_id = $1::TEXT",
+                &[
+                    &id.iter().collect::<String>(),
+                    &secret.iter().collect::<String>(),
+                ],
             )
             .await
             .unwrap();
-        return Ok(DBServiceResponse::DeviceId(id));
+        if updated == 0 {
+            return Err(DBServiceError::UnregisterdDevice);
+        }
+        Ok(DBServiceResponse::DeviceSecret(secret))
     }
 
     async fn get_temperature(
@@ -318,13 +494,245 @@ WHERE username = $2::TEXT",
             )
             .await
             .unwrap();
-        if let Some(user) = data.get(0) {
+        if let Some(user) = data.first() {
             return Ok(DBServiceResponse::Temperature(
                 user.get::<_, i32>("temperature"),
             ));
         }
 
-        return Err(DBServiceError::UnregisterdDevice);
+        Err(DBServiceError::UnregisterdDevice)
+    }
+
+    async fn get_control(&mut self, id: [char; 64]) -> Result<DBServiceResponse, DBServiceError> {
+        let data = self
+            .client
+            .query_opt(
+                "SELECT control_policy, control_settings FROM farms WHERE farm_id = $1::TEXT",
+                &[&id.iter().collect::<String>()],
+            )
+            .await
+            .unwrap()
+            .ok_or(DBServiceError::UnregisterdDevice)?;
+        let settings = data
+            .get::<_, Option<String>>("control_settings")
+            .and_then(|settings| {
+                serde_json::from_str(&settings)
+                    .inspect_err(|err| {
+                        println!(
+                            "Ignoring control settings of {}: {err}",
+                            id.iter().collect::<String>()
+                        )
+                    })
+                    .ok()
+            });
+        Ok(DBServiceResponse::Control {
+            policy: data.get::<_, Option<String>>("control_policy"),
+            settings,
+        })
+    }
+
+    async fn get_irrigation(
+        &mut self,
+        id: [char; 64],
+    ) -> Result<DBServiceResponse, DBServiceError> {
+        let data = self
+            .client
+            .query_opt(
+                "SELECT irrigation FROM farms WHERE farm_id = $1::TEXT",
+                &[&id.iter().collect::<String>()],
+            )
+            .await
+            .unwrap()
+            .ok_or(DBServiceError::UnregisterdDevice)?;
+        let settings = data
+            .get::<_, Option<String>>("irrigation")
+            .and_then(|settings| {
+                serde_json::from_str::<IrrigationSettings>(&settings)
+                    .map_err(|err| err.to_string())
+                    .and_then(|settings| settings.validate().map(|()| settings))
+                    .inspect_err(|err| {
+                        println!(
+                            "Ignoring irrigation settings of {}: {err}",
+                            id.iter().collect::<String>()
+                        )
+                    })
+                    .ok()
+            });
+        Ok(DBServiceResponse::Irrigation(settings))
+    }
+
+    async fn get_pump_runtime(
+        &mut self,
+        id: [char; 64],
+        since: SystemTime,
+    ) -> Result<DBServiceResponse, DBServiceError> {
+        let data = self
+            .client
+            .query_one(
+                "SELECT COALESCE(SUM(duration_ms), 0)::BIGINT AS runtime FROM pump_runs
+                WHERE farm_id = $1::TEXT AND started_at >= $2",
+                &[&id.iter().collect::<String>(), &since],
+            )
+            .await
+            .unwrap();
+        let millis = data.get::<_, i64>("runtime");
+        Ok(DBServiceResponse::PumpRuntime(Duration::from_millis(
+            millis.max(0) as u64,
+        )))
+    }
+
+    async fn store_pump_run(
+        &mut self,
+        id: [char; 64],
+        started_at: SystemTime,
+        runtime: Duration,
+    ) -> Result<DBServiceResponse, DBServiceError> {
+        self.client
+            .execute(
+                "INSERT INTO pump_runs (farm_id, started_at, duration_ms)
+                VALUES ($1::TEXT, $2, $3::INTEGER)",
+                &[
+                    &id.iter().collect::<String>(),
+                    &started_at,
+                    &(runtime.as_millis().min(i32::MAX as u128) as i32),
+                ],
+            )
+            .await
+            .unwrap();
+        Ok(DBServiceResponse::Empty)
+    }
+
+    async fn get_rules(&mut self, id: [char; 64]) -> Result<DBServiceResponse, DBServiceError> {
+        let data = self
+            .client
+            .query_opt(
+                "SELECT rules FROM farms WHERE farm_id = $1::TEXT",
+                &[&id.iter().collect::<String>()],
+            )
+            .await
+            .unwrap()
+            .ok_or(DBServiceError::UnregisterdDevice)?;
+        let rules = data
+            .get::<_, Option<String>>("rules")
+            .and_then(|rules| {
+                serde_json::from_str(&rules)
+                    .inspect_err(|err| {
+                        println!(
+                            "Ignoring the rules of {}: {err}",
+                            id.iter().collect::<String>()
+                        )
+                    })
+                    .ok()
+            })
+            .unwrap_or_default();
+        Ok(DBServiceResponse::Rules(rules))
+    }
+
+    async fn set_rules(
+        &mut self,
+        id: [char; 64],
+        rules: Vec<Rule>,
+    ) -> Result<DBServiceResponse, DBServiceError> {
+        let updated = self
+            .client
+            .execute(
+                "UPDATE farms SET rules = $2::TEXT WHERE farm_id = $1::TEXT",
+                &[
+                    &id.iter().collect::<String>(),
+                    &serde_json::to_string(&rules).unwrap(),
+                ],
+            )
+            .await
+            .unwrap();
+        if updated == 0 {
+            return Err(DBServiceError::UnregisterdDevice);
+        }
+        Ok(DBServiceResponse::Empty)
+    }
+
+    async fn set_config(
+        &mut self,
+        column: &str,
+        id: [char; 64],
+        settings: DeviceSettings,
+    ) -> Result<DBServiceResponse, DBServiceError> {
+        let updated = self
+            .client
+            .execute(
+                &format!("UPDATE farms SET {column} = $2::TEXT WHERE farm_id = $1::TEXT"),
+                &[
+                    &id.iter().collect::<String>(),
+                    &serde_json::to_string(&settings).unwrap(),
+                ],
+            )
+            .await
+            .unwrap();
+        if updated == 0 {
+            return Err(DBServiceError::UnregisterdDevice);
+        }
+        Ok(DBServiceResponse::Empty)
+    }
+
+    async fn get_desired_config(
+        &mut self,
+        id: [char; 64],
+    ) -> Result<DBServiceResponse, DBServiceError> {
+        let data = self
+            .client
+            .query_opt(
+                "SELECT desired_config FROM farms WHERE farm_id = $1::TEXT",
+                &[&id.iter().collect::<String>()],
+            )
+            .await
+            .unwrap()
+            .ok_or(DBServiceError::UnregisterdDevice)?;
+        let settings = data
+            .get::<_, Option<String>>("desired_config")
+            .and_then(|config| {
+                serde_json::from_str(&config)
+                    .inspect_err(|err| {
+                        println!(
+                            "Ignoring desired config of {}: {err}",
+                            id.iter().collect::<String>()
+                        )
+                    })
+                    .ok()
+            });
+        Ok(DBServiceResponse::DesiredConfig(settings))
+    }
+
+    async fn store_readings(
+        &mut self,
+        id: [char; 64],
+        captured_at: SystemTime,
+        readings: Vec<StoredReading>,
+    ) -> Result<DBServiceResponse, DBServiceError> {
+        let id = id.iter().collect::<String>();
+        let statement = self
+            .client
+            .prepare(
+                "INSERT INTO sensor_readings (farm_id, captured_at, sensor, probe, unit, value)
+                VALUES ($1::TEXT, $2, $3::TEXT, $4::SMALLINT, $5::TEXT, $6::INTEGER)",
+            )
+            .await
+            .unwrap();
+        for reading in readings {
+            self.client
+                .execute(
+                    &statement,
+                    &[
+                        &id,
+                        &captured_at,
+                        &reading.sensor,
+                        &i16::from(reading.index),
+                        &reading.unit.name(),
+                        &reading.value,
+                    ],
+                )
+                .await
+                .unwrap();
+        }
+        Ok(DBServiceResponse::Empty)
     }
 
     async fn create_user_default(
@@ -395,6 +803,32 @@ impl Service<DBServiceRequest, Result<DBServiceResponse, DBServiceError>> for DB
                 self.create_access_token_username(username).await
             }
             DBServiceRequest::GetTemperature { id } => self.get_temperature(id).await,
+            DBServiceRequest::GetDeviceSecret { id } => self.get_device_secret(id).await,
+            DBServiceRequest::RotateDeviceSecret { id } => self.rotate_device_secret(id).await,
+            DBServiceRequest::GetControl { id } => self.get_control(id).await,
+            DBServiceRequest::GetIrrigation { id } => self.get_irrigation(id).await,
+            DBServiceRequest::GetRules { id } => self.get_rules(id).await,
+            DBServiceRequest::SetRules { id, rules } => self.set_rules(id, rules).await,
+            DBServiceRequest::GetPumpRuntime { id, since } => {
+                self.get_pump_runtime(id, since).await
+            }
+            DBServiceRequest::StorePumpRun {
+                id,
+                started_at,
+                runtime,
+            } => self.store_pump_run(id, started_at, runtime).await,
+            DBServiceRequest::SetDesiredConfig { id, settings } => {
+                self.set_config("desired_config", id, settings).await
+            }
+            DBServiceRequest::GetDesiredConfig { id } => self.get_desired_config(id).await,
+            DBServiceRequest::StoreReportedConfig { id, settings } => {
+                self.set_config("reported_config", id, settings).await
+            }
+            DBServiceRequest::StoreReadings {
+                id,
+                captured_at,
+                readings,
+            } => self.store_readings(id, captured_at, readings).await,
             DBServiceRequest::CreateNewDevice { region } => self.create_device(region).await,
         }
     }
@@ -1,26 +1,96 @@
-use std::{collections::HashMap, fs::read_dir, net::SocketAddr, sync::Arc};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    path::PathBuf,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
-use crate::service::db_service::DBServiceResponse;
+use crate::{service::db_service::DBServiceResponse, web_server::BackendResponse};
 
 use super::db_service::{DBServiceHandle, DBServiceRequest};
-use client::{Client, ClientPacketId};
-use futures::FutureExt;
-use local_ip_address::local_ip;
-use packet::{Packet, PacketHeader};
-use sha2::digest::typenum::TArr;
+use axum::{http::StatusCode, Json};
+use axum_server::tls_rustls::RustlsConfig;
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use command::CommandTracker;
+use control::CoolerGuard;
+use firmware::{FirmwareRepository, FirmwareStatus, Rollout, RolloutTarget, FIRMWARE_CHUNK_SIZE};
+use handshake::Capabilities;
+use irrigation::Irrigation;
+use packet::PacketId;
+use rules::{RuleContext, RuleEngine};
+use server::ServerPacketId;
 use tokio::{
-    io::AsyncReadExt,
-    net::{TcpListener, TcpStream},
-    select,
+    net::TcpListener,
     sync::{
+        broadcast,
         mpsc::{channel, Receiver, Sender},
-        Mutex,
     },
+    time::interval,
 };
 
+mod auth;
+mod capture;
 mod client;
+mod clock;
+mod codec;
+mod command;
+mod control;
+mod firmware;
+mod handshake;
+mod irrigation;
+mod listener;
+mod mqtt;
 mod packet;
+mod rules;
+mod sensors;
 mod server;
+mod session;
+mod settings;
+mod telemetry;
+mod upload;
+mod websocket;
+
+pub use client::ClientPacket;
+pub use clock::ClockConfig;
+pub use command::{CommandConfig, CommandOutcome, CommandRecord, CommandStatus, DeviceCommand};
+pub use control::{
+    ControlConfig, ControlPolicy, ControlSettings, CoolerSettings, FarmState, PidGains,
+    SensorSnapshot,
+};
+pub use firmware::{
+    hex, parse_sha256, Firmware, FirmwareError, FirmwareInfo, RolloutStatus, UpdateState,
+    MAX_FIRMWARE_SIZE,
+};
+pub use irrigation::{IrrigationSettings, IrrigationWindow};
+pub use listener::DeviceGateway;
+pub use mqtt::MqttConfig;
+pub use rules::{Action, Condition, ConditionTrace, Rule, RuleTrace};
+pub use sensors::{Calibrated, CentiCelsius, CentiPercent, Readings};
+pub use server::ServerPacket;
+pub use session::SessionConfig;
+pub use settings::{CameraResolution, DeviceSettings, MAX_PUMP_PULSE};
+pub use telemetry::{
+    SensorDefinition, SensorRecord, SensorRegistry, SensorType, StoredReading, Unit,
+};
+pub use upload::UploadConfig;
+
+/// Everything needed to speak the device protocol from either end of the connection
+pub mod protocol {
+    pub use super::auth::{sign, NONCE_LENGTH};
+    pub use super::capture::{read_capture, CaptureRecord, CaptureWriter, Direction};
+    pub use super::client::{ClientPacket, ClientPacketId};
+    pub use super::codec::{Frame, PacketCodec, MAX_FRAME_LENGTH};
+    pub use super::command::DeviceCommand;
+    pub use super::firmware::FirmwareStatus;
+    pub use super::handshake::{Capabilities, PROTOCOL_VERSIONS};
+    pub use super::packet::{PacketError, PacketField, PacketHeader, PacketId};
+    pub use super::sensors::{Calibrated, CentiCelsius, CentiPercent, Readings};
+    pub use super::server::{ServerPacket, ServerPacketId};
+    pub use super::settings::{CameraResolution, DeviceSettings};
+    pub use super::telemetry::{SensorRecord, SensorType, Unit};
+    pub use super::upload::ImageChecksum;
+}
 
 pub type ServiceHandle =
     super::ServiceHandle<ServiceRequest, Result<ServiceResponse, ServiceError>>;
@@ -28,7 +98,13 @@ pub type ServiceHandle =
 type ServiceChannel = super::ServiceRequest<ServiceRequest, Result<ServiceResponse, ServiceError>>;
 
 struct ServerClient {
-    target_temperature: i32,
+    target_temperature: CentiCelsius,
+    policy: Box<dyn ControlPolicy>,
+    control: ControlSettings,
+    cooler: CoolerGuard,
+    capabilities: Capabilities,
+    protocol_version: u16,
+    firmware_version: String,
     sender: Sender<ServerPacket>,
 }
 
@@ -37,71 +113,269 @@ pub struct Service {
     receiver: Receiver<ServiceChannel>,
     db: DBServiceHandle,
     clients: HashMap<[char; 64], ServerClient>,
-    test_image: Option<Vec<u8>>,
+    events: broadcast::Sender<DeviceEvent>,
+    commands: CommandTracker,
+    gateway: DeviceGateway,
+    control: ControlConfig,
+    sensors: SensorRegistry,
+    /// Schedules and pump budgets of every farm that connected since the start
+    irrigation: HashMap<[char; 64], Irrigation>,
+    /// Automation rules of every farm that connected since the start
+    rules: HashMap<[char; 64], RuleEngine>,
+    image_dir: Option<PathBuf>,
+    firmware: FirmwareRepository,
+    rollout: Option<Rollout>,
+    next_rollout_id: u32,
+}
+
+/// Everything the farm service is configured with
+#[derive(Debug, Clone)]
+pub struct FarmConfig {
+    /// Address of the raw TCP device listener
+    pub device_listen: SocketAddr,
+    pub session: SessionConfig,
+    pub commands: CommandConfig,
+    pub control: ControlConfig,
+    /// Sensor types accepted in reports, readings of anything else are dropped
+    pub sensors: SensorRegistry,
+    /// Bridge MQTT devices through this broker
+    pub mqtt: Option<MqttConfig>,
+    /// Keep reported images in `<image_dir>/<device id>/`, images are dropped when unset
+    pub image_dir: Option<PathBuf>,
+    /// Keep uploaded firmware in `<firmware_dir>/<version>.bin`, only in memory when unset
+    pub firmware_dir: Option<PathBuf>,
+}
+
+/// Device lifecycle notifications for anyone subscribed through [`ServiceRequest::SubscribeEvents`]
+#[derive(Debug, Clone)]
+pub enum DeviceEvent {
+    Connected {
+        id: [char; 64],
+    },
+    Disconnected {
+        id: [char; 64],
+        reason: String,
+    },
+    CommandCompleted(CommandRecord),
+    /// A device moved to another state of the current rollout
+    UpdateChanged {
+        id: [char; 64],
+        state: UpdateState,
+    },
+    /// A device reported with a clock off by `millis`, it has been sent the time again
+    ClockSkew {
+        id: [char; 64],
+        millis: i64,
+    },
+    /// An automation rule of the farm started matching
+    Alert {
+        id: [char; 64],
+        rule: String,
+        message: String,
+    },
 }
 
+#[allow(clippy::large_enum_variant)]
 pub enum ServiceRequest {
     Pair {
         access_token: [char; 128],
         device_id: [char; 64],
     },
     ReceiverCommand(ClientReceiverCommand),
+    SubscribeEvents,
+    /// Send an actuator command, answered with [`ServiceResponse::CommandSent`]
+    SendCommand {
+        device_id: [char; 64],
+        command: DeviceCommand,
+    },
+    /// Look up a command sent earlier, answered with [`ServiceResponse::CommandStatus`]
+    CommandStatus {
+        sequence: u32,
+    },
+    /// Resend unacknowledged commands and expire the ones out of attempts
+    PollCommands,
+    /// Water the connected farms that have an irrigation pulse due
+    Irrigate,
+    /// Store the settings a device should run with and push them if it is connected,
+    /// answered with [`ServiceResponse::ConfigStored`]
+    SetDesiredConfig {
+        device_id: [char; 64],
+        settings: DeviceSettings,
+    },
+    /// Add an image already checked with `Firmware::verify` and written with `Firmware::store`
+    /// to the repository, answered with [`ServiceResponse::FirmwareStored`]
+    UploadFirmware {
+        firmware: Firmware,
+    },
+    ListFirmware,
+    /// Offer a firmware version to `devices`, or to every device when `None`, replacing
+    /// the current rollout
+    StartRollout {
+        version: String,
+        devices: Option<Vec<[char; 64]>>,
+    },
+    RolloutStatus,
+    AbortRollout,
+    /// Replace the automation rules of a farm
+    SetRules {
+        device_id: [char; 64],
+        rules: Vec<Rule>,
+    },
+    /// Evaluate `rules`, or the stored rules when `None`, on the last report of the farm
+    /// without acting on it, answered with [`ServiceResponse::RuleTraces`]
+    ExplainRules {
+        device_id: [char; 64],
+        rules: Option<Vec<Rule>>,
+    },
 }
 
-#[derive(Debug)]
-pub enum ServiceError {}
+#[derive(Debug, Clone)]
+pub enum ServiceError {
+    DeviceNotConnected,
+    UnknownDevice,
+    InvalidSettings(String),
+    InvalidFirmware(String),
+    UnknownFirmware,
+    NoRollout,
+    /// The pump of the farm already ran for its daily runtime
+    PumpBudgetExhausted,
+}
+
+impl From<ServiceError> for Json<BackendResponse> {
+    fn from(val: ServiceError) -> Self {
+        Json(BackendResponse::Error(match val {
+            ServiceError::DeviceNotConnected => "The device is not connected.".to_string(),
+            ServiceError::UnknownDevice => "Unknown device.".to_string(),
+            ServiceError::InvalidSettings(reason) | ServiceError::InvalidFirmware(reason) => reason,
+            ServiceError::UnknownFirmware => "No firmware with this version.".to_string(),
+            ServiceError::NoRollout => "No rollout has been started.".to_string(),
+            ServiceError::PumpBudgetExhausted => {
+                "The pump already ran for its daily runtime.".to_string()
+            }
+        }))
+    }
+}
 
-pub enum ServerPacket {
-    UpdateCooler { status: bool },
-    WaterPulse,
+impl From<ServiceError> for StatusCode {
+    fn from(val: ServiceError) -> Self {
+        match val {
+            ServiceError::DeviceNotConnected | ServiceError::PumpBudgetExhausted => {
+                StatusCode::CONFLICT
+            }
+            ServiceError::UnknownDevice
+            | ServiceError::UnknownFirmware
+            | ServiceError::NoRollout => StatusCode::NOT_FOUND,
+            ServiceError::InvalidSettings(..) | ServiceError::InvalidFirmware(..) => {
+                StatusCode::BAD_REQUEST
+            }
+        }
+    }
 }
 
 pub enum ClientReceiverCommand {
     ReportClient {
         id: [char; 64],
+        capabilities: Capabilities,
+        /// `0` for devices that don't speak the farm protocol, like MQTT devices
+        protocol_version: u16,
+        firmware_version: String,
         sender: Sender<ServerPacket>,
     },
     ReportSensors {
         id: [char; 64],
-        soil_moisture: u16,
-        air_temperature: u16,
-        light_sensor: u16,
+        records: Vec<SensorRecord>,
+        captured_at: SystemTime,
         image: Vec<u8>,
     },
-}
-
-pub enum ClientPacket {
-    ReportId {
+    /// A report was stamped with a time the device clock can't have been right at
+    ClockSkew {
         id: [char; 64],
+        /// Device time minus server time
+        millis: i64,
     },
-    ReportSensors {
-        soil_moisture: u16,
-        air_temperature: u16,
-        light_sensor: u16,
-        image_size: usize,
+    /// The session of an active device ended, `sender` tells which connection it was
+    Disconnect {
+        id: [char; 64],
+        sender: Sender<ServerPacket>,
+        reason: String,
     },
-    ImageFrame {
-        frame_size: usize,
-        frame: [u8; 128],
+    CommandResult {
+        id: [char; 64],
+        sequence: u32,
+        outcome: CommandOutcome,
+    },
+    /// The settings an authenticated device runs with
+    ReportConfig {
+        id: [char; 64],
+        settings: DeviceSettings,
+    },
+    FirmwareRequest {
+        id: [char; 64],
+        update_id: u32,
+        offset: u32,
+    },
+    FirmwareReport {
+        id: [char; 64],
+        update_id: u32,
+        status: FirmwareStatus,
     },
 }
 
 pub enum ServiceResponse {
     Image(Option<Vec<u8>>),
+    Events(broadcast::Receiver<DeviceEvent>),
+    CommandSent {
+        sequence: u32,
+    },
+    CommandStatus(Option<CommandStatus>),
+    /// `sequence` of the `Configure` command when the device was connected
+    ConfigStored {
+        sequence: Option<u32>,
+    },
+    FirmwareStored(FirmwareInfo),
+    Firmware(Vec<FirmwareInfo>),
+    Rollout(RolloutStatus),
+    RuleTraces(Vec<RuleTrace>),
     Empty,
 }
 
 impl Service {
-    pub fn new(db: DBServiceHandle) -> Self {
+    /// Create the farm service, device connections are wrapped in TLS when `tls` is given
+    pub fn new(db: DBServiceHandle, tls: Option<RustlsConfig>, config: FarmConfig) -> Self {
         let (sender, receiver) = channel(16);
         let (client_sender, clients_receiver) = channel(64);
-        tokio::spawn(Self::server_listener(client_sender));
+        let (events, _) = broadcast::channel(64);
+        if let Some(mqtt) = config.mqtt {
+            let (bridge, event_loop) =
+                mqtt::MqttBridge::new(mqtt, client_sender.clone(), db.clone());
+            tokio::spawn(bridge.run(event_loop));
+        }
+        let gateway = DeviceGateway::new(client_sender, db.clone(), config.session);
+        tokio::spawn(Self::server_listener(
+            gateway.clone(),
+            tls,
+            config.device_listen,
+        ));
         let service = Self {
             sender,
             receiver,
             clients: HashMap::new(),
             db,
-            test_image: None,
+            gateway,
+            events,
+            commands: CommandTracker::new(config.commands),
+            control: config.control,
+            sensors: config.sensors,
+            irrigation: HashMap::new(),
+            rules: HashMap::new(),
+            image_dir: config.image_dir,
+            firmware: FirmwareRepository::load(config.firmware_dir),
+            rollout: None,
+            // Update ids outlive restarts on the devices, don't hand out the same ones again
+            next_rollout_id: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs() as u32,
         };
         tokio::spawn(Self::server_main(
             super::Service::get(&service),
@@ -114,15 +388,18 @@ impl Service {
         service: ServiceHandle,
         mut clients_receiver: Receiver<ClientReceiverCommand>,
     ) {
+        let mut poll = interval(Duration::from_secs(1));
+        let mut irrigate = interval(irrigation::CHECK_INTERVAL);
         loop {
-            let command = match clients_receiver.recv().await {
-                Some(command) => command,
-                None => continue,
+            let request = tokio::select! {
+                command = clients_receiver.recv() => match command {
+                    Some(command) => ServiceRequest::ReceiverCommand(command),
+                    None => break,
+                },
+                _ = poll.tick() => ServiceRequest::PollCommands,
+                _ = irrigate.tick() => ServiceRequest::Irrigate,
             };
-            match service
-                .request(ServiceRequest::ReceiverCommand(command))
-                .await
-            {
+            match service.request(request).await {
                 Ok(_) => {}
                 Err(err) => {
                     println!("{err:?}");
@@ -134,41 +411,651 @@ impl Service {
     async fn process_sensor(
         &mut self,
         id: [char; 64],
-        soil_moisture: u16,
-        air_temperature: u16,
-        light_sensor: u16,
+        records: Vec<SensorRecord>,
+        captured_at: SystemTime,
         image: Vec<u8>,
     ) {
-        let client = match self.clients.get(&id) {
+        let mut accepted = Vec::new();
+        let mut readings = Vec::new();
+        for record in records {
+            match self.sensors.check(&record) {
+                Ok(reading) => {
+                    accepted.push(record);
+                    readings.push(reading);
+                }
+                Err(err) => println!(
+                    "Dropping reading of {}: {err}",
+                    id.iter().collect::<String>()
+                ),
+            }
+        }
+
+        let client = match self.clients.get_mut(&id) {
             Some(client) => client,
             None => {
                 println!("Unknown client with id {}", id.iter().collect::<String>());
                 return;
             }
         };
-        client
-            .sender
-            .send(ServerPacket::UpdateCooler {
-                status: (air_temperature as i32) > client.target_temperature,
+        let snapshot = SensorSnapshot {
+            records: &accepted,
+            captured_at,
+        };
+        let farm = FarmState {
+            id,
+            capabilities: client.capabilities,
+            protocol_version: client.protocol_version,
+            target_temperature: client.target_temperature,
+            settings: client.control,
+            cooler: client.cooler.state(),
+        };
+        let timezone = self
+            .irrigation
+            .get(&id)
+            .map_or(Tz::UTC, Irrigation::timezone);
+        let decision = self.rules.entry(id).or_default().evaluate(&RuleContext {
+            readings: &readings,
+            now: DateTime::<Utc>::from(captured_at).with_timezone(&timezone),
+            cooler: farm.cooler,
+        });
+        for (rule, message) in decision.alerts.iter().cloned() {
+            println!(
+                "Alert from {} by rule {rule}: {message}",
+                id.iter().collect::<String>()
+            );
+            self.events
+                .send(DeviceEvent::Alert { id, rule, message })
+                .ok();
+        }
+        let now = Instant::now();
+        let commands = decision
+            .merge(client.policy.decide(&snapshot, &farm))
+            .into_iter()
+            .filter(|command| {
+                let runnable = farm.can_run(command);
+                if !runnable {
+                    println!(
+                        "Not sending {command:?} to {}, the device can't run it",
+                        id.iter().collect::<String>()
+                    );
+                }
+                runnable
             })
-            .await
-            .unwrap();
-        if soil_moisture > 500 {
-            client.sender.send(ServerPacket::WaterPulse).await.unwrap();
+            .filter(|command| match command {
+                DeviceCommand::Cooler { status } => {
+                    client.cooler.allow(*status, &client.control.cooler, now)
+                }
+                _ => true,
+            })
+            .collect::<Vec<_>>();
+        for command in commands {
+            match self.send_command(id, command).await {
+                Ok(..) => {}
+                Err(ServiceError::PumpBudgetExhausted) => println!(
+                    "Not watering {}, the pump already ran for its daily runtime",
+                    id.iter().collect::<String>()
+                ),
+                // The readings and image are still stored below
+                Err(..) => break,
+            }
         }
 
         println!(
-            "Id: {}, soil_moisture: {}, air_temperature: {}, light_sensor: {}",
+            "Id: {}, {}",
             id.iter().collect::<String>(),
-            soil_moisture,
-            air_temperature,
-            light_sensor
+            readings
+                .iter()
+                .map(|reading| format!(
+                    "{}[{}]: {} {}",
+                    reading.sensor,
+                    reading.index,
+                    reading.value,
+                    reading.unit.name()
+                ))
+                .collect::<Vec<_>>()
+                .join(", ")
         );
+        self.store_readings(id, captured_at, readings);
+        self.store_image(id, captured_at, image);
+    }
+
+    /// The policy and settings the farm picked in the database, or the default ones
+    async fn load_control(&self, id: [char; 64]) -> (Box<dyn ControlPolicy>, ControlSettings) {
+        let (name, settings) = match self.db.request(DBServiceRequest::GetControl { id }).await {
+            Ok(DBServiceResponse::Control { policy, settings }) => (policy, settings),
+            Ok(..) => unreachable!(),
+            Err(..) => (None, None),
+        };
+        let name = name.unwrap_or_else(|| control::DEFAULT_POLICY.to_string());
+        let policy = control::policy(&name, &self.control).unwrap_or_else(|| {
+            println!(
+                "Unknown control policy {name} for {}, using {}",
+                id.iter().collect::<String>(),
+                control::DEFAULT_POLICY
+            );
+            control::policy(control::DEFAULT_POLICY, &self.control).unwrap()
+        });
+        (policy, settings.unwrap_or_default())
+    }
+
+    /// Pick up the automation rules of a farm, keeping what they did so far
+    async fn load_rules(&mut self, id: [char; 64]) -> Result<(), ServiceError> {
+        let rules = match self.db.request(DBServiceRequest::GetRules { id }).await {
+            Ok(DBServiceResponse::Rules(rules)) => rules,
+            Ok(..) => unreachable!(),
+            Err(..) => return Err(ServiceError::UnknownDevice),
+        };
+        self.rules.entry(id).or_default().configure(rules);
+        Ok(())
+    }
+
+    async fn set_rules(&mut self, id: [char; 64], rules: Vec<Rule>) -> Result<(), ServiceError> {
+        rules::validate(&rules).map_err(ServiceError::InvalidSettings)?;
+        self.db
+            .request(DBServiceRequest::SetRules {
+                id,
+                rules: rules.clone(),
+            })
+            .await
+            .map_err(|_| ServiceError::UnknownDevice)?;
+        self.rules.entry(id).or_default().configure(rules);
+        Ok(())
+    }
+
+    async fn explain_rules(
+        &mut self,
+        id: [char; 64],
+        rules: Option<Vec<Rule>>,
+    ) -> Result<Vec<RuleTrace>, ServiceError> {
+        if let Some(rules) = &rules {
+            rules::validate(rules).map_err(ServiceError::InvalidSettings)?;
+        }
+        if !self.rules.contains_key(&id) {
+            self.load_rules(id).await?;
+        }
+        let engine = &self.rules[&id];
+        let timezone = self
+            .irrigation
+            .get(&id)
+            .map_or(Tz::UTC, Irrigation::timezone);
+        let context = RuleContext {
+            readings: engine.latest(),
+            now: DateTime::<Utc>::from(SystemTime::now()).with_timezone(&timezone),
+            cooler: self
+                .clients
+                .get(&id)
+                .and_then(|client| client.cooler.state()),
+        };
+        Ok(engine.explain(rules.as_deref(), &context))
+    }
+
+    /// Pick up the irrigation settings of a farm and, on a new day of the farm, the
+    /// runtime its pump already ran for
+    async fn load_irrigation(&mut self, id: [char; 64]) {
+        let settings = match self
+            .db
+            .request(DBServiceRequest::GetIrrigation { id })
+            .await
+        {
+            Ok(DBServiceResponse::Irrigation(settings)) => settings,
+            Ok(..) => unreachable!(),
+            Err(..) => None,
+        };
+        let irrigation = self.irrigation.entry(id).or_default();
+        irrigation.configure(settings.unwrap_or_default());
+        let now = SystemTime::now();
+        let Some(since) = irrigation.unknown_day(now) else {
+            return;
+        };
+        match self
+            .db
+            .request(DBServiceRequest::GetPumpRuntime { id, since })
+            .await
+        {
+            Ok(DBServiceResponse::PumpRuntime(used)) => {
+                if let Some(irrigation) = self.irrigation.get_mut(&id) {
+                    irrigation.restore(used, now);
+                }
+            }
+            Ok(..) => unreachable!(),
+            Err(err) => println!(
+                "Cannot load the pump runtime of {}: {err:?}",
+                id.iter().collect::<String>()
+            ),
+        }
+    }
+
+    /// Send the irrigation pulses due on every connected farm with a pump
+    async fn irrigate(&mut self) {
+        let now = SystemTime::now();
+        let since = DeviceCommand::Water { millis: 0 }.since_version();
+        let mut commands = Vec::new();
+        for (id, client) in &self.clients {
+            if !client.capabilities.contains(Capabilities::WATER_PUMP) {
+                continue;
+            }
+            let Some(irrigation) = self.irrigation.get_mut(id) else {
+                continue;
+            };
+            let timed = client.protocol_version == 0 || since <= client.protocol_version;
+            for runtime in irrigation.due(now) {
+                commands.push((*id, irrigation.commands(runtime, timed)));
+            }
+        }
+        for (id, run) in commands {
+            for command in run {
+                match self.send_command(id, command).await {
+                    Ok(..) => {}
+                    Err(ServiceError::PumpBudgetExhausted) => {
+                        println!(
+                            "Skipping irrigation of {}, the pump already ran for its daily runtime",
+                            id.iter().collect::<String>()
+                        );
+                        break;
+                    }
+                    Err(..) => break,
+                }
+            }
+        }
+    }
+
+    /// Record a pump run in the background
+    fn store_pump_run(&self, id: [char; 64], runtime: Duration) {
+        let db = self.db.clone();
+        tokio::spawn(async move {
+            let request = DBServiceRequest::StorePumpRun {
+                id,
+                started_at: SystemTime::now(),
+                runtime,
+            };
+            if let Err(err) = db.request(request).await {
+                println!(
+                    "Cannot store a pump run of {}: {err:?}",
+                    id.iter().collect::<String>()
+                );
+            }
+        });
+    }
+
+    /// Save validated readings in the background
+    fn store_readings(
+        &self,
+        id: [char; 64],
+        captured_at: SystemTime,
+        readings: Vec<StoredReading>,
+    ) {
+        if readings.is_empty() {
+            return;
+        }
+        let db = self.db.clone();
+        tokio::spawn(async move {
+            let request = DBServiceRequest::StoreReadings {
+                id,
+                captured_at,
+                readings,
+            };
+            if let Err(err) = db.request(request).await {
+                println!(
+                    "Cannot store readings of {}: {err:?}",
+                    id.iter().collect::<String>()
+                );
+            }
+        });
+    }
+
+    /// Write a reported image to `<image_dir>/<device id>/<capture unix millis>.jpg`
+    /// in the background
+    fn store_image(&self, id: [char; 64], captured_at: SystemTime, image: Vec<u8>) {
+        let Some(image_dir) = &self.image_dir else {
+            return;
+        };
+        if image.is_empty() {
+            return;
+        }
+        let dir = image_dir.join(id.iter().collect::<String>());
+        let millis = clock::unix_millis(captured_at);
+        tokio::spawn(async move {
+            let path = dir.join(format!("{millis}.jpg"));
+            let result = match tokio::fs::create_dir_all(&dir).await {
+                Ok(()) => tokio::fs::write(&path, image).await,
+                Err(err) => Err(err),
+            };
+            if let Err(err) = result {
+                println!("Cannot store image {}: {err}", path.display());
+            }
+        });
+    }
+
+    fn remove_client(&mut self, id: [char; 64], reason: String) {
+        if self.clients.remove(&id).is_some() {
+            println!(
+                "Device {} disconnected: {reason}",
+                id.iter().collect::<String>()
+            );
+            for record in self.commands.disconnect(id) {
+                self.command_completed(record);
+            }
+            self.events
+                .send(DeviceEvent::Disconnected { id, reason })
+                .ok();
+        }
+    }
+
+    /// Send a tracked command to a connected device and return its sequence number
+    async fn send_command(
+        &mut self,
+        id: [char; 64],
+        command: DeviceCommand,
+    ) -> Result<u32, ServiceError> {
+        if let DeviceCommand::Configure(settings) = &command {
+            settings.validate().map_err(ServiceError::InvalidSettings)?;
+        }
+        let sender = match self.clients.get(&id) {
+            Some(client) => client.sender.clone(),
+            None => return Err(ServiceError::DeviceNotConnected),
+        };
+        if let Some(irrigation) = self.irrigation.get_mut(&id) {
+            if let Some(runtime) = irrigation.runtime(&command) {
+                if !irrigation.reserve(runtime, SystemTime::now()) {
+                    return Err(ServiceError::PumpBudgetExhausted);
+                }
+            }
+        }
+        let sequence = self.commands.issue(id, command.clone(), Instant::now());
+        if sender.send(command.packet(sequence)).await.is_err() {
+            // Completes the command just issued, which gives its pump runtime back
+            self.remove_client(id, "Connection task has stopped".to_string());
+            return Err(ServiceError::DeviceNotConnected);
+        }
+        Ok(sequence)
+    }
+
+    fn command_completed(&mut self, record: CommandRecord) {
+        let delivered = matches!(
+            record.outcome,
+            CommandOutcome::Acknowledged | CommandOutcome::Unconfirmed
+        );
+        if let (Some(client), false) = (self.clients.get_mut(&record.id), delivered) {
+            if let DeviceCommand::Cooler { status } = record.command {
+                client.cooler.forget(status);
+            }
+            client.policy.forget(&record.command);
+        }
+        let runtime = self
+            .irrigation
+            .get(&record.id)
+            .and_then(|irrigation| irrigation.runtime(&record.command));
+        if let Some(runtime) = runtime {
+            if delivered {
+                self.store_pump_run(record.id, runtime);
+            } else if let Some(irrigation) = self.irrigation.get_mut(&record.id) {
+                irrigation.refund(runtime, SystemTime::now());
+            }
+        }
+        self.events.send(DeviceEvent::CommandCompleted(record)).ok();
+    }
+
+    async fn poll_commands(&mut self) {
+        let (retries, expired) = self.commands.poll(Instant::now());
+        for record in expired {
+            println!(
+                "Command {} to {} timed out after {} attempts",
+                record.sequence,
+                record.id.iter().collect::<String>(),
+                record.attempts
+            );
+            self.command_completed(record);
+        }
+        for retry in retries {
+            let sender = match self.clients.get(&retry.id) {
+                Some(client) => client.sender.clone(),
+                None => continue,
+            };
+            if sender
+                .send(retry.command.packet(retry.sequence))
+                .await
+                .is_err()
+            {
+                self.remove_client(retry.id, "Connection task has stopped".to_string());
+            }
+        }
+    }
+
+    /// Remember what a device runs with and push the desired settings when they differ
+    async fn reconcile_config(&mut self, id: [char; 64], reported: DeviceSettings) {
+        let name = id.iter().collect::<String>();
+        self.irrigation.entry(id).or_default().pump_pulse =
+            Duration::from_millis(reported.pump_pulse.into());
+        let stored = self
+            .db
+            .request(DBServiceRequest::StoreReportedConfig {
+                id,
+                settings: reported.clone(),
+            })
+            .await;
+        if let Err(err) = stored {
+            println!("Cannot store the config reported by {name}: {err:?}");
+        }
+        let desired = match self
+            .db
+            .request(DBServiceRequest::GetDesiredConfig { id })
+            .await
+        {
+            Ok(DBServiceResponse::DesiredConfig(desired)) => desired,
+            Ok(..) => unreachable!(),
+            Err(err) => {
+                println!("Cannot look up the desired config of {name}: {err:?}");
+                return;
+            }
+        };
+        match desired {
+            Some(desired) if desired != reported => {
+                println!("Device {name} runs with outdated settings, reconfiguring");
+                self.send_command(id, DeviceCommand::Configure(desired))
+                    .await
+                    .ok();
+            }
+            _ => {}
+        }
+    }
+
+    async fn set_desired_config(
+        &mut self,
+        id: [char; 64],
+        settings: DeviceSettings,
+    ) -> Result<Option<u32>, ServiceError> {
+        settings.validate().map_err(ServiceError::InvalidSettings)?;
+        self.db
+            .request(DBServiceRequest::SetDesiredConfig {
+                id,
+                settings: settings.clone(),
+            })
+            .await
+            .map_err(|_| ServiceError::UnknownDevice)?;
+        if !self.clients.contains_key(&id) {
+            return Ok(None);
+        }
+        let sequence = self
+            .send_command(id, DeviceCommand::Configure(settings))
+            .await?;
+        Ok(Some(sequence))
+    }
+
+    fn set_update_state(&mut self, id: [char; 64], state: UpdateState) {
+        let Some(rollout) = self.rollout.as_mut() else {
+            return;
+        };
+        // Download progress is only announced once
+        if rollout.set(id, state.clone()) {
+            println!(
+                "Firmware {} on {}: {state:?}",
+                rollout.firmware.version,
+                id.iter().collect::<String>()
+            );
+            self.events
+                .send(DeviceEvent::UpdateChanged { id, state })
+                .ok();
+        }
+    }
+
+    /// Offer the rollout firmware to a device, or settle its update if it already runs it
+    async fn offer_update(&mut self, id: [char; 64]) {
+        let (Some(rollout), Some(client)) = (self.rollout.as_ref(), self.clients.get(&id)) else {
+            return;
+        };
+        let state = rollout.state(&id);
+        if !rollout.targets(&id) || state.is_some_and(|state| state.is_finished()) {
+            return;
+        }
+        let next = if client.firmware_version == rollout.firmware.version {
+            UpdateState::Succeeded
+        } else if state == Some(&UpdateState::Applying) {
+            UpdateState::Failed {
+                reason: format!("Came back running {}", client.firmware_version),
+            }
+        } else if client.protocol_version < ServerPacketId::FirmwareOffer.since_version() {
+            UpdateState::Unsupported
+        } else {
+            let offer = ServerPacket::FirmwareOffer {
+                update_id: rollout.id,
+                version: rollout.firmware.version.clone(),
+                size: rollout.firmware.size(),
+                sha256: rollout.firmware.sha256,
+                chunk_size: FIRMWARE_CHUNK_SIZE,
+            };
+            if client.sender.send(offer).await.is_err() {
+                self.remove_client(id, "Connection task has stopped".to_string());
+                return;
+            }
+            UpdateState::Offered
+        };
+        self.set_update_state(id, next);
+    }
+
+    async fn send_firmware_chunk(&mut self, id: [char; 64], update_id: u32, offset: u32) {
+        let Some(sender) = self.clients.get(&id).map(|client| client.sender.clone()) else {
+            return;
+        };
+        let updating = self.rollout.as_ref().filter(|rollout| {
+            rollout.id == update_id && rollout.state(&id).is_some_and(|state| !state.is_finished())
+        });
+        let (packet, state) = match updating {
+            Some(rollout) => match rollout.firmware.chunk(offset, FIRMWARE_CHUNK_SIZE) {
+                Some(data) => (
+                    ServerPacket::FirmwareChunk {
+                        update_id,
+                        offset,
+                        data: data.to_vec(),
+                    },
+                    Some(UpdateState::Downloading {
+                        offset: offset + data.len() as u32,
+                        size: rollout.firmware.size(),
+                    }),
+                ),
+                None => (
+                    ServerPacket::FirmwareAbort {
+                        update_id,
+                        reason: format!("Offset {offset} is past the end of the image"),
+                    },
+                    Some(UpdateState::Failed {
+                        reason: format!("Asked for offset {offset}"),
+                    }),
+                ),
+            },
+            None => (
+                ServerPacket::FirmwareAbort {
+                    update_id,
+                    reason: "The update is no longer offered".to_string(),
+                },
+                None,
+            ),
+        };
+        if sender.send(packet).await.is_err() {
+            self.remove_client(id, "Connection task has stopped".to_string());
+            return;
+        }
+        if let Some(state) = state {
+            self.set_update_state(id, state);
+        }
+    }
+
+    fn process_firmware_report(&mut self, id: [char; 64], update_id: u32, status: FirmwareStatus) {
+        let next = self
+            .rollout
+            .as_ref()
+            .filter(|rollout| rollout.id == update_id)
+            .and_then(|rollout| rollout.state(&id))
+            .and_then(|state| state.after(status));
+        if let Some(state) = next {
+            self.set_update_state(id, state);
+        }
+    }
+
+    async fn start_rollout(
+        &mut self,
+        version: String,
+        devices: Option<Vec<[char; 64]>>,
+    ) -> Result<RolloutStatus, ServiceError> {
+        let firmware = self
+            .firmware
+            .get(&version)
+            .ok_or(ServiceError::UnknownFirmware)?
+            .clone();
+        if self.rollout.as_ref().is_some_and(Rollout::is_active) {
+            self.abort_rollout().await?;
+        }
+        let target = match devices {
+            Some(devices) => RolloutTarget::Devices(devices.into_iter().collect()),
+            None => RolloutTarget::All,
+        };
+        let id = self.next_rollout_id;
+        self.next_rollout_id = self.next_rollout_id.wrapping_add(1);
+        println!("Starting rollout {id} of firmware {version}");
+        self.rollout = Some(Rollout::new(id, firmware, target));
+        let connected = self.clients.keys().copied().collect::<Vec<_>>();
+        for id in connected {
+            self.offer_update(id).await;
+        }
+        Ok(self.rollout.as_ref().unwrap().status())
+    }
+
+    /// Stop the current rollout, devices in the middle of a download are told to drop it
+    async fn abort_rollout(&mut self) -> Result<RolloutStatus, ServiceError> {
+        let rollout = self.rollout.as_mut().ok_or(ServiceError::NoRollout)?;
+        let update_id = rollout.id;
+        let unfinished = rollout.abort();
+        let status = rollout.status();
+        println!("Aborted rollout {update_id}");
+        for id in unfinished {
+            self.events
+                .send(DeviceEvent::UpdateChanged {
+                    id,
+                    state: UpdateState::Aborted,
+                })
+                .ok();
+            let Some(client) = self.clients.get(&id) else {
+                continue;
+            };
+            let abort = ServerPacket::FirmwareAbort {
+                update_id,
+                reason: "The rollout was aborted".to_string(),
+            };
+            if client.sender.send(abort).await.is_err() {
+                self.remove_client(id, "Connection task has stopped".to_string());
+            }
+        }
+        Ok(status)
     }
 
     async fn process_command(&mut self, command: ClientReceiverCommand) {
         match command {
-            ClientReceiverCommand::ReportClient { id, sender } => {
+            ClientReceiverCommand::ReportClient {
+                id,
+                capabilities,
+                protocol_version,
+                firmware_version,
+                sender,
+            } => {
                 let temperature = match self
                     .db
                     .request(DBServiceRequest::GetTemperature { id })
@@ -178,48 +1065,84 @@ impl Service {
                     Ok(..) => unreachable!(),
                     Err(..) => 0,
                 };
+                let (policy, control) = self.load_control(id).await;
+                self.load_irrigation(id).await;
+                if let Err(err) = self.load_rules(id).await {
+                    println!(
+                        "Cannot load the rules of {}: {err:?}",
+                        id.iter().collect::<String>()
+                    );
+                }
                 self.clients.insert(
                     id,
                     ServerClient {
-                        target_temperature: temperature,
+                        target_temperature: CentiCelsius::from_degrees(temperature),
+                        policy,
+                        control,
+                        cooler: CoolerGuard::default(),
+                        capabilities,
+                        protocol_version,
+                        firmware_version,
                         sender,
                     },
                 );
+                self.events.send(DeviceEvent::Connected { id }).ok();
+                self.offer_update(id).await;
+            }
+            ClientReceiverCommand::Disconnect { id, sender, reason } => {
+                // A device that reconnected quickly may already own a newer session
+                let current = self
+                    .clients
+                    .get(&id)
+                    .is_some_and(|client| client.sender.same_channel(&sender));
+                if current {
+                    self.remove_client(id, reason);
+                }
+            }
+            ClientReceiverCommand::CommandResult {
+                id,
+                sequence,
+                outcome,
+            } => {
+                if let Some(record) = self.commands.complete(id, sequence, outcome) {
+                    self.command_completed(record);
+                }
             }
             ClientReceiverCommand::ReportSensors {
                 id,
-                soil_moisture,
-                air_temperature,
-                light_sensor,
+                records,
+                captured_at,
                 image,
-            } => {
-                self.process_sensor(id, soil_moisture, air_temperature, light_sensor, image)
-                    .await
+            } => self.process_sensor(id, records, captured_at, image).await,
+            ClientReceiverCommand::ClockSkew { id, millis } => {
+                self.events.send(DeviceEvent::ClockSkew { id, millis }).ok();
             }
+            ClientReceiverCommand::ReportConfig { id, settings } => {
+                self.reconcile_config(id, settings).await
+            }
+            ClientReceiverCommand::FirmwareRequest {
+                id,
+                update_id,
+                offset,
+            } => self.send_firmware_chunk(id, update_id, offset).await,
+            ClientReceiverCommand::FirmwareReport {
+                id,
+                update_id,
+                status,
+            } => self.process_firmware_report(id, update_id, status),
         };
     }
 
-    async fn server_listener(sender: Sender<ClientReceiverCommand>) {
-        let listener = TcpListener::bind(SocketAddr::new(
-            local_ip().expect("Cannot get local ip"),
-            4000,
-        ))
-        .await
-        .expect("Cannot bind to port 4000");
-        loop {
-            let sender = sender.clone();
-            match listener.accept().await {
-                Ok((stream, addr)) => tokio::spawn(async move {
-                    println!("Incoming connection {addr}");
-                    let mut client = Client::new(sender, stream, addr);
-                    client.run().await;
-                }),
-                Err(error) => {
-                    println!("Failed to connect with a device {error}");
-                    continue;
-                }
-            };
-        }
+    /// Entry point for devices connecting through the web server instead of the device listener
+    pub fn gateway(&self) -> DeviceGateway {
+        self.gateway.clone()
+    }
+
+    async fn server_listener(gateway: DeviceGateway, tls: Option<RustlsConfig>, addr: SocketAddr) {
+        let listener = TcpListener::bind(addr)
+            .await
+            .unwrap_or_else(|err| panic!("Cannot bind the device listener to {addr}: {err}"));
+        listener::serve_devices(listener, tls, gateway).await;
     }
 }
 
@@ -240,13 +1163,61 @@ impl super::Service<ServiceRequest, Result<ServiceResponse, ServiceError>> for S
     async fn process(&mut self, data: ServiceRequest) -> Result<ServiceResponse, ServiceError> {
         match data {
             ServiceRequest::Pair {
-                access_token,
-                device_id,
+                access_token: _,
+                device_id: _,
             } => Ok(ServiceResponse::Empty),
             ServiceRequest::ReceiverCommand(command) => {
                 self.process_command(command).await;
-                return Ok(ServiceResponse::Empty);
+                Ok(ServiceResponse::Empty)
+            }
+            ServiceRequest::SubscribeEvents => Ok(ServiceResponse::Events(self.events.subscribe())),
+            ServiceRequest::SendCommand { device_id, command } => {
+                let sequence = self.send_command(device_id, command).await?;
+                Ok(ServiceResponse::CommandSent { sequence })
+            }
+            ServiceRequest::CommandStatus { sequence } => Ok(ServiceResponse::CommandStatus(
+                self.commands.status(sequence),
+            )),
+            ServiceRequest::PollCommands => {
+                self.poll_commands().await;
+                Ok(ServiceResponse::Empty)
+            }
+            ServiceRequest::Irrigate => {
+                self.irrigate().await;
+                Ok(ServiceResponse::Empty)
+            }
+            ServiceRequest::SetDesiredConfig {
+                device_id,
+                settings,
+            } => {
+                let sequence = self.set_desired_config(device_id, settings).await?;
+                Ok(ServiceResponse::ConfigStored { sequence })
+            }
+            ServiceRequest::UploadFirmware { firmware } => {
+                let info = self
+                    .firmware
+                    .add(firmware)
+                    .map_err(|err| ServiceError::InvalidFirmware(err.to_string()))?;
+                Ok(ServiceResponse::FirmwareStored(info))
+            }
+            ServiceRequest::ListFirmware => Ok(ServiceResponse::Firmware(self.firmware.list())),
+            ServiceRequest::StartRollout { version, devices } => Ok(ServiceResponse::Rollout(
+                self.start_rollout(version, devices).await?,
+            )),
+            ServiceRequest::RolloutStatus => {
+                let rollout = self.rollout.as_ref().ok_or(ServiceError::NoRollout)?;
+                Ok(ServiceResponse::Rollout(rollout.status()))
+            }
+            ServiceRequest::AbortRollout => {
+                Ok(ServiceResponse::Rollout(self.abort_rollout().await?))
+            }
+            ServiceRequest::SetRules { device_id, rules } => {
+                self.set_rules(device_id, rules).await?;
+                Ok(ServiceResponse::Empty)
             }
+            ServiceRequest::ExplainRules { device_id, rules } => Ok(ServiceResponse::RuleTraces(
+                self.explain_rules(device_id, rules).await?,
+            )),
         }
     }
 }
@@ -0,0 +1,66 @@
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub const NONCE_LENGTH: usize = 32;
+
+/// Generate a fresh random nonce for a device challenge
+pub fn new_challenge() -> [u8; NONCE_LENGTH] {
+    rand::thread_rng().gen()
+}
+
+fn mac(secret: &[char; 64], nonce: &[u8; NONCE_LENGTH], id: &[char; 64]) -> HmacSha256 {
+    let key = secret.iter().map(|c| *c as u8).collect::<Vec<u8>>();
+    let mut mac = HmacSha256::new_from_slice(&key).expect("HMAC accepts keys of any length");
+    mac.update(nonce);
+    mac.update(&id.iter().map(|c| *c as u8).collect::<Vec<u8>>());
+    mac
+}
+
+/// Compute the response a device holding `secret` is expected to send,
+/// `HMAC-SHA256(secret, nonce || id)`
+pub fn sign(secret: &[char; 64], nonce: &[u8; NONCE_LENGTH], id: &[char; 64]) -> [u8; 32] {
+    mac(secret, nonce, id).finalize().into_bytes().into()
+}
+
+/// Check a device response in constant time
+pub fn verify(
+    secret: &[char; 64],
+    nonce: &[u8; NONCE_LENGTH],
+    id: &[char; 64],
+    response: &[u8; 32],
+) -> bool {
+    mac(secret, nonce, id).verify_slice(response).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chars(fill: char) -> [char; 64] {
+        [fill; 64]
+    }
+
+    #[test]
+    fn accepts_matching_response() {
+        let nonce = new_challenge();
+        let response = sign(&chars('s'), &nonce, &chars('i'));
+        assert!(verify(&chars('s'), &nonce, &chars('i'), &response));
+    }
+
+    #[test]
+    fn rejects_wrong_secret_id_or_nonce() {
+        let nonce = new_challenge();
+        let response = sign(&chars('s'), &nonce, &chars('i'));
+        assert!(!verify(&chars('t'), &nonce, &chars('i'), &response));
+        assert!(!verify(&chars('s'), &nonce, &chars('j'), &response));
+        assert!(!verify(
+            &chars('s'),
+            &new_challenge(),
+            &chars('i'),
+            &response
+        ));
+    }
+}
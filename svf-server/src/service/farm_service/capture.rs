@@ -0,0 +1,192 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Read, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::mpsc::{channel, Receiver, Sender, TryRecvError},
+    thread::{self, JoinHandle},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::utils::{buffer_reader::BufferReader, buffer_writer::BufferWriter};
+
+use super::{codec::Frame, packet::PacketHeader};
+
+/// First bytes of every capture file, the last byte is the format version
+const MAGIC: &[u8; 8] = b"SVFCAP\0\x01";
+
+/// Which way a captured frame travelled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    FromDevice,
+    ToDevice,
+}
+
+/// One frame of a capture file
+///
+/// Records are stored as the capture time in microseconds since the Unix epoch
+/// (`u64`), the direction (`u8`), the 8 byte [`PacketHeader`] and the raw payload.
+/// Frames are kept as they came off the wire so packets that failed to decode can
+/// still be inspected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaptureRecord {
+    /// Time since the Unix epoch
+    pub timestamp: Duration,
+    pub direction: Direction,
+    pub frame: Frame,
+}
+
+/// Appends every frame of one device session to its own capture file
+///
+/// Records are written by a thread of their own so a slow disk doesn't hold up the
+/// session, and flushed whenever the session goes quiet.
+pub struct CaptureWriter {
+    sender: Sender<Vec<u8>>,
+    writer: JoinHandle<io::Result<()>>,
+    path: PathBuf,
+}
+
+/// Write records until the [`CaptureWriter`] is dropped
+fn write_records(file: File, receiver: Receiver<Vec<u8>>) -> io::Result<()> {
+    let mut file = BufWriter::new(file);
+    loop {
+        let record = match receiver.try_recv() {
+            Ok(record) => record,
+            Err(TryRecvError::Empty) => {
+                file.flush()?;
+                match receiver.recv() {
+                    Ok(record) => record,
+                    Err(..) => break,
+                }
+            }
+            Err(TryRecvError::Disconnected) => break,
+        };
+        file.write_all(&record)?;
+    }
+    file.flush()
+}
+
+impl CaptureWriter {
+    /// Create `<dir>/<unix millis>-<addr>.svfcap`
+    pub fn create(dir: &Path, addr: SocketAddr) -> io::Result<Self> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let name = format!("{}-{}.svfcap", now.as_millis(), addr).replace([':', '[', ']'], "_");
+        let path = dir.join(name);
+        let mut file = File::create(&path)?;
+        file.write_all(MAGIC)?;
+        let (sender, receiver) = channel();
+        let name = path.display().to_string();
+        let writer = thread::Builder::new()
+            .name(format!("capture {addr}"))
+            .spawn(move || {
+                write_records(file, receiver)
+                    .inspect_err(|err| println!("Cannot write capture {name}: {err}"))
+            })?;
+        Ok(Self {
+            sender,
+            writer,
+            path,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Queue a record for the writer thread, fails once the thread gave up on the file
+    pub fn record(&mut self, direction: Direction, frame: &Frame) -> io::Result<()> {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let mut buffer = Vec::with_capacity(13 + PacketHeader::SIZE + frame.payload().len());
+        BufferWriter::new(&mut buffer)
+            .write_u64(timestamp.as_micros() as u64)
+            .write_u8(match direction {
+                Direction::FromDevice => 0,
+                Direction::ToDevice => 1,
+            })
+            .write_bytes(&frame.header().to_bytes())
+            .write_bytes(frame.payload());
+        self.sender
+            .send(buffer)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Capture writer stopped"))
+    }
+
+    /// Write every queued record and close the file
+    pub fn finish(self) -> io::Result<()> {
+        drop(self.sender);
+        self.writer
+            .join()
+            .unwrap_or_else(|_| Err(io::Error::other("Capture writer panicked")))
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Read every record of a capture file
+pub fn read_capture(mut reader: impl Read) -> io::Result<Vec<CaptureRecord>> {
+    let mut content = Vec::new();
+    reader.read_to_end(&mut content)?;
+    if content.len() < MAGIC.len() || &content[..MAGIC.len()] != MAGIC {
+        return Err(invalid("Not a farm protocol capture"));
+    }
+    let mut buffer = BufferReader::new(&content[MAGIC.len()..]);
+    let mut records = Vec::new();
+    while buffer.get_read_pos() < content.len() - MAGIC.len() {
+        let truncated = || invalid("Truncated capture record");
+        let timestamp = Duration::from_micros(buffer.read_u64().ok_or_else(truncated)?);
+        let direction = match buffer.read_u8().ok_or_else(truncated)? {
+            0 => Direction::FromDevice,
+            1 => Direction::ToDevice,
+            _ => return Err(invalid("Unknown capture direction")),
+        };
+        let header = PacketHeader::from_bytes(
+            buffer
+                .read_bytes(PacketHeader::SIZE)
+                .ok_or_else(truncated)?,
+        )
+        .map_err(|_| truncated())?;
+        let payload = buffer
+            .read_bytes(header.length() as usize)
+            .ok_or_else(truncated)?;
+        records.push(CaptureRecord {
+            timestamp,
+            direction,
+            frame: Frame::new(header.id(), payload.to_vec()),
+        });
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_round_trip() {
+        let dir = std::env::temp_dir();
+        let mut writer = CaptureWriter::create(&dir, "127.0.0.1:4000".parse().unwrap()).unwrap();
+        let frames = [Frame::new(3, vec![1, 2, 3]), Frame::new(2, Vec::new())];
+        writer.record(Direction::FromDevice, &frames[0]).unwrap();
+        writer.record(Direction::ToDevice, &frames[1]).unwrap();
+        let path = writer.path().to_path_buf();
+        writer.finish().unwrap();
+
+        let records = read_capture(File::open(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].direction, Direction::FromDevice);
+        assert_eq!(records[0].frame, frames[0]);
+        assert_eq!(records[1].direction, Direction::ToDevice);
+        assert_eq!(records[1].frame, frames[1]);
+        assert!(records[0].timestamp <= records[1].timestamp);
+    }
+
+    #[test]
+    fn rejects_foreign_and_truncated_files() {
+        assert!(read_capture(&b"JFIF"[..]).is_err());
+        let mut content = MAGIC.to_vec();
+        content.extend([0; 5]);
+        assert!(read_capture(&content[..]).is_err());
+    }
+}
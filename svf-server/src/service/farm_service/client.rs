@@ -1,40 +1,95 @@
-use std::net::SocketAddr;
+use std::{
+    fmt::Display,
+    net::SocketAddr,
+    time::{Duration, SystemTime},
+};
 
-use futures::FutureExt;
-use num_enum::TryFromPrimitive;
+use futures::{SinkExt, StreamExt};
+use packet_derive::Packet;
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
+    select,
     sync::mpsc::{channel, Sender},
+    time::{interval, interval_at, sleep, Instant},
 };
 
-use crate::utils::{buffer_reader::BufferReader, buffer_writer::BufferWriter};
+use crate::{
+    service::db_service::{DBServiceHandle, DBServiceRequest, DBServiceResponse},
+    utils::{buffer_reader::BufferReader, buffer_writer::BufferWriter},
+};
 
 use super::{
-    packet::{Packet, PacketError, PacketHeader, PacketId},
+    auth,
+    capture::{CaptureWriter, Direction},
+    clock::{from_unix_millis, unix_millis},
+    codec::{Frame, Transport},
+    command::CommandOutcome,
+    firmware::FirmwareStatus,
+    handshake::{negotiate_version, Capabilities, Handshake, PROTOCOL_VERSIONS},
+    packet::{PacketError, PacketField, PacketId},
+    sensors::Readings,
     server::ServerPacketId,
-    ClientPacket, ClientReceiverCommand, ServerPacket,
+    session::{Challenge, PendingReport, SessionConfig, SessionState},
+    settings::DeviceSettings,
+    telemetry::SensorRecord,
+    upload::{ChunkOutcome, ImageChecksum, Upload, UploadError, UploadStore},
+    ClientReceiverCommand, ServerPacket,
 };
 
-pub struct Client {
+pub struct Client<T> {
     client_sender: Sender<ClientReceiverCommand>,
-    stream: TcpStream,
+    db: DBServiceHandle,
+    transport: T,
     addr: SocketAddr,
+    config: SessionConfig,
+    uploads: UploadStore,
+    state: SessionState,
+    heartbeat_sequence: u32,
     pending_report: Option<PendingReport>,
     image_buffer: Vec<u8>,
-    id: Option<[char; 64]>,
-}
-
-struct PendingReport {
-    soil_moisture: u16,
-    air_temperature: u16,
-    light_sensor: u16,
-    image_size: usize,
+    upload: Option<Upload>,
+    capture: Option<CaptureWriter>,
 }
 
 #[derive(Debug)]
 enum ClientError {
     InvalidPacket(PacketError),
+    HandshakeRequired,
+    DuplicateHello,
+    UnsupportedVersion { min: u16, max: u16 },
+    UnsupportedPacket { id: u32, version: u16 },
+    UnknownDevice,
+    Unauthenticated,
+    AuthenticationFailed,
+    IdleTimeout(Duration),
+    ConnectionClosed,
+    ImageTooLarge { size: usize, max: u32 },
+}
+
+impl Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidPacket(err) => write!(f, "{err}"),
+            Self::HandshakeRequired => write!(f, "Expected Hello as the first packet"),
+            Self::DuplicateHello => write!(f, "Hello sent twice"),
+            Self::UnsupportedVersion { min, max } => write!(
+                f,
+                "Device speaks protocol {min}..={max}, server speaks {}..={}",
+                PROTOCOL_VERSIONS.start(),
+                PROTOCOL_VERSIONS.end()
+            ),
+            Self::UnsupportedPacket { id, version } => {
+                write!(f, "Packet {id} is not part of protocol version {version}")
+            }
+            Self::UnknownDevice => write!(f, "Unknown device"),
+            Self::Unauthenticated => write!(f, "Device has not been authenticated"),
+            Self::AuthenticationFailed => write!(f, "Authentication failed"),
+            Self::IdleTimeout(timeout) => write!(f, "No traffic for {}s", timeout.as_secs_f32()),
+            Self::ConnectionClosed => write!(f, "Connection closed by the device"),
+            Self::ImageTooLarge { size, max } => {
+                write!(f, "Image of {size} bytes is larger than the limit of {max}")
+            }
+        }
+    }
 }
 
 impl From<PacketError> for ClientError {
@@ -43,37 +98,405 @@ impl From<PacketError> for ClientError {
     }
 }
 
-impl Client {
+impl<T: Transport> Client<T> {
     pub fn new(
         client_sender: Sender<ClientReceiverCommand>,
-        stream: TcpStream,
+        db: DBServiceHandle,
+        transport: T,
         addr: SocketAddr,
+        config: SessionConfig,
+        uploads: UploadStore,
     ) -> Self {
+        let capture = config.capture_dir.as_ref().and_then(|dir| {
+            CaptureWriter::create(dir, addr)
+                .inspect(|e| println!("Capturing {addr} to {}", e.path().display()))
+                .inspect_err(|err| println!("Cannot capture session with {addr}: {err}"))
+                .ok()
+        });
         Self {
             client_sender,
+            db,
+            transport,
+            addr,
+            config,
+            uploads,
+            state: SessionState::Connecting,
+            heartbeat_sequence: 0,
             pending_report: None,
             image_buffer: Vec::new(),
-            stream,
-            id: None,
-            addr,
+            upload: None,
+            capture,
+        }
+    }
+
+    async fn handle_hello(
+        &mut self,
+        min_protocol_version: u16,
+        max_protocol_version: u16,
+        firmware_version: String,
+        capabilities: u32,
+    ) -> Result<(), ClientError> {
+        if !matches!(self.state, SessionState::Connecting) {
+            return Err(ClientError::DuplicateHello);
+        }
+        let protocol_version = match negotiate_version(min_protocol_version..=max_protocol_version)
+        {
+            Some(version) => version,
+            None => {
+                return self
+                    .reject(ClientError::UnsupportedVersion {
+                        min: min_protocol_version,
+                        max: max_protocol_version,
+                    })
+                    .await
+            }
+        };
+        let capabilities =
+            Capabilities::from_bits(capabilities).intersection(Capabilities::SUPPORTED);
+        println!("Device {} speaks protocol {protocol_version}", self.addr);
+        self.state = SessionState::Identified {
+            handshake: Handshake {
+                protocol_version,
+                firmware_version,
+                capabilities,
+            },
+            challenge: None,
+        };
+        self.handle_server_packet(ServerPacket::Welcome {
+            protocol_version,
+            capabilities: capabilities.bits(),
+        })
+        .await
+    }
+
+    /// Tell the device why it is being dropped before closing the connection
+    async fn reject(&mut self, error: ClientError) -> Result<(), ClientError> {
+        self.handle_server_packet(ServerPacket::Disconnect {
+            reason: error.to_string(),
+        })
+        .await?;
+        Err(error)
+    }
+
+    fn authenticated_id(&self) -> Result<[char; 64], ClientError> {
+        match self.state {
+            SessionState::Active { ref id, .. } => Ok(**id),
+            _ => Err(ClientError::Unauthenticated),
+        }
+    }
+
+    async fn handle_report_id(&mut self, id: [char; 64]) -> Result<(), ClientError> {
+        if !matches!(
+            self.state,
+            SessionState::Identified {
+                challenge: None,
+                ..
+            }
+        ) {
+            return self.reject(ClientError::Unauthenticated).await;
+        }
+        let secret = match self
+            .db
+            .request(DBServiceRequest::GetDeviceSecret { id })
+            .await
+        {
+            Ok(DBServiceResponse::DeviceSecret(secret)) => secret,
+            Ok(..) => unreachable!(),
+            Err(..) => return self.reject(ClientError::UnknownDevice).await,
+        };
+        let nonce = auth::new_challenge();
+        if let SessionState::Identified { challenge, .. } = &mut self.state {
+            *challenge = Some(Box::new(Challenge { id, secret, nonce }));
+        }
+        self.handle_server_packet(ServerPacket::AuthChallenge { nonce })
+            .await
+    }
+
+    async fn handle_auth_response(
+        &mut self,
+        response: [u8; 32],
+        sender: Sender<ServerPacket>,
+    ) -> Result<(), ClientError> {
+        let (handshake, id) = match std::mem::replace(&mut self.state, SessionState::Closing) {
+            SessionState::Identified {
+                handshake,
+                challenge: Some(challenge),
+            } if auth::verify(
+                &challenge.secret,
+                &challenge.nonce,
+                &challenge.id,
+                &response,
+            ) =>
+            {
+                (handshake, challenge.id)
+            }
+            SessionState::Identified { handshake, .. } => {
+                self.state = SessionState::Identified {
+                    handshake,
+                    challenge: None,
+                };
+                return self.reject(ClientError::AuthenticationFailed).await;
+            }
+            state => {
+                self.state = state;
+                return self.reject(ClientError::Unauthenticated).await;
+            }
+        };
+        println!(
+            "Device {} authenticated as {}, firmware {}",
+            self.addr,
+            id.iter().collect::<String>(),
+            handshake.firmware_version
+        );
+        self.client_sender
+            .send(ClientReceiverCommand::ReportClient {
+                id,
+                capabilities: handshake.capabilities,
+                protocol_version: handshake.protocol_version,
+                firmware_version: handshake.firmware_version.clone(),
+                sender,
+            })
+            .await
+            .unwrap();
+        self.state = SessionState::Active {
+            handshake,
+            id: Box::new(id),
+        };
+        self.handle_server_packet(ServerPacket::Authenticated)
+            .await?;
+        self.send_time_sync().await?;
+        self.resume_upload().await
+    }
+
+    /// Pick up an upload this device left unfinished on an earlier connection
+    async fn resume_upload(&mut self) -> Result<(), ClientError> {
+        let id = self.authenticated_id()?;
+        let now = Instant::now().into_std();
+        let Some((mut upload, report)) = self.uploads.take_latest(id, now) else {
+            return Ok(());
+        };
+        upload.touch(now);
+        let resume = ServerPacket::UploadResume {
+            upload_id: upload.id(),
+            offset: upload.offset(),
+        };
+        println!(
+            "Resuming upload {} from {} at byte {}",
+            upload.id(),
+            self.addr,
+            upload.offset()
+        );
+        self.upload = Some(upload);
+        self.pending_report = Some(report);
+        self.handle_server_packet(resume).await
+    }
+
+    async fn submit_report(&mut self, id: [char; 64], report: PendingReport, image: Vec<u8>) {
+        self.client_sender
+            .send(ClientReceiverCommand::ReportSensors {
+                id,
+                records: report.records,
+                captured_at: report.captured_at,
+                image,
+            })
+            .await
+            .unwrap();
+    }
+
+    /// A new report drops whatever image bytes arrived for the one it replaces,
+    /// reports without an image are submitted right away
+    async fn replace_report(&mut self, report: PendingReport) -> Result<(), ClientError> {
+        let id = self.authenticated_id()?;
+        let max = self.config.upload.max_image_size;
+        if report.image_size > max as usize {
+            let size = report.image_size;
+            return self.reject(ClientError::ImageTooLarge { size, max }).await;
+        }
+        self.image_buffer.clear();
+        if report.image_size == 0 {
+            self.pending_report = None;
+            self.submit_report(id, report, Vec::new()).await;
+        } else {
+            self.pending_report = Some(report);
+        }
+        Ok(())
+    }
+
+    async fn append_image(&mut self, data: &[u8]) -> Result<(), ClientError> {
+        let id = self.authenticated_id()?;
+        if let Some(ref report) = self.pending_report {
+            self.image_buffer.extend_from_slice(data);
+            if self.image_buffer.len() >= report.image_size {
+                let report = self.pending_report.take().unwrap();
+                let image = std::mem::take(&mut self.image_buffer);
+                self.submit_report(id, report, image).await;
+            }
+        }
+        Ok(())
+    }
+
+    async fn abort_upload(
+        &mut self,
+        upload_id: u32,
+        error: UploadError,
+    ) -> Result<(), ClientError> {
+        println!("Upload {upload_id} from {} aborted: {error}", self.addr);
+        if self.upload.as_ref().is_some_and(|e| e.id() == upload_id) {
+            self.upload = None;
+        }
+        self.handle_server_packet(ServerPacket::UploadAborted {
+            upload_id,
+            reason: error.to_string(),
+        })
+        .await
+    }
+
+    async fn handle_upload_start(
+        &mut self,
+        upload_id: u32,
+        total_size: u32,
+        chunk_size: u16,
+        checksum: ImageChecksum,
+    ) -> Result<(), ClientError> {
+        let id = self.authenticated_id()?;
+        let now = Instant::now().into_std();
+        if self.upload.as_ref().map(|e| e.id()) != Some(upload_id) {
+            if let Some((upload, report)) = self.uploads.take(id, upload_id, now) {
+                self.upload = Some(upload);
+                self.pending_report.get_or_insert(report);
+            }
+        }
+        if let Some(upload) = self.upload.as_mut() {
+            if upload.id() == upload_id && upload.is_same_image(total_size, checksum) {
+                upload.touch(now);
+                let accepted = ServerPacket::UploadAccepted {
+                    upload_id,
+                    chunk_size: upload.chunk_size(),
+                    offset: upload.offset(),
+                };
+                return self.handle_server_packet(accepted).await;
+            }
+        }
+        if self.pending_report.is_none() {
+            return self
+                .abort_upload(upload_id, UploadError::UnknownUpload)
+                .await;
+        }
+        let upload = match Upload::start(
+            upload_id,
+            total_size,
+            chunk_size,
+            checksum,
+            &self.config.upload,
+            now,
+        ) {
+            Ok(upload) => upload,
+            Err(err) => return self.abort_upload(upload_id, err).await,
+        };
+        let accepted = ServerPacket::UploadAccepted {
+            upload_id,
+            chunk_size: upload.chunk_size(),
+            offset: upload.offset(),
+        };
+        self.upload = Some(upload);
+        self.handle_server_packet(accepted).await
+    }
+
+    async fn handle_upload_chunk(
+        &mut self,
+        upload_id: u32,
+        offset: u32,
+        data: &[u8],
+    ) -> Result<(), ClientError> {
+        let id = self.authenticated_id()?;
+        let outcome = match self.upload.as_mut() {
+            Some(upload) if upload.id() == upload_id => {
+                upload.accept(offset, data, Instant::now().into_std())
+            }
+            _ => Err(UploadError::UnknownUpload),
+        };
+        match outcome {
+            Ok(ChunkOutcome::Accepted | ChunkOutcome::Duplicate) => Ok(()),
+            Ok(ChunkOutcome::OutOfOrder { expected }) => {
+                self.handle_server_packet(ServerPacket::UploadResume {
+                    upload_id,
+                    offset: expected,
+                })
+                .await
+            }
+            Ok(ChunkOutcome::Complete(image)) => {
+                self.upload = None;
+                self.handle_server_packet(ServerPacket::UploadComplete { upload_id })
+                    .await?;
+                if let Some(report) = self.pending_report.take() {
+                    self.submit_report(id, report, image).await;
+                }
+                Ok(())
+            }
+            Err(err) => self.abort_upload(upload_id, err).await,
+        }
+    }
+
+    async fn check_upload(&mut self) -> Result<(), ClientError> {
+        match &self.upload {
+            Some(upload) if upload.is_stalled(Instant::now().into_std(), &self.config.upload) => {
+                self.abort_upload(upload.id(), UploadError::Stalled).await
+            }
+            _ => Ok(()),
         }
     }
 
     async fn handle_client_packet(
         &mut self,
-        header: PacketHeader,
+        mut frame: Frame,
         sender: Sender<ServerPacket>,
     ) -> Result<(), ClientError> {
-        let mut buffer = vec![0u8; header.length() as usize];
-        self.stream.read(&mut buffer).await.unwrap();
-        let packet = Packet::<ClientPacketId>::new(header.id(), &mut buffer)?;
-        match packet.decode()? {
-            ClientPacket::ReportId { id } => {
-                self.id = Some(id);
-                self.client_sender
-                    .send(ClientReceiverCommand::ReportClient { id, sender })
-                    .await
-                    .unwrap();
+        let packet = frame.decode::<ClientPacketId>()?;
+        if let ClientPacket::Hello {
+            min_protocol_version,
+            max_protocol_version,
+            firmware_version,
+            capabilities,
+        } = packet
+        {
+            return self
+                .handle_hello(
+                    min_protocol_version,
+                    max_protocol_version,
+                    firmware_version,
+                    capabilities,
+                )
+                .await;
+        }
+        let handshake = self
+            .state
+            .handshake()
+            .ok_or(ClientError::HandshakeRequired)?;
+        let packet_id = ClientPacketId::from(&packet);
+        if packet_id.since_version() > handshake.protocol_version {
+            return Err(ClientError::UnsupportedPacket {
+                id: packet_id.id(),
+                version: handshake.protocol_version,
+            });
+        }
+        match packet {
+            ClientPacket::Hello { .. } => unreachable!(),
+            ClientPacket::Ping { sequence } => {
+                self.handle_server_packet(ServerPacket::Pong { sequence })
+                    .await?
+            }
+            ClientPacket::Pong { .. } => {}
+            ClientPacket::Ack { sequence } => {
+                self.report_outcome(sequence, CommandOutcome::Acknowledged)
+                    .await?
+            }
+            ClientPacket::Nack { sequence, reason } => {
+                self.report_outcome(sequence, CommandOutcome::Rejected { reason })
+                    .await?
+            }
+            ClientPacket::ReportId { id } => self.handle_report_id(id).await?,
+            ClientPacket::AuthResponse { response } => {
+                self.handle_auth_response(response, sender).await?
             }
             ClientPacket::ReportSensors {
                 soil_moisture,
@@ -81,143 +504,538 @@ impl Client {
                 light_sensor,
                 image_size,
             } => {
-                self.pending_report = Some(PendingReport {
-                    soil_moisture,
-                    air_temperature,
-                    light_sensor,
+                self.authenticated_id()?;
+                self.replace_report(PendingReport {
+                    records: Readings::legacy(soil_moisture, air_temperature, light_sensor)
+                        .records(),
+                    image_size,
+                    captured_at: SystemTime::now(),
+                })
+                .await?;
+            }
+            ClientPacket::ReportSensorsAt {
+                captured_at,
+                soil_moisture,
+                air_temperature,
+                light_sensor,
+                image_size,
+            } => {
+                let captured_at = self.check_clock(from_unix_millis(captured_at)).await?;
+                self.replace_report(PendingReport {
+                    records: Readings::legacy(soil_moisture, air_temperature, light_sensor)
+                        .records(),
                     image_size,
-                });
+                    captured_at,
+                })
+                .await?;
+            }
+            ClientPacket::ReportReadings {
+                captured_at,
+                readings,
+                image_size,
+            } => {
+                let captured_at = self.check_clock(from_unix_millis(captured_at)).await?;
+                self.replace_report(PendingReport {
+                    records: readings.records(),
+                    image_size,
+                    captured_at,
+                })
+                .await?;
+            }
+            ClientPacket::ReportTelemetry {
+                captured_at,
+                records,
+                image_size,
+            } => {
+                let captured_at = self.check_clock(from_unix_millis(captured_at)).await?;
+                self.replace_report(PendingReport {
+                    records,
+                    image_size,
+                    captured_at,
+                })
+                .await?;
             }
             ClientPacket::ImageFrame { frame_size, frame } => {
-                if let Some(ref report) = self.pending_report {
-                    self.image_buffer.extend_from_slice(&frame[0..frame_size]);
-                    if self.image_buffer.len() >= report.image_size {
-                        self.client_sender
-                            .send(ClientReceiverCommand::ReportSensors {
-                                id: self.id.expect("Unauthorize"),
-                                soil_moisture: report.soil_moisture,
-                                air_temperature: report.air_temperature,
-                                light_sensor: report.light_sensor,
-                                image: self.image_buffer.clone(),
-                            })
-                            .await
-                            .unwrap();
-                        self.image_buffer.clear();
-                        self.pending_report = None;
-                    }
-                }
+                self.append_image(&frame[0..frame_size.min(frame.len())])
+                    .await?;
+            }
+            ClientPacket::ImageChunk { data } => self.append_image(&data).await?,
+            ClientPacket::UploadStart {
+                upload_id,
+                total_size,
+                chunk_size,
+                checksum,
+            } => {
+                self.handle_upload_start(upload_id, total_size, chunk_size, checksum)
+                    .await?
+            }
+            ClientPacket::UploadChunk {
+                upload_id,
+                offset,
+                data,
+            } => self.handle_upload_chunk(upload_id, offset, &data).await?,
+            ClientPacket::ReportConfig { settings } => {
+                let id = self.authenticated_id()?;
+                self.client_sender
+                    .send(ClientReceiverCommand::ReportConfig { id, settings })
+                    .await
+                    .unwrap();
+            }
+            ClientPacket::FirmwareRequest { update_id, offset } => {
+                let id = self.authenticated_id()?;
+                self.client_sender
+                    .send(ClientReceiverCommand::FirmwareRequest {
+                        id,
+                        update_id,
+                        offset,
+                    })
+                    .await
+                    .unwrap();
+            }
+            ClientPacket::FirmwareReport { update_id, status } => {
+                let id = self.authenticated_id()?;
+                self.client_sender
+                    .send(ClientReceiverCommand::FirmwareReport {
+                        id,
+                        update_id,
+                        status,
+                    })
+                    .await
+                    .unwrap();
             }
         }
-        return Ok(());
+        Ok(())
     }
 
-    async fn handle_server_packet(&mut self, server_packet: ServerPacket) {
-        let mut buffer = Vec::new();
-        let mut packet = Packet::<ServerPacketId>::new_packet(&server_packet, &mut buffer).unwrap();
-        packet.encode(server_packet);
-        self.stream
-            .write(&packet.header().to_bytes())
+    async fn report_outcome(
+        &mut self,
+        sequence: u32,
+        outcome: CommandOutcome,
+    ) -> Result<(), ClientError> {
+        let id = self.authenticated_id()?;
+        self.client_sender
+            .send(ClientReceiverCommand::CommandResult {
+                id,
+                sequence,
+                outcome,
+            })
             .await
             .unwrap();
-        self.stream.write(&buffer).await.unwrap();
+        Ok(())
     }
 
-    pub async fn run(&mut self) {
-        let mut header_buffer = [0u8; size_of::<PacketHeader>()];
-        let (server_sender, mut server_receiver) = channel::<ServerPacket>(16);
-        loop {
-            let stream_task = self.stream.read(&mut header_buffer).fuse();
-            let receiver_task = server_receiver.recv().fuse();
-            futures::pin_mut!(stream_task, receiver_task);
-
-            futures::select! {
-                readed = stream_task => {
-                    let header = PacketHeader::from_bytes(&header_buffer).expect("Should not failed");
-                    self.handle_client_packet(header, server_sender.clone()).await;
-                    continue;
-                }
-                packet = receiver_task => {
-                    self.handle_server_packet(packet.expect("Packet recv error")).await;
-                    continue;
+    async fn handle_server_packet(
+        &mut self,
+        server_packet: ServerPacket,
+    ) -> Result<(), ClientError> {
+        let version = self
+            .state
+            .handshake()
+            .map_or(*PROTOCOL_VERSIONS.start(), |e| e.protocol_version);
+        // Older firmware can't acknowledge, fall back to the fire-and-forget packets
+        let mut unconfirmed = None;
+        let server_packet = match server_packet {
+            ServerPacket::Command { sequence, command }
+                if ServerPacketId::Command.since_version() > version =>
+            {
+                match command.legacy_packet() {
+                    Some(packet) => {
+                        unconfirmed = Some(sequence);
+                        packet
+                    }
+                    None => ServerPacket::Command { sequence, command },
                 }
-                complete => continue,
             }
+            packet => packet,
+        };
+        let packet_id = ServerPacketId::from(&server_packet);
+        if packet_id.since_version() > version {
+            if let Some(sequence) = server_packet.command_sequence() {
+                // Fail commands right away instead of letting them time out
+                let reason = format!("Needs protocol version {}", packet_id.since_version());
+                return self
+                    .report_outcome(sequence, CommandOutcome::Rejected { reason })
+                    .await;
+            }
+            println!(
+                "Not sending packet {} to {}, it needs protocol version {}",
+                packet_id.id(),
+                self.addr,
+                packet_id.since_version()
+            );
+            return Ok(());
         }
+        let frame = Frame::from_packet::<ServerPacketId>(server_packet)?;
+        self.capture(Direction::ToDevice, &frame);
+        self.transport.send(frame).await?;
+        if let Some(sequence) = unconfirmed {
+            self.report_outcome(sequence, CommandOutcome::Unconfirmed)
+                .await?;
+        }
+        Ok(())
     }
-}
 
-#[derive(Clone, Copy, TryFromPrimitive)]
-#[repr(u32)]
-pub enum ClientPacketId {
-    ReportId = 0,
-    ReportSensors,
-    ImageFrame,
-}
+    fn capture(&mut self, direction: Direction, frame: &Frame) {
+        if let Some(capture) = self.capture.as_mut() {
+            if let Err(err) = capture.record(direction, frame) {
+                println!("Stopped capturing session with {}: {err}", self.addr);
+                self.capture = None;
+            }
+        }
+    }
 
-fn decode_report_id(mut buffer: BufferReader) -> Result<ClientPacket, PacketError> {
-    Ok(ClientPacket::ReportId {
-        id: buffer
-            .const_read_bytes::<64>()
-            .map(|e| {
-                e.iter()
-                    .map(|c| *c as char)
-                    .collect::<Vec<char>>()
-                    .try_into()
-                    .unwrap()
+    /// Stamp reports from devices with a skewed clock with the arrival time instead,
+    /// and tell the device the time again
+    async fn check_clock(&mut self, captured_at: SystemTime) -> Result<SystemTime, ClientError> {
+        let id = self.authenticated_id()?;
+        let now = SystemTime::now();
+        let Err(skew) = self.config.clock.check(captured_at, now) else {
+            return Ok(captured_at);
+        };
+        println!(
+            "Clock of {} is off by {}ms, resynchronising",
+            self.addr, skew.millis
+        );
+        self.client_sender
+            .send(ClientReceiverCommand::ClockSkew {
+                id,
+                millis: skew.millis,
             })
-            .ok_or(PacketError::InvalidPacketLength)?,
-    })
-}
+            .await
+            .unwrap();
+        self.send_time_sync().await?;
+        Ok(now)
+    }
 
-fn decode_sensors(mut buffer: BufferReader) -> Result<ClientPacket, PacketError> {
-    let soil_moisture = buffer.read_u16().ok_or(PacketError::InvalidPacketLength)?;
-    let air_temperature = buffer.read_u16().ok_or(PacketError::InvalidPacketLength)?;
-    let light_sensor = buffer.read_u16().ok_or(PacketError::InvalidPacketLength)?;
-    let camera_frames = buffer.read_u64().ok_or(PacketError::InvalidPacketLength)? as usize;
-
-    Ok(ClientPacket::ReportSensors {
-        soil_moisture,
-        air_temperature,
-        light_sensor,
-        image_size: camera_frames,
-    })
-}
+    async fn send_time_sync(&mut self) -> Result<(), ClientError> {
+        match self.state {
+            SessionState::Active { ref handshake, .. }
+                if ServerPacketId::TimeSync.since_version() <= handshake.protocol_version =>
+            {
+                self.handle_server_packet(ServerPacket::TimeSync {
+                    unix_millis: unix_millis(SystemTime::now()),
+                })
+                .await
+            }
+            _ => Ok(()),
+        }
+    }
 
-fn decode_frame(mut buffer: BufferReader) -> Result<ClientPacket, PacketError> {
-    Ok(ClientPacket::ImageFrame {
-        frame_size: buffer.read_u64().ok_or(PacketError::InvalidPacketLength)? as usize,
-        frame: buffer
-            .const_read_bytes::<128>()
-            .ok_or(PacketError::InvalidPacketLength)?,
-    })
-}
+    /// Ping devices that understand it, older devices only get the idle timeout
+    async fn send_heartbeat(&mut self) -> Result<(), ClientError> {
+        match self.state.handshake() {
+            Some(handshake)
+                if ServerPacketId::Ping.since_version() <= handshake.protocol_version =>
+            {
+                self.heartbeat_sequence = self.heartbeat_sequence.wrapping_add(1);
+                self.handle_server_packet(ServerPacket::Ping {
+                    sequence: self.heartbeat_sequence,
+                })
+                .await
+            }
+            _ => Ok(()),
+        }
+    }
 
-impl From<&ClientPacket> for ClientPacketId {
-    fn from(value: &ClientPacket) -> Self {
-        match value {
-            ClientPacket::ReportSensors { .. } => Self::ReportSensors,
-            ClientPacket::ImageFrame { .. } => Self::ImageFrame,
-            ClientPacket::ReportId { .. } => Self::ReportId,
+    async fn close(&mut self, reason: ClientError, sender: Sender<ServerPacket>) {
+        println!(
+            "Closing {} session with {}: {reason}",
+            self.state.name(),
+            self.addr
+        );
+        if let SessionState::Active { id, .. } =
+            std::mem::replace(&mut self.state, SessionState::Closing)
+        {
+            let id = *id;
+            if let (Some(upload), Some(report)) = (self.upload.take(), self.pending_report.take()) {
+                println!(
+                    "Keeping upload {} at byte {} for {} to resume",
+                    upload.id(),
+                    upload.offset(),
+                    id.iter().collect::<String>()
+                );
+                self.uploads
+                    .park(id, upload, report, Instant::now().into_std());
+            }
+            self.client_sender
+                .send(ClientReceiverCommand::Disconnect {
+                    id,
+                    sender,
+                    reason: reason.to_string(),
+                })
+                .await
+                .ok();
         }
+        self.transport.close().await.ok();
+    }
+
+    pub async fn run(&mut self) {
+        let (server_sender, mut server_receiver) = channel::<ServerPacket>(16);
+        let idle = sleep(self.config.idle_timeout);
+        let mut heartbeat = interval_at(
+            Instant::now() + self.config.heartbeat_interval,
+            self.config.heartbeat_interval,
+        );
+        let mut upload_check = interval(self.config.upload.stall_timeout / 4);
+        let mut time_sync = interval_at(
+            Instant::now() + self.config.clock.sync_interval,
+            self.config.clock.sync_interval,
+        );
+        tokio::pin!(idle);
+        let reason = loop {
+            let result = select! {
+                frame = self.transport.next() => match frame {
+                    Some(Ok(frame)) => {
+                        idle.as_mut().reset(Instant::now() + self.config.idle_timeout);
+                        self.capture(Direction::FromDevice, &frame);
+                        self.handle_client_packet(frame, server_sender.clone()).await
+                    }
+                    Some(Err(err)) => Err(err.into()),
+                    None => Err(ClientError::ConnectionClosed),
+                },
+                Some(packet) = server_receiver.recv() => self.handle_server_packet(packet).await,
+                _ = heartbeat.tick() => self.send_heartbeat().await,
+                _ = time_sync.tick() => self.send_time_sync().await,
+                _ = upload_check.tick() => self.check_upload().await,
+                _ = &mut idle => self.reject(ClientError::IdleTimeout(self.config.idle_timeout)).await,
+            };
+            if let Err(err) = result {
+                break err;
+            }
+        };
+        self.close(reason, server_sender).await;
     }
 }
 
-impl PacketId for ClientPacketId {
-    type Packet = ClientPacket;
+/// Packets sent by devices, ids follow declaration order so new packets go at the end
+#[derive(Packet, Debug, Clone, PartialEq)]
+#[packet(id = ClientPacketId)]
+pub enum ClientPacket {
+    ReportId {
+        id: [char; 64],
+    },
+    ReportSensors {
+        soil_moisture: u16,
+        air_temperature: u16,
+        light_sensor: u16,
+        image_size: usize,
+    },
+    ImageFrame {
+        frame_size: usize,
+        frame: [u8; 128],
+    },
+    Hello {
+        min_protocol_version: u16,
+        max_protocol_version: u16,
+        firmware_version: String,
+        capabilities: u32,
+    },
+    #[packet(since = 2)]
+    ImageChunk {
+        data: Vec<u8>,
+    },
+    AuthResponse {
+        response: [u8; 32],
+    },
+    #[packet(since = 3)]
+    Ping {
+        sequence: u32,
+    },
+    #[packet(since = 3)]
+    Pong {
+        sequence: u32,
+    },
+    #[packet(since = 4)]
+    Ack {
+        sequence: u32,
+    },
+    #[packet(since = 4)]
+    Nack {
+        sequence: u32,
+        reason: String,
+    },
+    #[packet(since = 5)]
+    UploadStart {
+        upload_id: u32,
+        total_size: u32,
+        chunk_size: u16,
+        checksum: ImageChecksum,
+    },
+    #[packet(since = 5)]
+    UploadChunk {
+        upload_id: u32,
+        offset: u32,
+        data: Vec<u8>,
+    },
+    /// The settings the device runs with, sent after `Authenticated` and after applying `Configure`
+    #[packet(since = 6)]
+    ReportConfig {
+        settings: DeviceSettings,
+    },
+    /// Accept a `FirmwareOffer` or ask for the next chunk, `offset` lets a device resume
+    #[packet(since = 7)]
+    FirmwareRequest {
+        update_id: u32,
+        offset: u32,
+    },
+    #[packet(since = 7)]
+    FirmwareReport {
+        update_id: u32,
+        status: FirmwareStatus,
+    },
+    /// `ReportSensors` stamped with the device time the readings were taken at
+    #[packet(since = 8)]
+    ReportSensorsAt {
+        captured_at: u64,
+        soil_moisture: u16,
+        air_temperature: u16,
+        light_sensor: u16,
+        image_size: usize,
+    },
+    /// Sensor readings in physical units, see [`Readings`]
+    #[packet(since = 9)]
+    ReportReadings {
+        captured_at: u64,
+        readings: Readings,
+        image_size: usize,
+    },
+    /// Readings of any sensors the device has, checked against the server's sensor registry
+    #[packet(since = 10)]
+    ReportTelemetry {
+        captured_at: u64,
+        records: Vec<SensorRecord>,
+        image_size: usize,
+    },
+}
 
-    fn decode(&self, buffer: BufferReader) -> Result<ClientPacket, PacketError> {
-        match self {
-            Self::ReportId => decode_report_id(buffer),
-            Self::ReportSensors => decode_sensors(buffer),
-            Self::ImageFrame => decode_frame(buffer),
-        }
+#[cfg(test)]
+mod tests {
+    use proptest::{collection::vec, prelude::*};
+
+    use super::*;
+    use crate::service::farm_service::{
+        codec::wire_round_trip, sensors::readings_strategy, settings::settings_strategy,
+        telemetry::records_strategy,
+    };
+
+    fn ascii<const N: usize>() -> impl Strategy<Value = [char; N]> {
+        vec(proptest::char::range(' ', '~'), N).prop_map(|e| e.try_into().unwrap())
     }
 
-    fn encode(&self, _buffer: BufferWriter, _packet: ClientPacket) {
-        panic!("Operation not supported");
+    fn checksum() -> impl Strategy<Value = ImageChecksum> {
+        prop_oneof![
+            any::<u32>().prop_map(ImageChecksum::Crc32),
+            any::<[u8; 32]>().prop_map(ImageChecksum::Sha256),
+        ]
+    }
+
+    fn firmware_status() -> impl Strategy<Value = FirmwareStatus> {
+        prop_oneof![
+            any::<String>().prop_map(|reason| FirmwareStatus::Declined { reason }),
+            Just(FirmwareStatus::Verified),
+            Just(FirmwareStatus::VerifyFailed),
+            Just(FirmwareStatus::Applying),
+            any::<String>().prop_map(|reason| FirmwareStatus::Failed { reason }),
+        ]
+    }
+
+    fn client_packet() -> impl Strategy<Value = ClientPacket> {
+        prop_oneof![
+            ascii::<64>().prop_map(|id| ClientPacket::ReportId { id }),
+            (any::<u16>(), any::<u16>(), any::<u16>(), any::<usize>()).prop_map(
+                |(soil_moisture, air_temperature, light_sensor, image_size)| {
+                    ClientPacket::ReportSensors {
+                        soil_moisture,
+                        air_temperature,
+                        light_sensor,
+                        image_size,
+                    }
+                }
+            ),
+            (any::<usize>(), vec(any::<u8>(), 128)).prop_map(|(frame_size, frame)| {
+                ClientPacket::ImageFrame {
+                    frame_size,
+                    frame: frame.try_into().unwrap(),
+                }
+            }),
+            (any::<u16>(), any::<u16>(), any::<String>(), any::<u32>()).prop_map(
+                |(min_protocol_version, max_protocol_version, firmware_version, capabilities)| {
+                    ClientPacket::Hello {
+                        min_protocol_version,
+                        max_protocol_version,
+                        firmware_version,
+                        capabilities,
+                    }
+                }
+            ),
+            vec(any::<u8>(), 0..512).prop_map(|data| ClientPacket::ImageChunk { data }),
+            any::<[u8; 32]>().prop_map(|response| ClientPacket::AuthResponse { response }),
+            any::<u32>().prop_map(|sequence| ClientPacket::Ping { sequence }),
+            any::<u32>().prop_map(|sequence| ClientPacket::Pong { sequence }),
+            any::<u32>().prop_map(|sequence| ClientPacket::Ack { sequence }),
+            (any::<u32>(), any::<String>())
+                .prop_map(|(sequence, reason)| ClientPacket::Nack { sequence, reason }),
+            (any::<u32>(), any::<u32>(), any::<u16>(), checksum()).prop_map(
+                |(upload_id, total_size, chunk_size, checksum)| ClientPacket::UploadStart {
+                    upload_id,
+                    total_size,
+                    chunk_size,
+                    checksum,
+                }
+            ),
+            (any::<u32>(), any::<u32>(), vec(any::<u8>(), 0..512)).prop_map(
+                |(upload_id, offset, data)| ClientPacket::UploadChunk {
+                    upload_id,
+                    offset,
+                    data,
+                }
+            ),
+            settings_strategy().prop_map(|settings| ClientPacket::ReportConfig { settings }),
+            (any::<u32>(), any::<u32>()).prop_map(|(update_id, offset)| {
+                ClientPacket::FirmwareRequest { update_id, offset }
+            }),
+            (any::<u32>(), firmware_status()).prop_map(|(update_id, status)| {
+                ClientPacket::FirmwareReport { update_id, status }
+            }),
+            (
+                any::<u64>(),
+                any::<u16>(),
+                any::<u16>(),
+                any::<u16>(),
+                any::<usize>()
+            )
+                .prop_map(
+                    |(captured_at, soil_moisture, air_temperature, light_sensor, image_size)| {
+                        ClientPacket::ReportSensorsAt {
+                            captured_at,
+                            soil_moisture,
+                            air_temperature,
+                            light_sensor,
+                            image_size,
+                        }
+                    }
+                ),
+            (any::<u64>(), readings_strategy(), any::<usize>()).prop_map(
+                |(captured_at, readings, image_size)| ClientPacket::ReportReadings {
+                    captured_at,
+                    readings,
+                    image_size,
+                }
+            ),
+            (any::<u64>(), records_strategy(), any::<usize>()).prop_map(
+                |(captured_at, records, image_size)| ClientPacket::ReportTelemetry {
+                    captured_at,
+                    records,
+                    image_size,
+                }
+            ),
+        ]
     }
 
-    fn id(&self) -> u32 {
-        *self as u32
+    proptest! {
+        #[test]
+        fn client_packets_round_trip(packet in client_packet()) {
+            prop_assert_eq!(wire_round_trip::<ClientPacketId>(packet.clone()), packet);
+        }
     }
 }
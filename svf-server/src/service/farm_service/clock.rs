@@ -0,0 +1,83 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+pub fn from_unix_millis(millis: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(millis)
+}
+
+/// How much clock skew and report queueing is tolerated
+#[derive(Debug, Clone, Copy)]
+pub struct ClockConfig {
+    /// How often devices are sent `TimeSync`, on top of once after authenticating
+    pub sync_interval: Duration,
+    /// Reports stamped further in the future than this come from a fast clock
+    pub max_skew: Duration,
+    /// Reports stamped further in the past than this come from a slow or unset clock,
+    /// anything younger may have waited in the device queue
+    pub max_report_age: Duration,
+}
+
+impl Default for ClockConfig {
+    fn default() -> Self {
+        Self {
+            sync_interval: Duration::from_secs(60 * 60),
+            max_skew: Duration::from_secs(5),
+            max_report_age: Duration::from_secs(24 * 60 * 60),
+        }
+    }
+}
+
+/// A capture timestamp that can't come from a synchronised clock
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClockSkew {
+    /// Device time minus server time, positive when the device is ahead
+    pub millis: i64,
+}
+
+impl ClockConfig {
+    /// Check when a device says it captured a report against when it arrived
+    pub fn check(&self, captured_at: SystemTime, arrived_at: SystemTime) -> Result<(), ClockSkew> {
+        match captured_at.duration_since(arrived_at) {
+            // Captured "after" it arrived
+            Ok(ahead) if ahead > self.max_skew => Err(ClockSkew {
+                millis: ahead.as_millis() as i64,
+            }),
+            Ok(_) => Ok(()),
+            Err(err) if err.duration() > self.max_report_age => Err(ClockSkew {
+                millis: -(err.duration().as_millis() as i64),
+            }),
+            Err(_) => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn millis_round_trip() {
+        let time = from_unix_millis(1_700_000_000_123);
+        assert_eq!(unix_millis(time), 1_700_000_000_123);
+    }
+
+    #[test]
+    fn queued_reports_are_fine_skewed_clocks_are_not() {
+        let config = ClockConfig::default();
+        let now = from_unix_millis(1_700_000_000_000);
+        assert_eq!(config.check(now, now), Ok(()));
+        assert_eq!(config.check(now + Duration::from_secs(2), now), Ok(()));
+        assert_eq!(config.check(now - Duration::from_secs(600), now), Ok(()));
+        assert_eq!(
+            config.check(now + Duration::from_secs(30), now),
+            Err(ClockSkew { millis: 30_000 })
+        );
+        // A device that never learned the time reports from 1970
+        assert!(config.check(from_unix_millis(5_000), now).is_err());
+    }
+}
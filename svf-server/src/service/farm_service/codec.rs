@@ -0,0 +1,277 @@
+use bytes::{Buf, BufMut, BytesMut};
+use futures::{Sink, Stream};
+use tokio_util::codec::{Decoder, Encoder};
+
+use super::packet::{Packet, PacketError, PacketHeader, PacketId};
+
+/// Largest payload accepted from a device unless configured otherwise
+pub const MAX_FRAME_LENGTH: u32 = 64 * 1024;
+
+/// A single length-delimited packet as it travels on the wire
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    id: u32,
+    payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(id: u32, payload: Vec<u8>) -> Self {
+        Self { id, payload }
+    }
+
+    /// Encode a packet into a frame ready to be sent
+    pub fn from_packet<T>(packet: T::Packet) -> Result<Self, PacketError>
+    where
+        T: PacketId + TryFrom<u32> + for<'b> TryFrom<&'b T::Packet>,
+    {
+        let mut payload = Vec::new();
+        let mut encoded = Packet::<T>::new_packet(&packet, &mut payload)?;
+        encoded.encode(packet);
+        let id = encoded.header().id();
+        Ok(Self { id, payload })
+    }
+
+    /// Decode the payload of this frame as a packet of type `T`
+    pub fn decode<T>(&mut self) -> Result<T::Packet, PacketError>
+    where
+        T: PacketId + TryFrom<u32> + for<'b> TryFrom<&'b T::Packet>,
+    {
+        Packet::<T>::new(self.id, &mut self.payload)?.decode()
+    }
+
+    pub fn header(&self) -> PacketHeader {
+        PacketHeader::new(self.payload.len() as u32, self.id)
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+}
+
+/// A device connection carrying whole frames
+///
+/// Implemented by `Framed<_, PacketCodec>` for raw TCP and TLS streams and by
+/// the WebSocket transport, which carries one frame per binary message.
+pub trait Transport:
+    Stream<Item = Result<Frame, PacketError>> + Sink<Frame, Error = PacketError> + Unpin
+{
+}
+
+impl<T> Transport for T where
+    T: Stream<Item = Result<Frame, PacketError>> + Sink<Frame, Error = PacketError> + Unpin
+{
+}
+
+/// Splits a byte stream into [`Frame`]s using the 8 byte [`PacketHeader`] prefix
+///
+/// Partial headers and payloads are buffered until the rest arrives, and frames
+/// announcing a payload larger than `max_length` are rejected before any of the
+/// payload is buffered.
+#[derive(Debug, Clone)]
+pub struct PacketCodec {
+    max_length: u32,
+}
+
+impl PacketCodec {
+    pub fn new() -> Self {
+        Self::with_max_length(MAX_FRAME_LENGTH)
+    }
+
+    pub fn with_max_length(max_length: u32) -> Self {
+        Self { max_length }
+    }
+}
+
+impl Default for PacketCodec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder for PacketCodec {
+    type Item = Frame;
+    type Error = PacketError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Frame>, PacketError> {
+        if src.len() < PacketHeader::SIZE {
+            src.reserve(PacketHeader::SIZE - src.len());
+            return Ok(None);
+        }
+
+        let header = PacketHeader::from_bytes(&src[..PacketHeader::SIZE])?;
+        if header.length() > self.max_length {
+            return Err(PacketError::FrameTooLarge {
+                length: header.length(),
+                max_length: self.max_length,
+            });
+        }
+
+        let frame_length = PacketHeader::SIZE + header.length() as usize;
+        if src.len() < frame_length {
+            src.reserve(frame_length - src.len());
+            return Ok(None);
+        }
+
+        src.advance(PacketHeader::SIZE);
+        let payload = src.split_to(header.length() as usize).to_vec();
+        Ok(Some(Frame::new(header.id(), payload)))
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Frame>, PacketError> {
+        match self.decode(src)? {
+            Some(frame) => Ok(Some(frame)),
+            None if src.is_empty() => Ok(None),
+            None => Err(PacketError::UnexpectedEof),
+        }
+    }
+}
+
+impl Encoder<Frame> for PacketCodec {
+    type Error = PacketError;
+
+    fn encode(&mut self, frame: Frame, dst: &mut BytesMut) -> Result<(), PacketError> {
+        let header = frame.header();
+        if header.length() > self.max_length {
+            return Err(PacketError::FrameTooLarge {
+                length: header.length(),
+                max_length: self.max_length,
+            });
+        }
+        dst.reserve(PacketHeader::SIZE + frame.payload.len());
+        dst.put_slice(&header.to_bytes());
+        dst.put_slice(&frame.payload);
+        Ok(())
+    }
+}
+
+/// Push a packet through [`Frame`] and [`PacketCodec`] and decode it back
+#[cfg(test)]
+pub fn wire_round_trip<T>(packet: T::Packet) -> T::Packet
+where
+    T: PacketId + TryFrom<u32> + for<'b> TryFrom<&'b T::Packet>,
+{
+    let mut codec = PacketCodec::new();
+    let mut bytes = BytesMut::new();
+    codec
+        .encode(Frame::from_packet::<T>(packet).unwrap(), &mut bytes)
+        .unwrap();
+    let mut frame = codec.decode(&mut bytes).unwrap().unwrap();
+    assert!(bytes.is_empty());
+    frame.decode::<T>().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{SinkExt, StreamExt};
+    use tokio::io::AsyncWriteExt;
+    use tokio_util::codec::{FramedRead, FramedWrite};
+
+    use super::*;
+
+    fn encode_all(frames: &[Frame]) -> Vec<u8> {
+        let mut codec = PacketCodec::new();
+        let mut buffer = BytesMut::new();
+        for frame in frames {
+            codec.encode(frame.clone(), &mut buffer).unwrap();
+        }
+        buffer.to_vec()
+    }
+
+    fn sample_frames() -> Vec<Frame> {
+        vec![
+            Frame::new(0, vec![b'a'; 64]),
+            Frame::new(1, vec![1, 0, 2, 0, 3, 0, 128, 0, 0, 0, 0, 0, 0, 0]),
+            Frame::new(2, Vec::new()),
+            Frame::new(2, (0..=255).collect()),
+        ]
+    }
+
+    #[test]
+    fn decodes_byte_by_byte() {
+        let frames = sample_frames();
+        let bytes = encode_all(&frames);
+        let mut codec = PacketCodec::new();
+        let mut buffer = BytesMut::new();
+        let mut decoded = Vec::new();
+        for byte in bytes {
+            buffer.put_u8(byte);
+            while let Some(frame) = codec.decode(&mut buffer).unwrap() {
+                decoded.push(frame);
+            }
+        }
+        assert_eq!(decoded, frames);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn decodes_coalesced_frames() {
+        let frames = sample_frames();
+        let mut buffer = BytesMut::from(&encode_all(&frames)[..]);
+        let mut codec = PacketCodec::new();
+        let mut decoded = Vec::new();
+        while let Some(frame) = codec.decode(&mut buffer).unwrap() {
+            decoded.push(frame);
+        }
+        assert_eq!(decoded, frames);
+    }
+
+    #[test]
+    fn rejects_oversized_frames() {
+        let mut codec = PacketCodec::with_max_length(16);
+        let mut buffer = BytesMut::from(&PacketHeader::new(17, 0).to_bytes()[..]);
+        assert!(matches!(
+            codec.decode(&mut buffer),
+            Err(PacketError::FrameTooLarge {
+                length: 17,
+                max_length: 16
+            })
+        ));
+        assert!(matches!(
+            codec.encode(Frame::new(0, vec![0; 17]), &mut BytesMut::new()),
+            Err(PacketError::FrameTooLarge { .. })
+        ));
+    }
+
+    #[test]
+    fn truncated_frame_at_eof_is_an_error() {
+        let bytes = encode_all(&[Frame::new(1, vec![0; 14])]);
+        let mut codec = PacketCodec::new();
+        let mut buffer = BytesMut::from(&bytes[..bytes.len() - 1]);
+        assert!(matches!(
+            codec.decode_eof(&mut buffer),
+            Err(PacketError::UnexpectedEof)
+        ));
+        assert!(codec.decode_eof(&mut BytesMut::new()).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn framed_stream_handles_split_writes() {
+        let frames = sample_frames();
+        let bytes = encode_all(&frames);
+        let (mut writer, reader) = tokio::io::duplex(4);
+        let producer = tokio::spawn(async move {
+            for byte in bytes {
+                writer.write_all(&[byte]).await.unwrap();
+            }
+        });
+        let decoded = FramedRead::new(reader, PacketCodec::new())
+            .map(|frame| frame.unwrap())
+            .collect::<Vec<_>>()
+            .await;
+        producer.await.unwrap();
+        assert_eq!(decoded, frames);
+    }
+
+    #[tokio::test]
+    async fn framed_sink_flushes_every_frame() {
+        let (writer, reader) = tokio::io::duplex(1024);
+        let mut sink = FramedWrite::new(writer, PacketCodec::new());
+        let mut stream = FramedRead::new(reader, PacketCodec::new());
+        for frame in sample_frames() {
+            sink.send(frame.clone()).await.unwrap();
+            assert_eq!(stream.next().await.unwrap().unwrap(), frame);
+        }
+        drop(sink);
+        assert!(stream.next().await.is_none());
+    }
+}
@@ -0,0 +1,416 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::utils::{buffer_reader::BufferReader, buffer_writer::BufferWriter};
+
+use super::{
+    packet::{PacketError, PacketField, PacketId},
+    server::ServerPacketId,
+    settings::DeviceSettings,
+    ServerPacket,
+};
+
+/// Number of finished commands kept around for [`CommandTracker::status`]
+const HISTORY_LENGTH: usize = 256;
+
+/// A command tracked until the device answers it, see [`DeviceCommand::packet`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceCommand {
+    Cooler {
+        status: bool,
+    },
+    WaterPulse,
+    /// Replace the settings of the device, it restarts itself if needed
+    Configure(DeviceSettings),
+    Reboot,
+    /// Wipe the stored settings and go back to the firmware defaults
+    FactoryReset,
+    /// Run the cooler at `duty` out of 255, 0 stops it
+    CoolerDuty {
+        duty: u8,
+    },
+    /// Run the water pump for `millis` instead of the configured pulse
+    Water {
+        millis: u32,
+    },
+}
+
+/// A `u8` kind followed by the arguments of that kind
+impl PacketField for DeviceCommand {
+    fn read(buffer: &mut BufferReader) -> Result<Self, PacketError> {
+        match u8::read(buffer)? {
+            0 => Ok(Self::Cooler {
+                status: bool::read(buffer)?,
+            }),
+            1 => Ok(Self::WaterPulse),
+            2 => Ok(Self::Configure(DeviceSettings::read(buffer)?)),
+            3 => Ok(Self::Reboot),
+            4 => Ok(Self::FactoryReset),
+            5 => Ok(Self::CoolerDuty {
+                duty: u8::read(buffer)?,
+            }),
+            6 => Ok(Self::Water {
+                millis: u32::read(buffer)?,
+            }),
+            _ => Err(PacketError::InvalidPacketId),
+        }
+    }
+
+    fn write(&self, buffer: &mut BufferWriter) {
+        match self {
+            Self::Cooler { status } => {
+                buffer.write_u8(0).write_bool(*status);
+            }
+            Self::WaterPulse => {
+                buffer.write_u8(1);
+            }
+            Self::Configure(settings) => {
+                buffer.write_u8(2);
+                settings.write(buffer);
+            }
+            Self::Reboot => {
+                buffer.write_u8(3);
+            }
+            Self::FactoryReset => {
+                buffer.write_u8(4);
+            }
+            Self::CoolerDuty { duty } => {
+                buffer.write_u8(5).write_u8(*duty);
+            }
+            Self::Water { millis } => {
+                buffer.write_u8(6).write_u32(*millis);
+            }
+        };
+    }
+}
+
+impl DeviceCommand {
+    /// The packet carrying this command, actuator commands go in [`ServerPacket::Command`]
+    /// and maintenance commands have dedicated packets
+    pub fn packet(&self, sequence: u32) -> ServerPacket {
+        match self {
+            Self::Cooler { .. } | Self::WaterPulse => ServerPacket::Command {
+                sequence,
+                command: self.clone(),
+            },
+            Self::Configure(settings) => ServerPacket::Configure {
+                sequence,
+                settings: settings.clone(),
+            },
+            Self::Reboot => ServerPacket::Reboot { sequence },
+            Self::FactoryReset => ServerPacket::FactoryReset { sequence },
+            Self::CoolerDuty { duty } => ServerPacket::SetCoolerDuty {
+                sequence,
+                duty: *duty,
+            },
+            Self::Water { millis } => ServerPacket::RunPump {
+                sequence,
+                millis: *millis,
+            },
+        }
+    }
+
+    /// Oldest protocol version the packet of this command exists in
+    pub fn since_version(&self) -> u16 {
+        ServerPacketId::from(&self.packet(0)).since_version()
+    }
+
+    /// The fire-and-forget packet understood by devices older than protocol version 4
+    pub fn legacy_packet(&self) -> Option<ServerPacket> {
+        match self {
+            Self::Cooler { status } => Some(ServerPacket::UpdateCooler { status: *status }),
+            Self::WaterPulse => Some(ServerPacket::WaterPulse),
+            Self::Configure(..)
+            | Self::Reboot
+            | Self::FactoryReset
+            | Self::CoolerDuty { .. }
+            | Self::Water { .. } => None,
+        }
+    }
+}
+
+/// How a command ended
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommandOutcome {
+    /// The device executed the command
+    Acknowledged,
+    /// The device refused or failed to execute the command
+    Rejected { reason: String },
+    /// The device speaks a protocol without acknowledgements, the command was only sent
+    Unconfirmed,
+    /// No answer after every retry
+    TimedOut,
+    /// The device went away before answering
+    Disconnected,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommandStatus {
+    Pending { attempts: u32 },
+    Completed(CommandOutcome),
+}
+
+/// A finished command, as reported to subscribers
+#[derive(Debug, Clone)]
+pub struct CommandRecord {
+    pub id: [char; 64],
+    pub sequence: u32,
+    pub command: DeviceCommand,
+    pub attempts: u32,
+    pub outcome: CommandOutcome,
+}
+
+/// How long to wait for an acknowledgement and how often to resend
+#[derive(Debug, Clone, Copy)]
+pub struct CommandConfig {
+    pub ack_timeout: Duration,
+    pub max_attempts: u32,
+}
+
+impl Default for CommandConfig {
+    fn default() -> Self {
+        Self {
+            ack_timeout: Duration::from_secs(5),
+            max_attempts: 3,
+        }
+    }
+}
+
+struct PendingCommand {
+    id: [char; 64],
+    command: DeviceCommand,
+    attempts: u32,
+    deadline: Instant,
+}
+
+/// A command that has to be sent again
+pub struct Retry {
+    pub id: [char; 64],
+    pub sequence: u32,
+    pub command: DeviceCommand,
+}
+
+/// Outstanding commands of every device
+///
+/// Sequence numbers are unique across devices and sessions so a late answer
+/// from an old connection can never complete a newer command.
+pub struct CommandTracker {
+    config: CommandConfig,
+    next_sequence: u32,
+    pending: HashMap<u32, PendingCommand>,
+    history: VecDeque<CommandRecord>,
+}
+
+impl CommandTracker {
+    pub fn new(config: CommandConfig) -> Self {
+        Self {
+            config,
+            next_sequence: 1,
+            pending: HashMap::new(),
+            history: VecDeque::new(),
+        }
+    }
+
+    /// Register a new command and return its sequence number
+    pub fn issue(&mut self, id: [char; 64], command: DeviceCommand, now: Instant) -> u32 {
+        let sequence = self.next_sequence;
+        self.next_sequence = self.next_sequence.wrapping_add(1).max(1);
+        self.pending.insert(
+            sequence,
+            PendingCommand {
+                id,
+                command,
+                attempts: 1,
+                deadline: now + self.config.ack_timeout,
+            },
+        );
+        sequence
+    }
+
+    /// Finish a command, ignoring answers for commands that are no longer pending
+    /// or that belong to another device
+    pub fn complete(
+        &mut self,
+        id: [char; 64],
+        sequence: u32,
+        outcome: CommandOutcome,
+    ) -> Option<CommandRecord> {
+        if self.pending.get(&sequence)?.id != id {
+            return None;
+        }
+        let pending = self.pending.remove(&sequence)?;
+        Some(self.finish(sequence, pending, outcome))
+    }
+
+    /// Commands due for another attempt, and commands that ran out of attempts
+    pub fn poll(&mut self, now: Instant) -> (Vec<Retry>, Vec<CommandRecord>) {
+        let mut retries = Vec::new();
+        let mut expired = Vec::new();
+        for (&sequence, pending) in self.pending.iter_mut() {
+            if pending.deadline > now {
+                continue;
+            }
+            if pending.attempts < self.config.max_attempts {
+                pending.attempts += 1;
+                pending.deadline = now + self.config.ack_timeout;
+                retries.push(Retry {
+                    id: pending.id,
+                    sequence,
+                    command: pending.command.clone(),
+                });
+            } else {
+                expired.push(sequence);
+            }
+        }
+        let expired = expired
+            .into_iter()
+            .filter_map(|sequence| {
+                let pending = self.pending.remove(&sequence)?;
+                Some(self.finish(sequence, pending, CommandOutcome::TimedOut))
+            })
+            .collect();
+        (retries, expired)
+    }
+
+    /// Fail every outstanding command of a device that went away
+    pub fn disconnect(&mut self, id: [char; 64]) -> Vec<CommandRecord> {
+        let sequences = self
+            .pending
+            .iter()
+            .filter(|(_, pending)| pending.id == id)
+            .map(|(sequence, _)| *sequence)
+            .collect::<Vec<_>>();
+        sequences
+            .into_iter()
+            .filter_map(|sequence| {
+                let pending = self.pending.remove(&sequence)?;
+                Some(self.finish(sequence, pending, CommandOutcome::Disconnected))
+            })
+            .collect()
+    }
+
+    pub fn status(&self, sequence: u32) -> Option<CommandStatus> {
+        if let Some(pending) = self.pending.get(&sequence) {
+            return Some(CommandStatus::Pending {
+                attempts: pending.attempts,
+            });
+        }
+        self.history
+            .iter()
+            .find(|record| record.sequence == sequence)
+            .map(|record| CommandStatus::Completed(record.outcome.clone()))
+    }
+
+    fn finish(
+        &mut self,
+        sequence: u32,
+        pending: PendingCommand,
+        outcome: CommandOutcome,
+    ) -> CommandRecord {
+        let record = CommandRecord {
+            id: pending.id,
+            sequence,
+            command: pending.command,
+            attempts: pending.attempts,
+            outcome,
+        };
+        if self.history.len() == HISTORY_LENGTH {
+            self.history.pop_front();
+        }
+        self.history.push_back(record.clone());
+        record
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEVICE: [char; 64] = ['d'; 64];
+
+    fn tracker() -> CommandTracker {
+        CommandTracker::new(CommandConfig {
+            ack_timeout: Duration::from_secs(1),
+            max_attempts: 2,
+        })
+    }
+
+    #[test]
+    fn acknowledged_command_completes_once() {
+        let mut tracker = tracker();
+        let now = Instant::now();
+        let sequence = tracker.issue(DEVICE, DeviceCommand::WaterPulse, now);
+        assert_eq!(
+            tracker.status(sequence),
+            Some(CommandStatus::Pending { attempts: 1 })
+        );
+        let record = tracker
+            .complete(DEVICE, sequence, CommandOutcome::Acknowledged)
+            .unwrap();
+        assert_eq!(record.command, DeviceCommand::WaterPulse);
+        assert!(tracker
+            .complete(DEVICE, sequence, CommandOutcome::Acknowledged)
+            .is_none());
+        assert_eq!(
+            tracker.status(sequence),
+            Some(CommandStatus::Completed(CommandOutcome::Acknowledged))
+        );
+    }
+
+    #[test]
+    fn answers_from_other_devices_are_ignored() {
+        let mut tracker = tracker();
+        let sequence = tracker.issue(DEVICE, DeviceCommand::WaterPulse, Instant::now());
+        assert!(tracker
+            .complete(['x'; 64], sequence, CommandOutcome::Acknowledged)
+            .is_none());
+        assert!(matches!(
+            tracker.status(sequence),
+            Some(CommandStatus::Pending { .. })
+        ));
+    }
+
+    #[test]
+    fn retries_then_times_out() {
+        let mut tracker = tracker();
+        let now = Instant::now();
+        let sequence = tracker.issue(DEVICE, DeviceCommand::Cooler { status: true }, now);
+
+        let (retries, expired) = tracker.poll(now);
+        assert!(retries.is_empty() && expired.is_empty());
+
+        let (retries, expired) = tracker.poll(now + Duration::from_secs(1));
+        assert_eq!(retries.len(), 1);
+        assert_eq!(retries[0].sequence, sequence);
+        assert!(expired.is_empty());
+
+        let (retries, expired) = tracker.poll(now + Duration::from_secs(2));
+        assert!(retries.is_empty());
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].attempts, 2);
+        assert_eq!(
+            tracker.status(sequence),
+            Some(CommandStatus::Completed(CommandOutcome::TimedOut))
+        );
+    }
+
+    #[test]
+    fn disconnect_fails_pending_commands() {
+        let mut tracker = tracker();
+        let now = Instant::now();
+        tracker.issue(DEVICE, DeviceCommand::WaterPulse, now);
+        let other = tracker.issue(['x'; 64], DeviceCommand::WaterPulse, now);
+        let failed = tracker.disconnect(DEVICE);
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].outcome, CommandOutcome::Disconnected);
+        assert!(matches!(
+            tracker.status(other),
+            Some(CommandStatus::Pending { .. })
+        ));
+    }
+}
@@ -0,0 +1,681 @@
+use std::time::{Duration, Instant, SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+use super::{
+    command::DeviceCommand,
+    handshake::Capabilities,
+    sensors::CentiCelsius,
+    telemetry::{self, SensorRecord, SensorType, Unit},
+};
+
+/// Policy used for farms that didn't pick one, or picked one this server doesn't know
+pub const DEFAULT_POLICY: &str = "threshold";
+/// Drives variable speed coolers with [`Pid`], other farms are run like [`DEFAULT_POLICY`]
+pub const PID_POLICY: &str = "pid";
+
+/// Duty changes smaller than this aren't sent, except to stop or to run at full speed
+const DUTY_STEP: u8 = 8;
+/// Longest gap between reports the integral and derivative terms are updated over,
+/// anything longer is treated as a fresh start
+const MAX_PID_STEP: Duration = Duration::from_secs(15 * 60);
+
+/// Thresholds the farm service drives the actuators with
+#[derive(Debug, Clone, Copy)]
+pub struct ControlConfig {
+    /// Soil moisture readings above this start the water pump, higher is drier
+    pub soil_moisture_threshold: u16,
+}
+
+impl Default for ControlConfig {
+    fn default() -> Self {
+        Self {
+            soil_moisture_threshold: 500,
+        }
+    }
+}
+
+/// How the cooler of one farm is switched
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CoolerSettings {
+    /// Width in hundredths of a degree of the band around the target temperature
+    /// in which the cooler keeps its state
+    pub deadband: u16,
+    /// Seconds the cooler runs at least once started
+    pub min_run: u64,
+    /// Seconds the cooler rests at least once stopped
+    pub min_rest: u64,
+}
+
+impl Default for CoolerSettings {
+    fn default() -> Self {
+        Self {
+            deadband: 100,
+            min_run: 180,
+            min_rest: 180,
+        }
+    }
+}
+
+/// Gains of the cooler PID loop, the output is a duty cycle out of 255 and the
+/// error is in degrees above the target
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PidGains {
+    /// Duty per degree
+    pub kp: f32,
+    /// Duty per degree and second
+    pub ki: f32,
+    /// Duty per degree per second
+    pub kd: f32,
+}
+
+impl Default for PidGains {
+    fn default() -> Self {
+        Self {
+            kp: 40.0,
+            ki: 0.05,
+            kd: 0.0,
+        }
+    }
+}
+
+/// Per-farm tuning, stored as JSON in `farms.control_settings`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ControlSettings {
+    pub cooler: CoolerSettings,
+    pub pid: PidGains,
+}
+
+/// A PID loop with its output clamped to a cooler duty cycle
+///
+/// The integral only grows while the output isn't saturated in the direction of
+/// the error, so a target the cooler can't reach doesn't leave a wound up term
+/// behind.
+#[derive(Debug, Clone, Default)]
+pub struct Pid {
+    integral: f32,
+    /// Last measurement and when it was taken
+    last: Option<(f32, SystemTime)>,
+}
+
+impl Pid {
+    /// The duty for `measurement` taken `at`, both temperatures in degrees
+    pub fn update(
+        &mut self,
+        gains: &PidGains,
+        target: f32,
+        measurement: f32,
+        at: SystemTime,
+    ) -> u8 {
+        let error = measurement - target;
+        let step = self.last.and_then(|(previous, last_at)| {
+            let dt = at.duration_since(last_at).ok()?;
+            (!dt.is_zero() && dt <= MAX_PID_STEP).then_some((previous, dt.as_secs_f32()))
+        });
+        // On the measurement, so changing the target doesn't kick the output
+        let derivative = step.map_or(0.0, |(previous, dt)| (measurement - previous) / dt);
+        let integral = self.integral + step.map_or(0.0, |(_, dt)| error * dt);
+        let output = |integral: f32| gains.kp * error + gains.ki * integral + gains.kd * derivative;
+        let max = f32::from(u8::MAX);
+        let winding_up =
+            (output(integral) > max && error > 0.0) || (output(integral) < 0.0 && error < 0.0);
+        if !winding_up {
+            self.integral = integral;
+        }
+        if step.is_some() || self.last.is_none_or(|(_, last_at)| at > last_at) {
+            self.last = Some((measurement, at));
+        }
+        output(self.integral).clamp(0.0, max).round() as u8
+    }
+}
+
+/// Sends only changes of the cooler state and keeps the compressor from short cycling
+#[derive(Debug, Clone, Default)]
+pub struct CoolerGuard {
+    /// Last state sent and when it was sent, `None` until the first command or
+    /// after a command that didn't reach the device
+    state: Option<(bool, Instant)>,
+}
+
+impl CoolerGuard {
+    /// The state the device was last told to be in
+    pub fn state(&self) -> Option<bool> {
+        self.state.map(|(status, _)| status)
+    }
+
+    /// Whether switching to `status` has to be sent now, recording it if so
+    pub fn allow(&mut self, status: bool, settings: &CoolerSettings, now: Instant) -> bool {
+        if let Some((current, since)) = self.state {
+            let hold = if current {
+                settings.min_run
+            } else {
+                settings.min_rest
+            };
+            if current == status || now.duration_since(since) < Duration::from_secs(hold) {
+                return false;
+            }
+        }
+        self.state = Some((status, now));
+        true
+    }
+
+    /// A command switching to `status` failed, so the device state is unknown
+    pub fn forget(&mut self, status: bool) {
+        if self.state() == Some(status) {
+            self.state = None;
+        }
+    }
+}
+
+/// The validated readings of one report
+#[derive(Debug, Clone, Copy)]
+pub struct SensorSnapshot<'a> {
+    pub records: &'a [SensorRecord],
+    pub captured_at: SystemTime,
+}
+
+impl SensorSnapshot<'_> {
+    pub fn value(&self, sensor: SensorType, index: u8, unit: Unit) -> Option<i32> {
+        telemetry::find(self.records, sensor, index, unit)
+    }
+
+    /// Temperature of the first air probe
+    pub fn air_temperature(&self) -> Option<CentiCelsius> {
+        self.value(SensorType::AIR_TEMPERATURE, 0, Unit::CentiCelsius)
+            .and_then(|value| i16::try_from(value).ok())
+            .map(CentiCelsius)
+    }
+
+    /// Raw reading of the first soil probe, higher is drier
+    pub fn soil_moisture(&self) -> Option<u16> {
+        self.value(SensorType::SOIL_MOISTURE, 0, Unit::Raw)
+            .and_then(|value| u16::try_from(value).ok())
+    }
+}
+
+/// What the farm service knows about a farm besides its latest readings
+#[derive(Debug, Clone, Copy)]
+pub struct FarmState {
+    pub id: [char; 64],
+    pub capabilities: Capabilities,
+    /// `0` for devices that don't speak the farm protocol, like MQTT devices, which
+    /// are sent every command
+    pub protocol_version: u16,
+    pub target_temperature: CentiCelsius,
+    pub settings: ControlSettings,
+    /// What the cooler was last switched to, `None` when unknown
+    pub cooler: Option<bool>,
+}
+
+impl FarmState {
+    /// Whether the device can be sent `command`
+    pub fn supports(&self, command: &DeviceCommand) -> bool {
+        self.protocol_version == 0 || command.since_version() <= self.protocol_version
+    }
+
+    /// Whether the device can be sent `command` and has the actuator it drives
+    pub fn can_run(&self, command: &DeviceCommand) -> bool {
+        let needs = match command {
+            DeviceCommand::Cooler { .. } => Capabilities::COOLER,
+            DeviceCommand::CoolerDuty { .. } => Capabilities::VARIABLE_COOLER,
+            DeviceCommand::WaterPulse | DeviceCommand::Water { .. } => Capabilities::WATER_PUMP,
+            DeviceCommand::Configure(..) | DeviceCommand::Reboot | DeviceCommand::FactoryReset => {
+                Capabilities::default()
+            }
+        };
+        self.capabilities.contains(needs) && self.supports(command)
+    }
+
+    /// Cooler on above the deadband around the target temperature, off below it
+    fn cooler_switch(&self, air_temperature: CentiCelsius) -> bool {
+        let temperature = i32::from(air_temperature.0);
+        let target = i32::from(self.target_temperature.0);
+        let half_band = i32::from(self.settings.cooler.deadband / 2);
+        if temperature > target + half_band {
+            true
+        } else if temperature < target - half_band {
+            false
+        } else {
+            self.cooler.unwrap_or(temperature > target)
+        }
+    }
+}
+
+/// One water pulse per report while the soil is drier than the threshold
+fn water(
+    config: &ControlConfig,
+    snapshot: &SensorSnapshot,
+    farm: &FarmState,
+) -> Option<DeviceCommand> {
+    (snapshot
+        .soil_moisture()
+        .is_some_and(|value| value > config.soil_moisture_threshold)
+        && farm.capabilities.contains(Capabilities::WATER_PUMP))
+    .then_some(DeviceCommand::WaterPulse)
+}
+
+/// Turns the readings of a farm into commands for its actuators
+///
+/// Every connected farm gets its own instance, so a policy may keep state
+/// between reports.
+pub trait ControlPolicy: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn decide(&mut self, snapshot: &SensorSnapshot, farm: &FarmState) -> Vec<DeviceCommand>;
+
+    /// `command` was rejected by the device or timed out, so it never took effect
+    fn forget(&mut self, _command: &DeviceCommand) {}
+}
+
+/// Cooler on above the deadband around the target temperature and off below it,
+/// one water pulse per report while the soil is drier than the threshold
+#[derive(Debug, Clone)]
+pub struct ThresholdPolicy {
+    config: ControlConfig,
+}
+
+impl ThresholdPolicy {
+    pub fn new(config: ControlConfig) -> Self {
+        Self { config }
+    }
+}
+
+impl ControlPolicy for ThresholdPolicy {
+    fn name(&self) -> &'static str {
+        DEFAULT_POLICY
+    }
+
+    fn decide(&mut self, snapshot: &SensorSnapshot, farm: &FarmState) -> Vec<DeviceCommand> {
+        let mut commands = Vec::new();
+        if let Some(air_temperature) = snapshot.air_temperature() {
+            if farm.capabilities.contains(Capabilities::COOLER) {
+                commands.push(DeviceCommand::Cooler {
+                    status: farm.cooler_switch(air_temperature),
+                });
+            }
+        }
+        commands.extend(water(&self.config, snapshot, farm));
+        commands
+    }
+}
+
+/// Sets the speed of variable speed coolers with a PID loop toward the target
+/// temperature, keeping the minimum run and rest times of the compressor
+#[derive(Debug, Clone)]
+pub struct PidPolicy {
+    config: ControlConfig,
+    pid: Pid,
+    /// Last duty sent and when the cooler last started or stopped
+    duty: Option<(u8, SystemTime)>,
+}
+
+impl PidPolicy {
+    pub fn new(config: ControlConfig) -> Self {
+        Self {
+            config,
+            pid: Pid::default(),
+            duty: None,
+        }
+    }
+
+    fn cooler_duty(
+        &mut self,
+        air_temperature: CentiCelsius,
+        farm: &FarmState,
+        at: SystemTime,
+    ) -> Option<u8> {
+        let mut duty = self.pid.update(
+            &farm.settings.pid,
+            farm.target_temperature.degrees(),
+            air_temperature.degrees(),
+            at,
+        );
+        let Some((last, switched_at)) = self.duty else {
+            self.duty = Some((duty, at));
+            return Some(duty);
+        };
+        let cooler = &farm.settings.cooler;
+        if (duty > 0) != (last > 0) {
+            let hold = if last > 0 {
+                cooler.min_run
+            } else {
+                cooler.min_rest
+            };
+            let held = at
+                .duration_since(switched_at)
+                .is_ok_and(|held| held >= Duration::from_secs(hold));
+            if !held {
+                // Keep running at the lowest speed or stay stopped until the hold ends
+                duty = if last > 0 { duty.max(1) } else { 0 };
+            }
+        }
+        let switched_at = if (duty > 0) != (last > 0) {
+            at
+        } else {
+            switched_at
+        };
+        let significant =
+            duty.abs_diff(last) >= DUTY_STEP || (duty != last && matches!(duty, 0 | u8::MAX));
+        if !significant {
+            self.duty = Some((last, switched_at));
+            return None;
+        }
+        self.duty = Some((duty, switched_at));
+        Some(duty)
+    }
+}
+
+impl ControlPolicy for PidPolicy {
+    fn name(&self) -> &'static str {
+        PID_POLICY
+    }
+
+    fn decide(&mut self, snapshot: &SensorSnapshot, farm: &FarmState) -> Vec<DeviceCommand> {
+        let mut commands = Vec::new();
+        let variable = farm.capabilities.contains(Capabilities::VARIABLE_COOLER)
+            && farm.supports(&DeviceCommand::CoolerDuty { duty: 0 });
+        if let Some(air_temperature) = snapshot.air_temperature() {
+            if variable {
+                let duty = self.cooler_duty(air_temperature, farm, snapshot.captured_at);
+                commands.extend(duty.map(|duty| DeviceCommand::CoolerDuty { duty }));
+            } else if farm.capabilities.contains(Capabilities::COOLER) {
+                commands.push(DeviceCommand::Cooler {
+                    status: farm.cooler_switch(air_temperature),
+                });
+            }
+        }
+        commands.extend(water(&self.config, snapshot, farm));
+        commands
+    }
+
+    fn forget(&mut self, command: &DeviceCommand) {
+        // The device runs at an unknown duty, the next report sends the current one
+        if let DeviceCommand::CoolerDuty { duty } = command {
+            if self.duty.is_some_and(|(last, _)| last == *duty) {
+                self.duty = None;
+            }
+        }
+    }
+}
+
+/// The policy called `name`, `None` if there is no such policy
+pub fn policy(name: &str, config: &ControlConfig) -> Option<Box<dyn ControlPolicy>> {
+    match name {
+        DEFAULT_POLICY => Some(Box::new(ThresholdPolicy::new(*config))),
+        PID_POLICY => Some(Box::new(PidPolicy::new(*config))),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::farm_service::sensors::Readings;
+
+    fn farm(capabilities: Capabilities) -> FarmState {
+        FarmState {
+            id: ['f'; 64],
+            capabilities,
+            target_temperature: CentiCelsius::from_degrees(25),
+            protocol_version: 11,
+            settings: ControlSettings::default(),
+            cooler: None,
+        }
+    }
+
+    fn decide(
+        policy: &mut dyn ControlPolicy,
+        readings: Readings,
+        farm: &FarmState,
+    ) -> Vec<DeviceCommand> {
+        let records = readings.records();
+        let snapshot = SensorSnapshot {
+            records: &records,
+            captured_at: SystemTime::now(),
+        };
+        policy.decide(&snapshot, farm)
+    }
+
+    #[test]
+    fn threshold_policy_keeps_the_old_decisions() {
+        let mut policy = policy(DEFAULT_POLICY, &ControlConfig::default()).unwrap();
+        let everything = farm(Capabilities::SUPPORTED);
+        assert_eq!(
+            decide(&mut *policy, Readings::legacy(501, 26, 0), &everything),
+            [
+                DeviceCommand::Cooler { status: true },
+                DeviceCommand::WaterPulse
+            ]
+        );
+        assert_eq!(
+            decide(&mut *policy, Readings::legacy(500, 25, 0), &everything),
+            [DeviceCommand::Cooler { status: false }]
+        );
+        assert_eq!(
+            decide(
+                &mut *policy,
+                Readings::legacy(900, 40, 0),
+                &farm(Capabilities::CAMERA)
+            ),
+            []
+        );
+    }
+
+    #[test]
+    fn cooler_keeps_its_state_inside_the_deadband() {
+        let mut policy = ThresholdPolicy::new(ControlConfig::default());
+        let cooling = FarmState {
+            cooler: Some(true),
+            ..farm(Capabilities::COOLER)
+        };
+        let resting = FarmState {
+            cooler: Some(false),
+            ..cooling
+        };
+        let at = |celsius: f32| Readings {
+            air_temperature: CentiCelsius::from_degrees_f32(celsius),
+            ..Readings::legacy(0, 0, 0)
+        };
+        for (readings, farm, status) in [
+            (at(25.4), &cooling, true),
+            (at(25.4), &resting, false),
+            (at(24.6), &cooling, true),
+            (at(25.6), &resting, true),
+            (at(24.4), &cooling, false),
+        ] {
+            assert_eq!(
+                decide(&mut policy, readings, farm),
+                [DeviceCommand::Cooler { status }],
+                "{readings:?} {farm:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn guard_sends_changes_after_the_minimum_times() {
+        let settings = CoolerSettings::default();
+        let mut guard = CoolerGuard::default();
+        let start = Instant::now();
+        let after = |secs| start + Duration::from_secs(secs);
+        assert!(guard.allow(true, &settings, start));
+        assert!(!guard.allow(true, &settings, after(10)));
+        // Still inside the minimum run time
+        assert!(!guard.allow(false, &settings, after(60)));
+        assert!(guard.allow(false, &settings, after(180)));
+        assert!(!guard.allow(true, &settings, after(300)));
+        assert!(guard.allow(true, &settings, after(360)));
+        guard.forget(false);
+        assert_eq!(guard.state(), Some(true));
+        guard.forget(true);
+        assert!(guard.allow(true, &settings, after(361)));
+    }
+
+    #[test]
+    fn settings_are_stored_as_json() {
+        let settings: ControlSettings =
+            serde_json::from_str(r#"{"cooler":{"deadband":50}}"#).unwrap();
+        assert_eq!(settings.cooler.deadband, 50);
+        assert_eq!(settings.cooler.min_run, CoolerSettings::default().min_run);
+        assert!(serde_json::from_str::<ControlSettings>(r#"{"coolr":{}}"#).is_err());
+    }
+
+    /// A room 32°C outside that loses heat toward the outside over half an hour,
+    /// and a cooler that removes up to 0.006°C a second
+    struct Room {
+        temperature: f32,
+    }
+
+    impl Room {
+        const AMBIENT: f32 = 32.0;
+
+        fn step(&mut self, duty: u8, secs: u32) {
+            for _ in 0..secs {
+                self.temperature +=
+                    (Self::AMBIENT - self.temperature) / 1800.0 - 0.006 * f32::from(duty) / 255.0;
+            }
+        }
+    }
+
+    /// Runs `pid` on reports every 30 seconds for `minutes`, returns the temperatures
+    fn run(
+        pid: &mut Pid,
+        room: &mut Room,
+        target: f32,
+        start: SystemTime,
+        minutes: u64,
+    ) -> Vec<f32> {
+        (0..minutes * 2)
+            .map(|step| {
+                let at = start + Duration::from_secs(step * 30);
+                let duty = pid.update(&PidGains::default(), target, room.temperature, at);
+                room.step(duty, 30);
+                room.temperature
+            })
+            .collect()
+    }
+
+    #[test]
+    fn pid_settles_on_the_target() {
+        let mut pid = Pid::default();
+        let mut room = Room {
+            temperature: Room::AMBIENT,
+        };
+        let temperatures = run(&mut pid, &mut room, 25.0, SystemTime::UNIX_EPOCH, 6 * 60);
+        let last_hour = &temperatures[temperatures.len() - 120..];
+        assert!(
+            last_hour
+                .iter()
+                .all(|temperature| (temperature - 25.0).abs() < 0.3),
+            "{last_hour:?}"
+        );
+    }
+
+    #[test]
+    fn pid_recovers_from_an_unreachable_target() {
+        let mut pid = Pid::default();
+        let mut room = Room {
+            temperature: Room::AMBIENT,
+        };
+        let start = SystemTime::UNIX_EPOCH;
+        // Full speed holds the room around 21°C, so 15°C is never reached
+        run(&mut pid, &mut room, 15.0, start, 4 * 60);
+        let temperatures = run(
+            &mut pid,
+            &mut room,
+            25.0,
+            start + Duration::from_secs(4 * 3600),
+            3 * 60,
+        );
+        // The room warms back up instead of staying cooled by a wound up integral
+        assert!(temperatures[..60]
+            .iter()
+            .any(|temperature| *temperature > 24.5));
+        let last_hour = &temperatures[temperatures.len() - 120..];
+        assert!(
+            last_hour
+                .iter()
+                .all(|temperature| (temperature - 25.0).abs() < 0.3),
+            "{last_hour:?}"
+        );
+    }
+
+    #[test]
+    fn pid_policy_sends_duty_changes_or_falls_back() {
+        let mut policy = policy(PID_POLICY, &ControlConfig::default()).unwrap();
+        let variable = farm(Capabilities::from_bits(
+            Capabilities::COOLER.bits() | Capabilities::VARIABLE_COOLER.bits(),
+        ));
+        let start = SystemTime::UNIX_EPOCH;
+        let report = |policy: &mut dyn ControlPolicy, celsius: f32, secs: u64, farm: &FarmState| {
+            let records = Readings {
+                air_temperature: CentiCelsius::from_degrees_f32(celsius),
+                ..Readings::legacy(0, 0, 0)
+            }
+            .records();
+            let snapshot = SensorSnapshot {
+                records: &records,
+                captured_at: start + Duration::from_secs(secs),
+            };
+            policy.decide(&snapshot, farm)
+        };
+        assert_eq!(
+            report(&mut *policy, 32.0, 0, &variable),
+            [DeviceCommand::CoolerDuty { duty: 255 }]
+        );
+        // Still saturated
+        assert_eq!(report(&mut *policy, 31.9, 30, &variable), []);
+        // Below the target, but inside the minimum run time
+        assert_eq!(
+            report(&mut *policy, 20.0, 60, &variable),
+            [DeviceCommand::CoolerDuty { duty: 1 }]
+        );
+        assert_eq!(
+            report(&mut *policy, 20.0, 240, &variable),
+            [DeviceCommand::CoolerDuty { duty: 0 }]
+        );
+
+        // A rejected duty is sent again instead of being taken as applied
+        policy.forget(&DeviceCommand::CoolerDuty { duty: 128 });
+        assert_eq!(report(&mut *policy, 20.0, 250, &variable), []);
+        policy.forget(&DeviceCommand::CoolerDuty { duty: 0 });
+        assert_eq!(
+            report(&mut *policy, 20.0, 260, &variable),
+            [DeviceCommand::CoolerDuty { duty: 0 }]
+        );
+
+        let old = FarmState {
+            protocol_version: 10,
+            ..variable
+        };
+        assert_eq!(
+            report(&mut *policy, 26.0, 270, &old),
+            [DeviceCommand::Cooler { status: true }]
+        );
+    }
+
+    #[test]
+    fn commands_need_the_actuator_and_protocol() {
+        let cooler = farm(Capabilities::COOLER);
+        assert!(cooler.can_run(&DeviceCommand::Cooler { status: true }));
+        assert!(!cooler.can_run(&DeviceCommand::CoolerDuty { duty: 10 }));
+        assert!(!cooler.can_run(&DeviceCommand::WaterPulse));
+        assert!(cooler.can_run(&DeviceCommand::Reboot));
+
+        let pump = farm(Capabilities::WATER_PUMP);
+        assert!(pump.can_run(&DeviceCommand::WaterPulse));
+        assert!(!pump.can_run(&DeviceCommand::Water { millis: 500 }));
+        let mqtt = FarmState {
+            protocol_version: 0,
+            ..pump
+        };
+        assert!(mqtt.can_run(&DeviceCommand::Water { millis: 500 }));
+    }
+
+    #[test]
+    fn unknown_policies_are_none() {
+        assert!(policy("bang-bang", &ControlConfig::default()).is_none());
+    }
+}
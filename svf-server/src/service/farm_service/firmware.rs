@@ -0,0 +1,513 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt::Display,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::utils::{buffer_reader::BufferReader, buffer_writer::BufferWriter};
+
+use super::packet::{PacketError, PacketField};
+
+/// Size of the OTA partition of the controllers
+pub const MAX_FIRMWARE_SIZE: usize = 4 * 1024 * 1024;
+
+/// Bytes sent in one `FirmwareChunk`, small enough for the device to buffer
+pub const FIRMWARE_CHUNK_SIZE: u16 = 1024;
+
+const MAX_VERSION_LENGTH: usize = 32;
+
+/// Lowercase hex of a digest, the form used by the admin API
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+pub fn parse_sha256(hex: &str) -> Option<[u8; 32]> {
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+    let mut digest = [0; 32];
+    for (byte, pair) in digest.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(digest)
+}
+
+/// A firmware image devices can be updated to
+#[derive(Debug, Clone)]
+pub struct Firmware {
+    pub version: String,
+    pub sha256: [u8; 32],
+    pub data: Arc<[u8]>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FirmwareInfo {
+    pub version: String,
+    pub sha256: String,
+    pub size: u32,
+}
+
+impl Firmware {
+    /// Check an uploaded image against its version and hash, blocks while hashing
+    pub fn verify(version: String, sha256: [u8; 32], data: Vec<u8>) -> Result<Self, FirmwareError> {
+        if !valid_version(&version) {
+            return Err(FirmwareError::InvalidVersion);
+        }
+        if data.is_empty() {
+            return Err(FirmwareError::Empty);
+        }
+        if data.len() > MAX_FIRMWARE_SIZE {
+            return Err(FirmwareError::TooLarge { size: data.len() });
+        }
+        if <[u8; 32]>::from(Sha256::digest(&data)) != sha256 {
+            return Err(FirmwareError::HashMismatch);
+        }
+        Ok(Self {
+            version,
+            sha256,
+            data: data.into(),
+        })
+    }
+
+    /// Write the image to `<dir>/<version>.bin`, blocking. A file stored for the
+    /// version before is never replaced and has to hold this same image
+    pub fn store(&self, dir: &Path) -> Result<(), FirmwareError> {
+        let storage = |err: io::Error| FirmwareError::Storage(err.to_string());
+        fs::create_dir_all(dir).map_err(storage)?;
+        let path = dir.join(format!("{}.bin", self.version));
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(mut file) => file.write_all(&self.data).map_err(|err| {
+                fs::remove_file(&path).ok();
+                storage(err)
+            }),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+                let existing = fs::read(&path).map_err(storage)?;
+                match <[u8; 32]>::from(Sha256::digest(&existing)) == self.sha256 {
+                    true => Ok(()),
+                    false => Err(FirmwareError::VersionExists),
+                }
+            }
+            Err(err) => Err(storage(err)),
+        }
+    }
+
+    pub fn size(&self) -> u32 {
+        self.data.len() as u32
+    }
+
+    pub fn info(&self) -> FirmwareInfo {
+        FirmwareInfo {
+            version: self.version.clone(),
+            sha256: hex(&self.sha256),
+            size: self.size(),
+        }
+    }
+
+    /// At most `chunk_size` bytes from `offset`, `None` past the end of the image
+    pub fn chunk(&self, offset: u32, chunk_size: u16) -> Option<&[u8]> {
+        let start = offset as usize;
+        if start >= self.data.len() {
+            return None;
+        }
+        let end = (start + chunk_size as usize).min(self.data.len());
+        Some(&self.data[start..end])
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum FirmwareError {
+    InvalidVersion,
+    Empty,
+    TooLarge {
+        size: usize,
+    },
+    HashMismatch,
+    /// Versions can't be replaced, devices may already run the old image
+    VersionExists,
+    Storage(String),
+}
+
+impl Display for FirmwareError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidVersion => write!(
+                f,
+                "Versions are 1 to {MAX_VERSION_LENGTH} letters, digits, '.', '-' or '_'"
+            ),
+            Self::Empty => write!(f, "The firmware image is empty"),
+            Self::TooLarge { size } => write!(
+                f,
+                "Firmware of {size} bytes is larger than {MAX_FIRMWARE_SIZE} bytes"
+            ),
+            Self::HashMismatch => write!(f, "The image does not match the SHA-256 hash"),
+            Self::VersionExists => write!(f, "A different image with this version exists"),
+            Self::Storage(error) => write!(f, "Cannot store the firmware: {error}"),
+        }
+    }
+}
+
+fn valid_version(version: &str) -> bool {
+    (1..=MAX_VERSION_LENGTH).contains(&version.len())
+        && version
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'))
+        && !version.starts_with('.')
+}
+
+/// Firmware images devices can be updated to, read from `<dir>/<version>.bin` on startup
+///
+/// Uploads are checked with [`Firmware::verify`] and written with [`Firmware::store`]
+/// before they're added, so hashing and disk writes stay out of the farm service.
+/// Without a directory images only live until the server restarts.
+pub struct FirmwareRepository {
+    images: BTreeMap<String, Firmware>,
+}
+
+impl FirmwareRepository {
+    /// Read every image stored in `dir`, unreadable files are skipped
+    pub fn load(dir: Option<PathBuf>) -> Self {
+        let mut images = BTreeMap::new();
+        let entries = dir.as_ref().and_then(|dir| fs::read_dir(dir).ok());
+        for path in entries.into_iter().flatten().flatten().map(|e| e.path()) {
+            let version = match path.file_stem().and_then(|stem| stem.to_str()) {
+                Some(version) if path.extension().is_some_and(|e| e == "bin") => version,
+                _ => continue,
+            };
+            if !valid_version(version) {
+                continue;
+            }
+            match fs::read(&path) {
+                Ok(data) => {
+                    let firmware = Firmware {
+                        version: version.to_string(),
+                        sha256: Sha256::digest(&data).into(),
+                        data: data.into(),
+                    };
+                    images.insert(firmware.version.clone(), firmware);
+                }
+                Err(err) => println!("Cannot read firmware {}: {err}", path.display()),
+            }
+        }
+        Self { images }
+    }
+
+    /// Offer a checked image for rollouts
+    pub fn add(&mut self, firmware: Firmware) -> Result<FirmwareInfo, FirmwareError> {
+        if let Some(existing) = self.images.get(&firmware.version) {
+            return match existing.sha256 == firmware.sha256 {
+                true => Ok(existing.info()),
+                false => Err(FirmwareError::VersionExists),
+            };
+        }
+        let info = firmware.info();
+        self.images.insert(firmware.version.clone(), firmware);
+        Ok(info)
+    }
+
+    pub fn get(&self, version: &str) -> Option<&Firmware> {
+        self.images.get(version)
+    }
+
+    pub fn list(&self) -> Vec<FirmwareInfo> {
+        self.images.values().map(Firmware::info).collect()
+    }
+}
+
+/// Progress a device reports with `FirmwareReport`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FirmwareStatus {
+    /// The device won't take the update, for example because it is busy
+    Declined {
+        reason: String,
+    },
+    /// Every byte was received and the hash matches
+    Verified,
+    VerifyFailed,
+    /// About to reboot into the new image
+    Applying,
+    Failed {
+        reason: String,
+    },
+}
+
+/// A `u8` kind followed by the reason where there is one
+impl PacketField for FirmwareStatus {
+    fn read(buffer: &mut BufferReader) -> Result<Self, PacketError> {
+        match u8::read(buffer)? {
+            0 => Ok(Self::Declined {
+                reason: String::read(buffer)?,
+            }),
+            1 => Ok(Self::Verified),
+            2 => Ok(Self::VerifyFailed),
+            3 => Ok(Self::Applying),
+            4 => Ok(Self::Failed {
+                reason: String::read(buffer)?,
+            }),
+            _ => Err(PacketError::InvalidPacketId),
+        }
+    }
+
+    fn write(&self, buffer: &mut BufferWriter) {
+        match self {
+            Self::Declined { reason } => {
+                buffer.write_u8(0);
+                reason.write(buffer);
+            }
+            Self::Verified => {
+                buffer.write_u8(1);
+            }
+            Self::VerifyFailed => {
+                buffer.write_u8(2);
+            }
+            Self::Applying => {
+                buffer.write_u8(3);
+            }
+            Self::Failed { reason } => {
+                buffer.write_u8(4);
+                reason.write(buffer);
+            }
+        }
+    }
+}
+
+/// Where a device is in a rollout, as seen by the server
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum UpdateState {
+    /// Targeted but not connected since the rollout started
+    Pending,
+    Offered,
+    Downloading {
+        offset: u32,
+        size: u32,
+    },
+    Verified,
+    Applying,
+    /// The device came back running the new version
+    Succeeded,
+    Declined {
+        reason: String,
+    },
+    Failed {
+        reason: String,
+    },
+    /// The device speaks a protocol without firmware updates
+    Unsupported,
+    Aborted,
+}
+
+impl UpdateState {
+    pub fn is_finished(&self) -> bool {
+        matches!(
+            self,
+            Self::Succeeded
+                | Self::Declined { .. }
+                | Self::Failed { .. }
+                | Self::Unsupported
+                | Self::Aborted
+        )
+    }
+
+    /// The state after a device report, `None` when the report doesn't fit the current state
+    pub fn after(&self, status: FirmwareStatus) -> Option<Self> {
+        if self.is_finished() {
+            return None;
+        }
+        Some(match status {
+            FirmwareStatus::Declined { reason } => Self::Declined { reason },
+            FirmwareStatus::Verified => Self::Verified,
+            FirmwareStatus::VerifyFailed => Self::Failed {
+                reason: "The downloaded image does not match the hash".to_string(),
+            },
+            FirmwareStatus::Applying => Self::Applying,
+            FirmwareStatus::Failed { reason } => Self::Failed { reason },
+        })
+    }
+}
+
+/// Devices a rollout is offered to
+#[derive(Debug, Clone)]
+pub enum RolloutTarget {
+    All,
+    Devices(HashSet<[char; 64]>),
+}
+
+/// The update of a set of devices to one firmware image
+///
+/// Device states only live in memory, a server restart forgets the rollout.
+pub struct Rollout {
+    pub id: u32,
+    pub firmware: Firmware,
+    target: RolloutTarget,
+    devices: HashMap<[char; 64], UpdateState>,
+    aborted: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RolloutStatus {
+    pub id: u32,
+    pub version: String,
+    pub aborted: bool,
+    pub devices: BTreeMap<String, UpdateState>,
+}
+
+impl Rollout {
+    pub fn new(id: u32, firmware: Firmware, target: RolloutTarget) -> Self {
+        let devices = match &target {
+            RolloutTarget::All => HashMap::new(),
+            RolloutTarget::Devices(devices) => devices
+                .iter()
+                .map(|id| (*id, UpdateState::Pending))
+                .collect(),
+        };
+        Self {
+            id,
+            firmware,
+            target,
+            devices,
+            aborted: false,
+        }
+    }
+
+    pub fn targets(&self, id: &[char; 64]) -> bool {
+        !self.aborted
+            && match &self.target {
+                RolloutTarget::All => true,
+                RolloutTarget::Devices(devices) => devices.contains(id),
+            }
+    }
+
+    pub fn state(&self, id: &[char; 64]) -> Option<&UpdateState> {
+        self.devices.get(id)
+    }
+
+    /// Record a new state, returns whether it is a different kind of state than before
+    pub fn set(&mut self, id: [char; 64], state: UpdateState) -> bool {
+        let previous = self.devices.insert(id, state.clone());
+        previous.map(|e| std::mem::discriminant(&e)) != Some(std::mem::discriminant(&state))
+    }
+
+    /// Stop the rollout and return the devices that were still updating
+    pub fn abort(&mut self) -> Vec<[char; 64]> {
+        self.aborted = true;
+        let mut unfinished = Vec::new();
+        for (id, state) in self.devices.iter_mut() {
+            if !state.is_finished() {
+                *state = UpdateState::Aborted;
+                unfinished.push(*id);
+            }
+        }
+        unfinished
+    }
+
+    pub fn is_active(&self) -> bool {
+        !self.aborted
+    }
+
+    pub fn status(&self) -> RolloutStatus {
+        RolloutStatus {
+            id: self.id,
+            version: self.firmware.version.clone(),
+            aborted: self.aborted,
+            devices: self
+                .devices
+                .iter()
+                .map(|(id, state)| (id.iter().collect(), state.clone()))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEVICE: [char; 64] = ['d'; 64];
+
+    fn firmware(data: &[u8]) -> Firmware {
+        Firmware {
+            version: "1.0.0".to_string(),
+            sha256: Sha256::digest(data).into(),
+            data: data.into(),
+        }
+    }
+
+    #[test]
+    fn sha256_hex_round_trip() {
+        let digest: [u8; 32] = Sha256::digest(b"firmware").into();
+        assert_eq!(parse_sha256(&hex(&digest)), Some(digest));
+        assert_eq!(parse_sha256(&hex(&digest).to_uppercase()), Some(digest));
+        assert_eq!(parse_sha256("abc"), None);
+        assert_eq!(parse_sha256(&"zz".repeat(32)), None);
+    }
+
+    #[test]
+    fn images_are_checked_and_persisted() {
+        let dir = std::env::temp_dir().join(format!("svf-firmware-{}", std::process::id()));
+        let image = vec![7u8; 3000];
+        let sha256 = Sha256::digest(&image).into();
+
+        assert_eq!(
+            Firmware::verify("1.0.0".to_string(), [0; 32], image.clone()).unwrap_err(),
+            FirmwareError::HashMismatch
+        );
+        assert_eq!(
+            Firmware::verify("../1.0.0".to_string(), sha256, image.clone()).unwrap_err(),
+            FirmwareError::InvalidVersion
+        );
+        let other = firmware(&[8u8; 10]);
+        let firmware = Firmware::verify("1.0.0".to_string(), sha256, image).unwrap();
+
+        // Storing the same image again is fine, replacing it is not
+        firmware.store(&dir).unwrap();
+        firmware.store(&dir).unwrap();
+        assert_eq!(other.store(&dir), Err(FirmwareError::VersionExists));
+
+        let mut repository = FirmwareRepository::load(None);
+        let info = repository.add(firmware.clone()).unwrap();
+        assert_eq!(info.size, 3000);
+        assert!(repository.add(firmware).is_ok());
+        assert_eq!(repository.add(other), Err(FirmwareError::VersionExists));
+
+        let reloaded = FirmwareRepository::load(Some(dir.clone()));
+        assert_eq!(reloaded.list(), vec![info]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn chunks_cover_the_image() {
+        let firmware = firmware(&[1; 2500]);
+        assert_eq!(firmware.chunk(0, 1024).unwrap().len(), 1024);
+        assert_eq!(firmware.chunk(2048, 1024).unwrap().len(), 452);
+        assert_eq!(firmware.chunk(2500, 1024), None);
+    }
+
+    #[test]
+    fn reports_move_devices_through_the_rollout() {
+        let mut rollout = Rollout::new(
+            1,
+            firmware(b"image"),
+            RolloutTarget::Devices(HashSet::from([DEVICE])),
+        );
+        assert!(rollout.targets(&DEVICE));
+        assert!(!rollout.targets(&['x'; 64]));
+        assert_eq!(rollout.state(&DEVICE), Some(&UpdateState::Pending));
+
+        assert!(rollout.set(DEVICE, UpdateState::Downloading { offset: 0, size: 5 }));
+        assert!(!rollout.set(DEVICE, UpdateState::Downloading { offset: 5, size: 5 }));
+        let state = rollout.state(&DEVICE).unwrap();
+        let failed = state.after(FirmwareStatus::VerifyFailed).unwrap();
+        assert!(matches!(failed, UpdateState::Failed { .. }));
+        assert_eq!(failed.after(FirmwareStatus::Applying), None);
+
+        assert_eq!(rollout.abort(), vec![DEVICE]);
+        assert!(!rollout.targets(&DEVICE));
+        assert_eq!(rollout.status().devices.len(), 1);
+    }
+}
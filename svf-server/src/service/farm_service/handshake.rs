@@ -0,0 +1,92 @@
+use std::ops::RangeInclusive;
+
+/// Protocol versions this server can speak, oldest first
+///
+/// * `1`: the original controller protocol with fixed 128 byte image frames
+/// * `2`: adds variable length image chunks
+/// * `3`: adds `Ping`/`Pong` heartbeats
+/// * `4`: adds sequenced `Command`s answered with `Ack`/`Nack`
+/// * `5`: adds checksummed image uploads addressed by upload id and byte offset
+/// * `6`: adds remote configuration, reboot and factory reset
+/// * `7`: adds firmware updates offered by the server and pulled in chunks by the device
+/// * `8`: adds `TimeSync` and sensor reports stamped with their capture time
+/// * `9`: adds `ReportReadings` with signed fixed-point temperature, humidity and calibrated values
+/// * `10`: adds `ReportTelemetry`, a list of typed records for any number and kind of sensors
+/// * `11`: adds `SetCoolerDuty` for devices with a variable speed cooler
+/// * `12`: adds `RunPump` to water for a given time instead of one configured pulse
+pub const PROTOCOL_VERSIONS: RangeInclusive<u16> = 1..=12;
+
+/// Pick the highest protocol version supported by both the device and the server
+pub fn negotiate_version(device_versions: RangeInclusive<u16>) -> Option<u16> {
+    let highest = (*device_versions.end()).min(*PROTOCOL_VERSIONS.end());
+    let lowest = (*device_versions.start()).max(*PROTOCOL_VERSIONS.start());
+    (lowest <= highest).then_some(highest)
+}
+
+/// Hardware features a device reports during the handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    pub const CAMERA: Self = Self(1 << 0);
+    pub const COOLER: Self = Self(1 << 1);
+    pub const WATER_PUMP: Self = Self(1 << 2);
+    /// The cooler speed can be set with `SetCoolerDuty`
+    pub const VARIABLE_COOLER: Self = Self(1 << 3);
+
+    /// Everything this server knows how to drive
+    pub const SUPPORTED: Self =
+        Self(Self::CAMERA.0 | Self::COOLER.0 | Self::WATER_PUMP.0 | Self::VARIABLE_COOLER.0);
+
+    pub fn from_bits(bits: u32) -> Self {
+        Self(bits)
+    }
+
+    pub fn bits(&self) -> u32 {
+        self.0
+    }
+
+    pub fn contains(&self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn intersection(&self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+}
+
+/// State agreed on by the `Hello`/`Welcome` exchange
+#[derive(Debug, Clone)]
+pub struct Handshake {
+    pub protocol_version: u16,
+    pub firmware_version: String,
+    pub capabilities: Capabilities,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_highest_common_version() {
+        assert_eq!(negotiate_version(1..=1), Some(1));
+        assert_eq!(negotiate_version(1..=2), Some(2));
+        assert_eq!(negotiate_version(2..=20), Some(*PROTOCOL_VERSIONS.end()));
+    }
+
+    #[test]
+    fn rejects_disjoint_versions() {
+        assert_eq!(negotiate_version(0..=0), None);
+        let newest = *PROTOCOL_VERSIONS.end();
+        assert_eq!(negotiate_version(newest + 1..=newest + 4), None);
+    }
+
+    #[test]
+    fn unknown_capabilities_are_masked() {
+        let device = Capabilities::from_bits(Capabilities::CAMERA.bits() | 1 << 31);
+        let agreed = device.intersection(Capabilities::SUPPORTED);
+        assert!(agreed.contains(Capabilities::CAMERA));
+        assert!(!agreed.contains(Capabilities::COOLER));
+        assert_eq!(agreed.bits(), Capabilities::CAMERA.bits());
+    }
+}
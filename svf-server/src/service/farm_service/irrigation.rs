@@ -0,0 +1,365 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, SystemTime},
+};
+
+use chrono::{DateTime, Days, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+
+use super::{command::DeviceCommand, settings::MAX_PUMP_PULSE};
+
+/// How often the farm service looks for irrigation pulses that are due
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+/// What devices run the pump for on a `WaterPulse` until they report their settings
+pub const DEFAULT_PUMP_PULSE: Duration = Duration::from_millis(1500);
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// A part of the day the farm is watered in with `pulses` runs of the pump spread
+/// evenly over it, the first one at `start`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct IrrigationWindow {
+    /// Local time of the farm, windows ending before they start run past midnight
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+    pub pulses: u16,
+    /// Milliseconds the pump runs for each pulse
+    pub pulse: u32,
+}
+
+impl IrrigationWindow {
+    fn length(&self) -> Duration {
+        let length = (self.end - self.start).to_std().unwrap_or_default();
+        if self.end > self.start {
+            length
+        } else {
+            DAY - (self.start - self.end).to_std().unwrap_or_default()
+        }
+    }
+
+    fn pulse(&self) -> Duration {
+        Duration::from_millis(self.pulse.into())
+    }
+}
+
+/// Irrigation schedule and pump budget of one farm, stored as JSON in `farms.irrigation`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IrrigationSettings {
+    /// IANA name of the zone the windows and the days of the budget are in
+    pub timezone: Tz,
+    pub windows: Vec<IrrigationWindow>,
+    /// Seconds the pump may run in a day of the farm, scheduled and moisture
+    /// triggered runs together, unlimited when unset
+    pub max_daily_runtime: Option<u32>,
+}
+
+impl Default for IrrigationSettings {
+    fn default() -> Self {
+        Self {
+            timezone: Tz::UTC,
+            windows: Vec::new(),
+            max_daily_runtime: None,
+        }
+    }
+}
+
+impl IrrigationSettings {
+    pub fn validate(&self) -> Result<(), String> {
+        for window in &self.windows {
+            if window.start == window.end {
+                return Err("An irrigation window can't start when it ends".to_string());
+            }
+            if window.pulses == 0 {
+                return Err("An irrigation window needs at least one pulse".to_string());
+            }
+            if window.pulse == 0 || window.pulse() > MAX_PUMP_PULSE {
+                return Err("A pump pulse can last at most 60 seconds".to_string());
+            }
+            if window.pulse() > window.length() / u32::from(window.pulses) {
+                return Err(format!(
+                    "{} pulses don't fit between {} and {}",
+                    window.pulses, window.start, window.end
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The instant a local time of `timezone` happens at, times skipped by a daylight
+/// saving change are moved an hour later
+fn resolve(timezone: Tz, local: NaiveDateTime) -> Option<DateTime<Tz>> {
+    timezone.from_local_datetime(&local).earliest().or_else(|| {
+        timezone
+            .from_local_datetime(&(local + chrono::Duration::hours(1)))
+            .earliest()
+    })
+}
+
+/// Pump runs of one farm, kept across reconnects of its device
+#[derive(Debug, Clone)]
+pub struct Irrigation {
+    settings: IrrigationSettings,
+    /// What the device runs the pump for on a `WaterPulse`
+    pub pump_pulse: Duration,
+    /// Pulses sent of each window that started, by window and start
+    sent: HashMap<(usize, SystemTime), u16>,
+    /// Day of the farm the runtime was counted on, `None` before it was restored
+    day: Option<NaiveDate>,
+    used: Duration,
+}
+
+impl Default for Irrigation {
+    fn default() -> Self {
+        Self {
+            settings: IrrigationSettings::default(),
+            pump_pulse: DEFAULT_PUMP_PULSE,
+            sent: HashMap::new(),
+            day: None,
+            used: Duration::ZERO,
+        }
+    }
+}
+
+impl Irrigation {
+    pub fn configure(&mut self, settings: IrrigationSettings) {
+        if settings.windows != self.settings.windows {
+            self.sent.clear();
+        }
+        self.settings = settings;
+    }
+
+    pub fn timezone(&self) -> Tz {
+        self.settings.timezone
+    }
+
+    fn today(&self, now: SystemTime) -> NaiveDate {
+        DateTime::<Utc>::from(now)
+            .with_timezone(&self.settings.timezone)
+            .date_naive()
+    }
+
+    /// When the current day of the farm started, `None` once the runtime of that day
+    /// is known
+    pub fn unknown_day(&self, now: SystemTime) -> Option<SystemTime> {
+        let today = self.today(now);
+        if self.day == Some(today) {
+            return None;
+        }
+        resolve(self.settings.timezone, today.and_time(NaiveTime::MIN))
+            .map(|start| start.with_timezone(&Utc).into())
+    }
+
+    /// Start counting the day of `now` from the runtime already used in it
+    pub fn restore(&mut self, used: Duration, now: SystemTime) {
+        self.day = Some(self.today(now));
+        self.used = used;
+    }
+
+    /// How long `command` runs the pump for
+    pub fn runtime(&self, command: &DeviceCommand) -> Option<Duration> {
+        match command {
+            DeviceCommand::WaterPulse => Some(self.pump_pulse),
+            DeviceCommand::Water { millis } => Some(Duration::from_millis((*millis).into())),
+            _ => None,
+        }
+    }
+
+    /// Count `runtime` against the budget of the day, `false` when it would go over it
+    pub fn reserve(&mut self, runtime: Duration, now: SystemTime) -> bool {
+        let today = self.today(now);
+        if self.day != Some(today) {
+            self.day = Some(today);
+            self.used = Duration::ZERO;
+        }
+        let limit = self
+            .settings
+            .max_daily_runtime
+            .map(|secs| Duration::from_secs(secs.into()));
+        if limit.is_some_and(|limit| self.used + runtime > limit) {
+            return false;
+        }
+        self.used += runtime;
+        true
+    }
+
+    /// Give back the runtime of a command the device never ran
+    pub fn refund(&mut self, runtime: Duration, now: SystemTime) {
+        if self.day == Some(self.today(now)) {
+            self.used = self.used.saturating_sub(runtime);
+        }
+    }
+
+    /// Pump runs due at `now`, at most one for each window, pulses missed while the
+    /// device was away are skipped
+    pub fn due(&mut self, now: SystemTime) -> Vec<Duration> {
+        let timezone = self.settings.timezone;
+        let today = self.today(now);
+        let mut runs = Vec::new();
+        for (index, window) in self.settings.windows.iter().enumerate() {
+            let length = window.length();
+            // A window that started yesterday may still be running past midnight
+            for day in [today.checked_sub_days(Days::new(1)), Some(today)] {
+                let Some(start) = day.and_then(|day| resolve(timezone, day.and_time(window.start)))
+                else {
+                    continue;
+                };
+                let start = SystemTime::from(start.with_timezone(&Utc));
+                let Ok(elapsed) = now.duration_since(start) else {
+                    continue;
+                };
+                if elapsed >= length {
+                    continue;
+                }
+                let pulses = u32::from(window.pulses);
+                let due =
+                    ((elapsed.as_secs_f64() / length.as_secs_f64() * f64::from(pulses)) as u32 + 1)
+                        .min(pulses) as u16;
+                let sent = self.sent.entry((index, start)).or_default();
+                if due > *sent {
+                    *sent = due;
+                    runs.push(window.pulse());
+                }
+            }
+        }
+        let windows = &self.settings.windows;
+        self.sent.retain(|(index, start), _| {
+            windows.get(*index).is_some_and(|window| {
+                now.duration_since(*start)
+                    .map_or(true, |elapsed| elapsed < window.length())
+            })
+        });
+        runs
+    }
+
+    /// Commands running the pump for `runtime`, whole `WaterPulse`s for devices that
+    /// can't be told how long to water for
+    pub fn commands(&self, runtime: Duration, timed: bool) -> Vec<DeviceCommand> {
+        if timed {
+            return vec![DeviceCommand::Water {
+                millis: runtime.as_millis() as u32,
+            }];
+        }
+        let pulse = self.pump_pulse.as_millis().max(1);
+        let pulses = runtime.as_millis().div_ceil(pulse);
+        vec![DeviceCommand::WaterPulse; pulses as usize]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(timezone: Tz, date: (i32, u32, u32), time: (u32, u32)) -> SystemTime {
+        let local = NaiveDate::from_ymd_opt(date.0, date.1, date.2)
+            .unwrap()
+            .and_hms_opt(time.0, time.1, 0)
+            .unwrap();
+        resolve(timezone, local).unwrap().with_timezone(&Utc).into()
+    }
+
+    fn settings(json: &str) -> IrrigationSettings {
+        let settings: IrrigationSettings = serde_json::from_str(json).unwrap();
+        settings.validate().unwrap();
+        settings
+    }
+
+    #[test]
+    fn pulses_are_spread_over_the_window_in_local_time() {
+        let timezone = Tz::Europe__Brussels;
+        let mut irrigation = Irrigation::default();
+        irrigation.configure(settings(
+            r#"{"timezone":"Europe/Brussels","windows":[
+                {"start":"06:00:00","end":"07:00:00","pulses":3,"pulse":5000}
+            ]}"#,
+        ));
+        let at = |time| at(timezone, (2024, 7, 1), time);
+        assert!(irrigation.due(at((5, 59))).is_empty());
+        assert_eq!(irrigation.due(at((6, 0))), [Duration::from_secs(5)]);
+        assert!(irrigation.due(at((6, 10))).is_empty());
+        assert_eq!(irrigation.due(at((6, 20))), [Duration::from_secs(5)]);
+        // The device was away for the third pulse
+        assert!(irrigation.due(at((7, 0))).is_empty());
+        assert!(irrigation.due(at((7, 30))).is_empty());
+    }
+
+    #[test]
+    fn windows_run_past_midnight_and_over_daylight_saving_changes() {
+        let timezone = Tz::Europe__Brussels;
+        let mut irrigation = Irrigation::default();
+        irrigation.configure(settings(
+            r#"{"timezone":"Europe/Brussels","windows":[
+                {"start":"23:30:00","end":"00:30:00","pulses":2,"pulse":1000},
+                {"start":"02:30:00","end":"03:30:00","pulses":1,"pulse":1000}
+            ]}"#,
+        ));
+        assert_eq!(
+            irrigation.due(at(timezone, (2024, 3, 30), (23, 30))).len(),
+            1
+        );
+        assert_eq!(irrigation.due(at(timezone, (2024, 3, 31), (0, 0))).len(), 1);
+        // 02:30 doesn't exist on the day clocks go forward, it runs at 03:30 instead
+        assert!(irrigation
+            .due(at(timezone, (2024, 3, 31), (1, 59)))
+            .is_empty());
+        assert_eq!(
+            irrigation.due(at(timezone, (2024, 3, 31), (3, 30))).len(),
+            1
+        );
+    }
+
+    #[test]
+    fn budget_resets_every_local_day() {
+        let timezone = Tz::America__New_York;
+        let mut irrigation = Irrigation::default();
+        irrigation.configure(settings(
+            r#"{"timezone":"America/New_York","max_daily_runtime":10}"#,
+        ));
+        let evening = at(timezone, (2024, 7, 1), (20, 0));
+        assert!(irrigation.unknown_day(evening).is_some());
+        irrigation.restore(Duration::from_secs(6), evening);
+        assert_eq!(irrigation.unknown_day(evening), None);
+        assert!(irrigation.reserve(Duration::from_secs(3), evening));
+        assert!(!irrigation.reserve(Duration::from_secs(3), evening));
+        irrigation.refund(Duration::from_secs(3), evening);
+        assert!(irrigation.reserve(Duration::from_secs(4), evening));
+        // Already the next day in UTC, still the same one in New York
+        assert!(!irrigation.reserve(Duration::from_secs(1), at(timezone, (2024, 7, 1), (23, 0))));
+        assert!(irrigation.reserve(Duration::from_secs(10), at(timezone, (2024, 7, 2), (0, 0))));
+    }
+
+    #[test]
+    fn old_devices_water_with_whole_pulses() {
+        let irrigation = Irrigation::default();
+        assert_eq!(
+            irrigation.commands(Duration::from_secs(4), false),
+            vec![DeviceCommand::WaterPulse; 3]
+        );
+        assert_eq!(
+            irrigation.commands(Duration::from_secs(4), true),
+            [DeviceCommand::Water { millis: 4000 }]
+        );
+        assert_eq!(
+            irrigation.runtime(&DeviceCommand::WaterPulse),
+            Some(DEFAULT_PUMP_PULSE)
+        );
+    }
+
+    #[test]
+    fn invalid_schedules_are_rejected() {
+        for json in [
+            r#"{"windows":[{"start":"06:00:00","end":"06:00:00","pulses":1,"pulse":1000}]}"#,
+            r#"{"windows":[{"start":"06:00:00","end":"07:00:00","pulses":0,"pulse":1000}]}"#,
+            r#"{"windows":[{"start":"06:00:00","end":"07:00:00","pulses":1,"pulse":60001}]}"#,
+            r#"{"windows":[{"start":"06:00:00","end":"06:01:00","pulses":3,"pulse":30000}]}"#,
+        ] {
+            let settings: IrrigationSettings = serde_json::from_str(json).unwrap();
+            assert!(settings.validate().is_err(), "{json}");
+        }
+        assert!(
+            serde_json::from_str::<IrrigationSettings>(r#"{"timezone":"Mars/Olympus"}"#).is_err()
+        );
+    }
+}
@@ -0,0 +1,459 @@
+use std::net::SocketAddr;
+
+use axum_server::tls_rustls::RustlsConfig;
+use tokio::{net::TcpListener, sync::mpsc::Sender, time::timeout};
+use tokio_rustls::TlsAcceptor;
+use tokio_util::codec::Framed;
+
+use crate::service::db_service::DBServiceHandle;
+
+use super::{
+    client::Client,
+    codec::{PacketCodec, Transport},
+    session::SessionConfig,
+    upload::UploadStore,
+    ClientReceiverCommand,
+};
+
+/// Everything a device session needs to join the farm service, whichever
+/// transport the device connected over
+#[derive(Clone)]
+pub struct DeviceGateway {
+    sender: Sender<ClientReceiverCommand>,
+    db: DBServiceHandle,
+    session: SessionConfig,
+    uploads: UploadStore,
+}
+
+impl DeviceGateway {
+    pub(super) fn new(
+        sender: Sender<ClientReceiverCommand>,
+        db: DBServiceHandle,
+        session: SessionConfig,
+    ) -> Self {
+        Self {
+            uploads: UploadStore::new(&session.upload),
+            sender,
+            db,
+            session,
+        }
+    }
+
+    /// Run one device session until the device goes away
+    pub(super) async fn serve(self, transport: impl Transport, addr: SocketAddr) {
+        Client::new(
+            self.sender,
+            self.db,
+            transport,
+            addr,
+            self.session,
+            self.uploads,
+        )
+        .run()
+        .await
+    }
+}
+
+/// Accept device connections forever, wrapping each one in TLS when `tls` is set
+///
+/// `tls` is the config the web server serves with and it is read again for every
+/// connection, so a certificate reloaded into it is picked up by devices as well.
+/// Devices that don't finish the TLS handshake within the session's
+/// `handshake_timeout` are dropped.
+pub async fn serve_devices(
+    listener: TcpListener,
+    tls: Option<RustlsConfig>,
+    gateway: DeviceGateway,
+) {
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(error) => {
+                println!("Failed to connect with a device {error}");
+                continue;
+            }
+        };
+        let gateway = gateway.clone();
+        let tls = tls.clone();
+        tokio::spawn(async move {
+            println!("Incoming connection {addr}");
+            match tls {
+                Some(config) => {
+                    let accept = TlsAcceptor::from(config.get_inner()).accept(stream);
+                    match timeout(gateway.session.handshake_timeout, accept).await {
+                        Ok(Ok(stream)) => {
+                            gateway
+                                .serve(Framed::new(stream, PacketCodec::new()), addr)
+                                .await
+                        }
+                        Ok(Err(error)) => println!("TLS handshake with {addr} failed: {error}"),
+                        Err(..) => println!("TLS handshake with {addr} timed out"),
+                    }
+                }
+                None => {
+                    gateway
+                        .serve(Framed::new(stream, PacketCodec::new()), addr)
+                        .await
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+    use futures::{SinkExt, StreamExt};
+    use tokio::{
+        io::{AsyncRead, AsyncReadExt, AsyncWrite},
+        net::TcpStream,
+        sync::mpsc::channel,
+    };
+    use tokio_rustls::{
+        rustls::{pki_types::ServerName, ClientConfig, RootCertStore},
+        TlsConnector,
+    };
+    use tokio_util::codec::Framed;
+
+    use crate::{
+        service::{
+            db_service::{DBServiceError, DBServiceRequest, DBServiceResponse},
+            farm_service::{
+                auth,
+                client::ClientPacketId,
+                codec::{Frame, PacketCodec},
+                handshake::PROTOCOL_VERSIONS,
+                packet::PacketField,
+                server::ServerPacketId,
+                upload::ImageChecksum,
+            },
+            ServiceHandle,
+        },
+        utils::{buffer_reader::BufferReader, buffer_writer::BufferWriter},
+    };
+
+    use super::*;
+
+    const DEVICE_ID: [char; 64] = ['i'; 64];
+    const DEVICE_SECRET: [char; 64] = ['s'; 64];
+
+    async fn start(tls: Option<RustlsConfig>, session: SessionConfig) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (sender, receiver) = channel(16);
+        let (db_sender, mut db_receiver) = channel::<crate::service::ServiceRequest<_, _>>(16);
+        // Only knows the secret of `DEVICE_ID`
+        tokio::spawn(async move {
+            while let Some(request) = db_receiver.recv().await {
+                let response = match request.data {
+                    DBServiceRequest::GetDeviceSecret { id } if id == DEVICE_ID => {
+                        Ok(DBServiceResponse::DeviceSecret(DEVICE_SECRET))
+                    }
+                    _ => Err(DBServiceError::UnregisterdDevice),
+                };
+                request.result_sender.send(response).await.ok();
+            }
+        });
+        tokio::spawn(async move {
+            // Keep the farm service end open for the lifetime of the listener
+            let _receiver = receiver;
+            let gateway = DeviceGateway::new(sender, ServiceHandle::new(db_sender), session);
+            serve_devices(listener, tls, gateway).await;
+        });
+        addr
+    }
+
+    async fn next_frame<S: AsyncRead + AsyncWrite + Unpin>(
+        framed: &mut Framed<S, PacketCodec>,
+    ) -> Frame {
+        framed.next().await.unwrap().unwrap()
+    }
+
+    async fn send<S: AsyncRead + AsyncWrite + Unpin>(
+        framed: &mut Framed<S, PacketCodec>,
+        id: ClientPacketId,
+        write: impl FnOnce(&mut BufferWriter),
+    ) {
+        let mut payload = Vec::new();
+        write(&mut BufferWriter::new(&mut payload));
+        framed.send(Frame::new(id as u32, payload)).await.unwrap();
+    }
+
+    /// Connect, handshake and authenticate as `DEVICE_ID`, returning every frame
+    /// received after `Authenticated` and the `TimeSync` that follows it
+    async fn connect_authenticated(addr: SocketAddr) -> Framed<TcpStream, PacketCodec> {
+        let mut framed = Framed::new(TcpStream::connect(addr).await.unwrap(), PacketCodec::new());
+        hello(&mut framed).await.expect("Welcome");
+        send(&mut framed, ClientPacketId::ReportId, |buffer| {
+            buffer.write_bytes(&DEVICE_ID.map(|c| c as u8));
+        })
+        .await;
+        let challenge = next_frame(&mut framed).await;
+        assert_eq!(
+            challenge.header().id(),
+            ServerPacketId::AuthChallenge as u32
+        );
+        let nonce = BufferReader::new(challenge.payload())
+            .const_read_bytes::<32>()
+            .unwrap();
+        let response = auth::sign(&DEVICE_SECRET, &nonce, &DEVICE_ID);
+        send(&mut framed, ClientPacketId::AuthResponse, |buffer| {
+            buffer.write_bytes(&response);
+        })
+        .await;
+        let authenticated = next_frame(&mut framed).await;
+        assert_eq!(
+            authenticated.header().id(),
+            ServerPacketId::Authenticated as u32
+        );
+        let time_sync = next_frame(&mut framed).await;
+        assert_eq!(time_sync.header().id(), ServerPacketId::TimeSync as u32);
+        framed
+    }
+
+    async fn hello<S: AsyncRead + AsyncWrite + Unpin>(
+        framed: &mut Framed<S, PacketCodec>,
+    ) -> Option<Frame> {
+        let mut payload = Vec::new();
+        BufferWriter::new(&mut payload)
+            .write_u16(*PROTOCOL_VERSIONS.start())
+            .write_u16(*PROTOCOL_VERSIONS.end())
+            .write_string("test".to_string())
+            .write_u32(0);
+        framed
+            .send(Frame::new(ClientPacketId::Hello as u32, payload))
+            .await
+            .ok()?;
+        framed.next().await?.ok()
+    }
+
+    fn welcome_version(frame: &Frame) -> u16 {
+        assert_eq!(frame.header().id(), ServerPacketId::Welcome as u32);
+        BufferReader::new(frame.payload()).read_u16().unwrap()
+    }
+
+    fn sequence_frame(id: u32, sequence: u32) -> Frame {
+        let mut payload = Vec::new();
+        BufferWriter::new(&mut payload).write_u32(sequence);
+        Frame::new(id, payload)
+    }
+
+    fn sequence(frame: &Frame) -> u32 {
+        BufferReader::new(frame.payload()).read_u32().unwrap()
+    }
+
+    /// Returns the certificate and key PEM plus a root store trusting the certificate
+    fn self_signed() -> (Vec<u8>, Vec<u8>, RootCertStore) {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let mut roots = RootCertStore::empty();
+        roots.add(certified.cert.der().clone()).unwrap();
+        (
+            certified.cert.pem().into_bytes(),
+            certified.key_pair.serialize_pem().into_bytes(),
+            roots,
+        )
+    }
+
+    #[tokio::test]
+    async fn plain_tcp_handshake() {
+        let addr = start(None, SessionConfig::default()).await;
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let welcome = hello(&mut Framed::new(stream, PacketCodec::new()))
+            .await
+            .expect("Welcome over plain TCP");
+        assert_eq!(welcome_version(&welcome), *PROTOCOL_VERSIONS.end());
+    }
+
+    #[tokio::test]
+    async fn tls_handshake_with_self_signed_cert() {
+        let (cert, key, roots) = self_signed();
+        let config = RustlsConfig::from_pem(cert, key).await.unwrap();
+        let addr = start(Some(config), SessionConfig::default()).await;
+
+        let connector = TlsConnector::from(Arc::new(
+            ClientConfig::builder()
+                .with_root_certificates(roots)
+                .with_no_client_auth(),
+        ));
+        let stream = connector
+            .connect(
+                ServerName::try_from("localhost").unwrap(),
+                TcpStream::connect(addr).await.unwrap(),
+            )
+            .await
+            .unwrap();
+        let welcome = hello(&mut Framed::new(stream, PacketCodec::new()))
+            .await
+            .expect("Welcome over TLS");
+        assert_eq!(welcome_version(&welcome), *PROTOCOL_VERSIONS.end());
+    }
+
+    #[tokio::test]
+    async fn plain_client_is_refused_by_tls_listener() {
+        let (cert, key, _) = self_signed();
+        let config = RustlsConfig::from_pem(cert, key).await.unwrap();
+        let addr = start(Some(config), SessionConfig::default()).await;
+        let stream = TcpStream::connect(addr).await.unwrap();
+        assert!(hello(&mut Framed::new(stream, PacketCodec::new()))
+            .await
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn stalled_tls_handshake_is_dropped() {
+        let (cert, key, _) = self_signed();
+        let config = RustlsConfig::from_pem(cert, key).await.unwrap();
+        let addr = start(
+            Some(config),
+            SessionConfig {
+                handshake_timeout: Duration::from_millis(100),
+                ..SessionConfig::default()
+            },
+        )
+        .await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let read = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut [0; 16])).await;
+        assert!(matches!(read, Ok(Ok(0))));
+    }
+
+    #[tokio::test]
+    async fn silent_device_is_disconnected() {
+        let addr = start(
+            None,
+            SessionConfig {
+                heartbeat_interval: Duration::from_secs(60),
+                idle_timeout: Duration::from_millis(100),
+                ..Default::default()
+            },
+        )
+        .await;
+        let mut framed = Framed::new(TcpStream::connect(addr).await.unwrap(), PacketCodec::new());
+        hello(&mut framed).await.expect("Welcome");
+        let disconnect = framed.next().await.unwrap().unwrap();
+        assert_eq!(disconnect.header().id(), ServerPacketId::Disconnect as u32);
+        assert!(framed.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn heartbeat_pings_and_answers_pings() {
+        let addr = start(
+            None,
+            SessionConfig {
+                heartbeat_interval: Duration::from_millis(50),
+                idle_timeout: Duration::from_secs(60),
+                ..Default::default()
+            },
+        )
+        .await;
+        let mut framed = Framed::new(TcpStream::connect(addr).await.unwrap(), PacketCodec::new());
+        hello(&mut framed).await.expect("Welcome");
+
+        let ping = framed.next().await.unwrap().unwrap();
+        assert_eq!(ping.header().id(), ServerPacketId::Ping as u32);
+        assert_eq!(sequence(&ping), 1);
+
+        framed
+            .send(sequence_frame(ClientPacketId::Ping as u32, 7))
+            .await
+            .unwrap();
+        loop {
+            let frame = framed.next().await.unwrap().unwrap();
+            if frame.header().id() == ServerPacketId::Pong as u32 {
+                assert_eq!(sequence(&frame), 7);
+                break;
+            }
+            assert_eq!(frame.header().id(), ServerPacketId::Ping as u32);
+        }
+    }
+
+    #[tokio::test]
+    async fn upload_resumes_after_reconnect() {
+        let addr = start(None, SessionConfig::default()).await;
+        let image = (0..600u32).map(|i| i as u8).collect::<Vec<_>>();
+        let checksum = ImageChecksum::of_sha256(&image);
+        let upload_chunk = |offset: usize, chunk: &[u8]| {
+            let chunk = chunk.to_vec();
+            move |buffer: &mut BufferWriter| {
+                buffer
+                    .write_u32(9)
+                    .write_u32(offset as u32)
+                    .write_u32(chunk.len() as u32)
+                    .write_bytes(&chunk);
+            }
+        };
+
+        let mut framed = connect_authenticated(addr).await;
+        send(&mut framed, ClientPacketId::ReportSensors, |buffer| {
+            buffer.write_u16(1).write_u16(2).write_u16(3).write_u64(600);
+        })
+        .await;
+        send(&mut framed, ClientPacketId::UploadStart, |buffer| {
+            buffer.write_u32(9).write_u32(600).write_u16(256);
+            checksum.write(buffer);
+        })
+        .await;
+        let accepted = next_frame(&mut framed).await;
+        assert_eq!(
+            accepted.header().id(),
+            ServerPacketId::UploadAccepted as u32
+        );
+        send(
+            &mut framed,
+            ClientPacketId::UploadChunk,
+            upload_chunk(0, &image[..256]),
+        )
+        .await;
+        // Round trip so the chunk is processed before the connection drops
+        framed
+            .send(sequence_frame(ClientPacketId::Ping as u32, 1))
+            .await
+            .unwrap();
+        assert_eq!(
+            next_frame(&mut framed).await.header().id(),
+            ServerPacketId::Pong as u32
+        );
+        drop(framed);
+
+        let mut framed = connect_authenticated(addr).await;
+        let resume = next_frame(&mut framed).await;
+        assert_eq!(resume.header().id(), ServerPacketId::UploadResume as u32);
+        let mut reader = BufferReader::new(resume.payload());
+        assert_eq!(reader.read_u32(), Some(9));
+        assert_eq!(reader.read_u32(), Some(256));
+
+        for (offset, chunk) in [(256, &image[256..512]), (512, &image[512..])] {
+            send(
+                &mut framed,
+                ClientPacketId::UploadChunk,
+                upload_chunk(offset, chunk),
+            )
+            .await;
+        }
+        let complete = next_frame(&mut framed).await;
+        assert_eq!(
+            complete.header().id(),
+            ServerPacketId::UploadComplete as u32
+        );
+    }
+
+    #[tokio::test]
+    async fn oversized_image_report_is_disconnected() {
+        let addr = start(None, SessionConfig::default()).await;
+        let mut framed = connect_authenticated(addr).await;
+        let too_large = SessionConfig::default().upload.max_image_size as u64 + 1;
+        send(&mut framed, ClientPacketId::ReportSensors, |buffer| {
+            buffer
+                .write_u16(1)
+                .write_u16(2)
+                .write_u16(3)
+                .write_u64(too_large);
+        })
+        .await;
+        assert_eq!(
+            next_frame(&mut framed).await.header().id(),
+            ServerPacketId::Disconnect as u32
+        );
+        assert!(framed.next().await.is_none());
+    }
+}
@@ -0,0 +1,651 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant, SystemTime},
+};
+
+use rumqttc::{AsyncClient, Event, EventLoop, MqttOptions, Packet, Publish, QoS};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::{
+    sync::mpsc::{channel, Receiver, Sender},
+    time::{interval, sleep},
+};
+
+use crate::{
+    service::db_service::{DBServiceHandle, DBServiceRequest},
+    utils::buffer_reader::BufferReader,
+};
+
+use super::{
+    command::{CommandOutcome, DeviceCommand},
+    handshake::Capabilities,
+    sensors::{CentiCelsius, CentiPercent, Readings},
+    telemetry::SensorRecord,
+    ClientReceiverCommand, ServerPacket,
+};
+
+/// Where the device id goes in the topic templates
+const ID_PLACEHOLDER: &str = "{id}";
+
+/// How to reach the broker and which topics carry device traffic
+#[derive(Debug, Clone)]
+pub struct MqttConfig {
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    pub credentials: Option<(String, String)>,
+    /// Topic devices publish readings to, `{id}` stands for the device id
+    pub telemetry_topic: String,
+    /// Topic commands for a device are published to, `{id}` stands for the device id
+    pub command_topic: String,
+    /// Hardware driven by MQTT devices, they have no handshake to report it
+    pub capabilities: Capabilities,
+    /// A device that has not published for this long is reported as disconnected
+    pub idle_timeout: Duration,
+}
+
+impl MqttConfig {
+    pub fn new(host: String, port: u16) -> Self {
+        Self {
+            host,
+            port,
+            client_id: "svf-server".to_string(),
+            credentials: None,
+            telemetry_topic: "svf/{id}/sensors".to_string(),
+            command_topic: "svf/{id}/commands".to_string(),
+            capabilities: Capabilities::from_bits(
+                Capabilities::COOLER.bits() | Capabilities::WATER_PUMP.bits(),
+            ),
+            idle_timeout: Duration::from_secs(300),
+        }
+    }
+}
+
+/// The subscription matching the telemetry of every device
+fn topic_filter(template: &str) -> String {
+    template.replace(ID_PLACEHOLDER, "+")
+}
+
+fn device_topic(template: &str, id: &[char; 64]) -> String {
+    template.replace(ID_PLACEHOLDER, &id.iter().collect::<String>())
+}
+
+/// Pull the device id out of a topic matching `template`
+fn topic_device_id(template: &str, topic: &str) -> Option<[char; 64]> {
+    let mut template = template.split('/');
+    let mut topic = topic.split('/');
+    let mut id = None;
+    loop {
+        match (template.next(), topic.next()) {
+            (Some(ID_PLACEHOLDER), Some(level)) => {
+                id = Some(level.chars().collect::<Vec<_>>().try_into().ok()?)
+            }
+            (Some(expected), Some(level)) if expected == level => {}
+            (None, None) => return id,
+            _ => return None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+enum Telemetry {
+    /// The records of `ReportTelemetry`
+    Records { records: Vec<SensorRecord> },
+    /// Temperature in degrees and humidity in percent, both may have a fraction
+    Fixed {
+        soil_moisture: u16,
+        air_temperature: f32,
+        humidity: Option<f32>,
+        light_sensor: u16,
+    },
+}
+
+/// Decode a JSON object, or the three little endian `u16`s of `ReportSensors`
+fn parse_telemetry(payload: &[u8]) -> Option<Vec<SensorRecord>> {
+    if payload.trim_ascii_start().starts_with(b"{") {
+        return match serde_json::from_slice(payload).ok()? {
+            Telemetry::Records { records } => Some(records),
+            Telemetry::Fixed {
+                soil_moisture,
+                air_temperature,
+                humidity,
+                light_sensor,
+            } => Some(
+                Readings {
+                    air_temperature: CentiCelsius::from_degrees_f32(air_temperature),
+                    humidity: humidity.map(CentiPercent::from_percent_f32),
+                    ..Readings::legacy(soil_moisture, 0, light_sensor)
+                }
+                .records(),
+            ),
+        };
+    }
+    if payload.len() != 6 {
+        return None;
+    }
+    let mut buffer = BufferReader::new(payload);
+    Some(Readings::legacy(buffer.read_u16()?, buffer.read_u16()?, buffer.read_u16()?).records())
+}
+
+fn command_json(command: &DeviceCommand) -> serde_json::Value {
+    match command {
+        DeviceCommand::Cooler { status } => json!({ "command": "cooler", "status": status }),
+        DeviceCommand::WaterPulse => json!({ "command": "water_pulse" }),
+        DeviceCommand::Configure(settings) => {
+            json!({ "command": "configure", "settings": settings })
+        }
+        DeviceCommand::Reboot => json!({ "command": "reboot" }),
+        DeviceCommand::FactoryReset => json!({ "command": "factory_reset" }),
+        DeviceCommand::CoolerDuty { duty } => json!({ "command": "cooler_duty", "duty": duty }),
+        DeviceCommand::Water { millis } => json!({ "command": "water", "millis": millis }),
+    }
+}
+
+/// The JSON published for a command packet, and the sequence number it completes
+///
+/// Packets that only make sense on a device connection are not forwarded.
+fn command_payload(packet: &ServerPacket) -> Option<(Option<u32>, Vec<u8>)> {
+    let (sequence, mut value) = match packet {
+        ServerPacket::Command { sequence, command } => (Some(*sequence), command_json(command)),
+        ServerPacket::Configure { sequence, settings } => (
+            Some(*sequence),
+            command_json(&DeviceCommand::Configure(settings.clone())),
+        ),
+        ServerPacket::Reboot { sequence } => {
+            (Some(*sequence), command_json(&DeviceCommand::Reboot))
+        }
+        ServerPacket::FactoryReset { sequence } => {
+            (Some(*sequence), command_json(&DeviceCommand::FactoryReset))
+        }
+        ServerPacket::SetCoolerDuty { sequence, duty } => (
+            Some(*sequence),
+            command_json(&DeviceCommand::CoolerDuty { duty: *duty }),
+        ),
+        ServerPacket::RunPump { sequence, millis } => (
+            Some(*sequence),
+            command_json(&DeviceCommand::Water { millis: *millis }),
+        ),
+        ServerPacket::UpdateCooler { status } => (
+            None,
+            command_json(&DeviceCommand::Cooler { status: *status }),
+        ),
+        ServerPacket::WaterPulse => (None, command_json(&DeviceCommand::WaterPulse)),
+        _ => return None,
+    };
+    if let Some(sequence) = sequence {
+        value["sequence"] = json!(sequence);
+    }
+    Some((sequence, value.to_string().into_bytes()))
+}
+
+struct MqttDevice {
+    sender: Sender<ServerPacket>,
+    last_seen: Instant,
+}
+
+/// Maps MQTT telemetry onto [`ClientReceiverCommand`]s and publishes commands back
+///
+/// MQTT devices have no session of their own. A device joins the farm service with
+/// its first reading and leaves once it has been quiet for `idle_timeout`. The
+/// broker is trusted to authenticate devices, the bridge only checks that the id
+/// is registered. Devices cannot acknowledge commands, so every command completes
+/// as [`CommandOutcome::Unconfirmed`] once published.
+pub struct MqttBridge {
+    config: MqttConfig,
+    client: AsyncClient,
+    client_sender: Sender<ClientReceiverCommand>,
+    db: DBServiceHandle,
+    devices: HashMap<[char; 64], MqttDevice>,
+}
+
+impl MqttBridge {
+    pub fn new(
+        config: MqttConfig,
+        client_sender: Sender<ClientReceiverCommand>,
+        db: DBServiceHandle,
+    ) -> (Self, EventLoop) {
+        let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+        options.set_keep_alive(Duration::from_secs(30));
+        if let Some((username, password)) = &config.credentials {
+            options.set_credentials(username, password);
+        }
+        let (client, event_loop) = AsyncClient::new(options, 64);
+        let bridge = Self {
+            config,
+            client,
+            client_sender,
+            db,
+            devices: HashMap::new(),
+        };
+        (bridge, event_loop)
+    }
+
+    pub async fn run(mut self, mut event_loop: EventLoop) {
+        let mut sweep = interval(self.config.idle_timeout / 4);
+        loop {
+            tokio::select! {
+                event = event_loop.poll() => match event {
+                    Ok(Event::Incoming(Packet::ConnAck(..))) => {
+                        println!("Connected to MQTT broker {}:{}", self.config.host, self.config.port);
+                        let filter = topic_filter(&self.config.telemetry_topic);
+                        if let Err(err) = self.client.try_subscribe(filter, QoS::AtLeastOnce) {
+                            println!("Cannot subscribe to device telemetry: {err}");
+                        }
+                    }
+                    Ok(Event::Incoming(Packet::Publish(publish))) => self.handle_publish(publish).await,
+                    Ok(..) => {}
+                    Err(err) => {
+                        println!("MQTT connection failed: {err}");
+                        sleep(Duration::from_secs(3)).await;
+                    }
+                },
+                _ = sweep.tick() => self.disconnect_idle().await,
+            }
+        }
+    }
+
+    async fn handle_publish(&mut self, publish: Publish) {
+        let Some(id) = topic_device_id(&self.config.telemetry_topic, &publish.topic) else {
+            return;
+        };
+        let Some(records) = parse_telemetry(&publish.payload) else {
+            println!("Invalid telemetry on {}", publish.topic);
+            return;
+        };
+        if !self.join(id).await {
+            return;
+        }
+        self.client_sender
+            .send(ClientReceiverCommand::ReportSensors {
+                id,
+                records,
+                captured_at: SystemTime::now(),
+                image: Vec::new(),
+            })
+            .await
+            .ok();
+    }
+
+    /// Register a device with the farm service on its first reading
+    async fn join(&mut self, id: [char; 64]) -> bool {
+        if let Some(device) = self.devices.get_mut(&id) {
+            device.last_seen = Instant::now();
+            return true;
+        }
+        if self
+            .db
+            .request(DBServiceRequest::GetDeviceSecret { id })
+            .await
+            .is_err()
+        {
+            println!(
+                "Ignoring MQTT telemetry of unknown device {}",
+                id.iter().collect::<String>()
+            );
+            return false;
+        }
+        let (sender, receiver) = channel(16);
+        tokio::spawn(forward_commands(
+            id,
+            device_topic(&self.config.command_topic, &id),
+            self.client.clone(),
+            receiver,
+            self.client_sender.clone(),
+        ));
+        self.client_sender
+            .send(ClientReceiverCommand::ReportClient {
+                id,
+                capabilities: self.config.capabilities,
+                protocol_version: 0,
+                firmware_version: String::new(),
+                sender: sender.clone(),
+            })
+            .await
+            .ok();
+        self.devices.insert(
+            id,
+            MqttDevice {
+                sender,
+                last_seen: Instant::now(),
+            },
+        );
+        true
+    }
+
+    async fn disconnect_idle(&mut self) {
+        let now = Instant::now();
+        let idle = self
+            .devices
+            .iter()
+            .filter(|(_, device)| now - device.last_seen >= self.config.idle_timeout)
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for id in idle {
+            let device = self.devices.remove(&id).unwrap();
+            self.client_sender
+                .send(ClientReceiverCommand::Disconnect {
+                    id,
+                    sender: device.sender,
+                    reason: format!(
+                        "No MQTT telemetry for {}s",
+                        self.config.idle_timeout.as_secs()
+                    ),
+                })
+                .await
+                .ok();
+        }
+    }
+}
+
+/// Publish the commands the farm service sends one device until it forgets the device
+async fn forward_commands(
+    id: [char; 64],
+    topic: String,
+    client: AsyncClient,
+    mut receiver: Receiver<ServerPacket>,
+    client_sender: Sender<ClientReceiverCommand>,
+) {
+    while let Some(packet) = receiver.recv().await {
+        let Some((sequence, payload)) = command_payload(&packet) else {
+            continue;
+        };
+        if let Err(err) = client
+            .publish(&topic, QoS::AtLeastOnce, false, payload)
+            .await
+        {
+            println!("Cannot publish to {topic}: {err}");
+            continue;
+        }
+        if let Some(sequence) = sequence {
+            client_sender
+                .send(ClientReceiverCommand::CommandResult {
+                    id,
+                    sequence,
+                    outcome: CommandOutcome::Unconfirmed,
+                })
+                .await
+                .ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use rumqttc::{mqttbytes::v4, ConnAck, ConnectReturnCode, PubAck, SubAck, SubscribeReasonCode};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use crate::service::{
+        db_service::{DBServiceError, DBServiceResponse},
+        farm_service::telemetry::{self, SensorType, Unit},
+        ServiceHandle,
+    };
+
+    use super::*;
+
+    const DEVICE_ID: [char; 64] = ['m'; 64];
+
+    #[test]
+    fn device_id_comes_from_the_topic() {
+        let id = DEVICE_ID.iter().collect::<String>();
+        assert_eq!(
+            topic_device_id("svf/{id}/sensors", &format!("svf/{id}/sensors")),
+            Some(DEVICE_ID)
+        );
+        assert_eq!(
+            topic_device_id("svf/{id}/sensors", "svf/short/sensors"),
+            None
+        );
+        assert_eq!(
+            topic_device_id("svf/{id}/sensors", &format!("svf/{id}/commands")),
+            None
+        );
+        assert_eq!(
+            topic_device_id("svf/{id}/sensors", &format!("svf/{id}/sensors/extra")),
+            None
+        );
+        assert_eq!(topic_filter("farm/{id}/tele"), "farm/+/tele");
+    }
+
+    #[test]
+    fn telemetry_is_json_or_binary() {
+        let expected = Readings::legacy(400, 27, 800);
+        assert_eq!(
+            parse_telemetry(br#" {"soil_moisture":400,"air_temperature":27,"light_sensor":800}"#),
+            Some(expected.records())
+        );
+        assert_eq!(
+            parse_telemetry(
+                br#"{"soil_moisture":400,"air_temperature":-3.5,"humidity":81.25,"light_sensor":800}"#
+            ),
+            Some(Readings {
+                air_temperature: CentiCelsius(-350),
+                humidity: Some(CentiPercent(8125)),
+                ..expected
+            }
+            .records())
+        );
+        assert_eq!(
+            parse_telemetry(br#"{"records":[{"sensor":5,"index":1,"unit":"ppm","value":650}]}"#),
+            Some(vec![SensorRecord {
+                sensor: SensorType::CO2,
+                index: 1,
+                unit: Unit::Ppm,
+                value: 650,
+            }])
+        );
+        assert_eq!(
+            parse_telemetry(&[0x90, 0x01, 27, 0, 0x20, 0x03]),
+            Some(expected.records())
+        );
+        assert_eq!(parse_telemetry(br#"{"soil_moisture":400}"#), None);
+        assert_eq!(parse_telemetry(&[1, 2, 3]), None);
+    }
+
+    #[test]
+    fn only_command_packets_are_published() {
+        let (sequence, payload) = command_payload(&ServerPacket::Command {
+            sequence: 7,
+            command: DeviceCommand::Cooler { status: true },
+        })
+        .unwrap();
+        assert_eq!(sequence, Some(7));
+        let value: serde_json::Value = serde_json::from_slice(&payload).unwrap();
+        assert_eq!(
+            value,
+            json!({ "command": "cooler", "status": true, "sequence": 7 })
+        );
+        assert_eq!(command_payload(&ServerPacket::WaterPulse).unwrap().0, None);
+        let (sequence, payload) = command_payload(&ServerPacket::Reboot { sequence: 8 }).unwrap();
+        assert_eq!(sequence, Some(8));
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&payload).unwrap(),
+            json!({ "command": "reboot", "sequence": 8 })
+        );
+        assert!(command_payload(&ServerPacket::Ping { sequence: 1 }).is_none());
+    }
+
+    /// Just enough of a broker to accept the bridge, acknowledge its requests and
+    /// exchange publishes with the test
+    struct Broker {
+        stream: TcpStream,
+        buffer: BytesMut,
+    }
+
+    impl Broker {
+        async fn accept(listener: &TcpListener) -> Self {
+            let (stream, _) = listener.accept().await.unwrap();
+            Self {
+                stream,
+                buffer: BytesMut::new(),
+            }
+        }
+
+        async fn read(&mut self) -> v4::Packet {
+            loop {
+                match v4::read(&mut self.buffer, 1 << 20) {
+                    Ok(packet) => return packet,
+                    Err(rumqttc::Error::InsufficientBytes(_)) => {
+                        assert!(self.stream.read_buf(&mut self.buffer).await.unwrap() > 0)
+                    }
+                    Err(err) => panic!("{err:?}"),
+                }
+            }
+        }
+
+        async fn write(&mut self, write: impl FnOnce(&mut BytesMut)) {
+            let mut buffer = BytesMut::new();
+            write(&mut buffer);
+            self.stream.write_all(&buffer).await.unwrap();
+        }
+
+        /// Answer the bridge until it sends something the test has to look at
+        async fn next(&mut self) -> v4::Packet {
+            loop {
+                match self.read().await {
+                    v4::Packet::Connect(..) => {
+                        self.write(|buffer| {
+                            ConnAck::new(ConnectReturnCode::Success, false)
+                                .write(buffer)
+                                .unwrap();
+                        })
+                        .await
+                    }
+                    v4::Packet::PingReq => {
+                        self.write(|buffer| {
+                            v4::PingResp.write(buffer).unwrap();
+                        })
+                        .await
+                    }
+                    v4::Packet::Subscribe(subscribe) => {
+                        self.write(|buffer| {
+                            SubAck::new(
+                                subscribe.pkid,
+                                vec![SubscribeReasonCode::Success(QoS::AtLeastOnce)],
+                            )
+                            .write(buffer)
+                            .unwrap();
+                        })
+                        .await;
+                        return v4::Packet::Subscribe(subscribe);
+                    }
+                    v4::Packet::Publish(publish) => {
+                        self.write(|buffer| {
+                            PubAck::new(publish.pkid).write(buffer).unwrap();
+                        })
+                        .await;
+                        return v4::Packet::Publish(publish);
+                    }
+                    packet => return packet,
+                }
+            }
+        }
+
+        async fn publish(&mut self, topic: &str, payload: &[u8]) {
+            self.write(|buffer| {
+                Publish::new(topic, QoS::AtMostOnce, payload)
+                    .write(buffer)
+                    .unwrap();
+            })
+            .await
+        }
+    }
+
+    #[tokio::test]
+    async fn telemetry_and_commands_cross_the_bridge() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (client_sender, mut client_receiver) = channel(16);
+        let (db_sender, mut db_receiver) = channel::<crate::service::ServiceRequest<_, _>>(16);
+        tokio::spawn(async move {
+            while let Some(request) = db_receiver.recv().await {
+                let response = match request.data {
+                    DBServiceRequest::GetDeviceSecret { id } if id == DEVICE_ID => {
+                        Ok(DBServiceResponse::DeviceSecret(['s'; 64]))
+                    }
+                    _ => Err(DBServiceError::UnregisterdDevice),
+                };
+                request.result_sender.send(response).await.ok();
+            }
+        });
+        let config = MqttConfig::new(addr.ip().to_string(), addr.port());
+        let (bridge, event_loop) =
+            MqttBridge::new(config, client_sender, ServiceHandle::new(db_sender));
+        tokio::spawn(bridge.run(event_loop));
+
+        let mut broker = Broker::accept(&listener).await;
+        let v4::Packet::Subscribe(subscribe) = broker.next().await else {
+            panic!("expected a subscription");
+        };
+        assert_eq!(subscribe.filters[0].path, "svf/+/sensors");
+
+        let id = DEVICE_ID.iter().collect::<String>();
+        broker
+            .publish(
+                &format!("svf/{}/sensors", "u".repeat(64)),
+                b"\x01\x00\x02\x00\x03\x00",
+            )
+            .await;
+        broker
+            .publish(
+                &format!("svf/{id}/sensors"),
+                br#"{"soil_moisture":600,"air_temperature":30,"light_sensor":100}"#,
+            )
+            .await;
+
+        let Some(ClientReceiverCommand::ReportClient {
+            id: reported,
+            capabilities,
+            sender,
+            ..
+        }) = client_receiver.recv().await
+        else {
+            panic!("expected the device to join");
+        };
+        assert_eq!(reported, DEVICE_ID);
+        assert!(capabilities.contains(Capabilities::COOLER));
+        match client_receiver.recv().await {
+            Some(ClientReceiverCommand::ReportSensors {
+                id, records, image, ..
+            }) => {
+                assert_eq!(id, DEVICE_ID);
+                assert_eq!(
+                    telemetry::find(&records, SensorType::SOIL_MOISTURE, 0, Unit::Raw),
+                    Some(600)
+                );
+                assert_eq!(
+                    telemetry::find(&records, SensorType::AIR_TEMPERATURE, 0, Unit::CentiCelsius),
+                    Some(3000)
+                );
+                assert!(image.is_empty());
+            }
+            _ => panic!("expected a sensor report"),
+        }
+
+        sender
+            .send(ServerPacket::Command {
+                sequence: 3,
+                command: DeviceCommand::WaterPulse,
+            })
+            .await
+            .unwrap();
+        let v4::Packet::Publish(publish) = broker.next().await else {
+            panic!("expected a command");
+        };
+        assert_eq!(publish.topic, format!("svf/{id}/commands"));
+        let value: serde_json::Value = serde_json::from_slice(&publish.payload).unwrap();
+        assert_eq!(value, json!({ "command": "water_pulse", "sequence": 3 }));
+        assert!(matches!(
+            client_receiver.recv().await,
+            Some(ClientReceiverCommand::CommandResult {
+                sequence: 3,
+                outcome: CommandOutcome::Unconfirmed,
+                ..
+            })
+        ));
+    }
+}
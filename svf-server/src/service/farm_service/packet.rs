@@ -1,4 +1,4 @@
-use std::{error::Error, fmt::Display};
+use std::{error::Error, fmt::Display, io};
 
 use crate::utils::{buffer_reader::BufferReader, buffer_writer::BufferWriter};
 
@@ -13,12 +13,19 @@ pub struct PacketHeader {
 }
 
 impl PacketHeader {
+    /// Size of an encoded header on the wire
+    pub const SIZE: usize = 8;
+
+    pub fn new(length: u32, id: u32) -> Self {
+        Self { length, id }
+    }
+
     pub fn length(&self) -> u32 {
-        return self.length;
+        self.length
     }
 
     pub fn id(&self) -> u32 {
-        return self.id;
+        self.id
     }
 
     pub fn from_bytes(buffer: &[u8]) -> Result<Self, PacketError> {
@@ -57,7 +64,7 @@ impl<'a, T: PacketId + TryFrom<u32> + for<'b> TryFrom<&'b T::Packet>> Packet<'a,
     }
 
     pub fn encode(&mut self, packet: T::Packet) -> &mut Self {
-        self.id.encode(BufferWriter::new(&mut self.buffer), packet);
+        T::encode(BufferWriter::new(self.buffer), packet);
         self
     }
 
@@ -77,6 +84,9 @@ impl<'a, T: PacketId + TryFrom<u32> + for<'b> TryFrom<&'b T::Packet>> Packet<'a,
 pub enum PacketError {
     InvalidPacketLength,
     InvalidPacketId,
+    FrameTooLarge { length: u32, max_length: u32 },
+    UnexpectedEof,
+    Io(io::Error),
 }
 
 impl Display for PacketError {
@@ -84,18 +94,203 @@ impl Display for PacketError {
         match self {
             Self::InvalidPacketLength => write!(f, "Invalid packet length"),
             Self::InvalidPacketId => write!(f, "Invalid packet id"),
+            Self::FrameTooLarge { length, max_length } => write!(
+                f,
+                "Frame of {length} bytes exceeds the maximum of {max_length} bytes"
+            ),
+            Self::UnexpectedEof => write!(f, "Stream ended in the middle of a frame"),
+            Self::Io(err) => write!(f, "IO error: {err}"),
         }
     }
 }
 
 impl Error for PacketError {}
 
+impl From<io::Error> for PacketError {
+    fn from(value: io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+/// Implemented by `#[derive(Packet)]` for the id enum of a packet enum
 pub trait PacketId {
     type Packet;
 
     fn decode(&self, buffer: BufferReader) -> Result<Self::Packet, PacketError>;
 
-    fn encode(&self, buffer: BufferWriter, packet: Self::Packet);
+    fn encode(buffer: BufferWriter, packet: Self::Packet);
 
     fn id(&self) -> u32;
+
+    /// First protocol version in which this packet exists
+    fn since_version(&self) -> u16;
+}
+
+/// A value that can be a field of a `#[derive(Packet)]` enum variant
+pub trait PacketField: Sized {
+    fn read(buffer: &mut BufferReader) -> Result<Self, PacketError>;
+
+    fn write(&self, buffer: &mut BufferWriter);
+}
+
+macro_rules! packet_field {
+    ($($ty:ty => $read:ident, $write:ident;)*) => {
+        $(
+            impl PacketField for $ty {
+                fn read(buffer: &mut BufferReader) -> Result<Self, PacketError> {
+                    buffer.$read().ok_or(PacketError::InvalidPacketLength)
+                }
+
+                fn write(&self, buffer: &mut BufferWriter) {
+                    buffer.$write(*self);
+                }
+            }
+        )*
+    };
+}
+
+packet_field! {
+    u8 => read_u8, write_u8;
+    u16 => read_u16, write_u16;
+    u32 => read_u32, write_u32;
+    u64 => read_u64, write_u64;
+    i8 => read_i8, write_i8;
+    i16 => read_i16, write_i16;
+    i32 => read_i32, write_i32;
+    i64 => read_i64, write_i64;
+    bool => read_bool, write_bool;
+}
+
+/// Sizes travel as `u64` so the wire format doesn't depend on the platform
+impl PacketField for usize {
+    fn read(buffer: &mut BufferReader) -> Result<Self, PacketError> {
+        usize::try_from(u64::read(buffer)?).map_err(|_| PacketError::InvalidPacketLength)
+    }
+
+    fn write(&self, buffer: &mut BufferWriter) {
+        buffer.write_u64(*self as u64);
+    }
+}
+
+/// `u32` length prefixed UTF-8
+impl PacketField for String {
+    fn read(buffer: &mut BufferReader) -> Result<Self, PacketError> {
+        buffer.read_string().ok_or(PacketError::InvalidPacketLength)
+    }
+
+    fn write(&self, buffer: &mut BufferWriter) {
+        buffer.write_string(self.clone());
+    }
+}
+
+/// `u32` length prefixed bytes
+impl PacketField for Vec<u8> {
+    fn read(buffer: &mut BufferReader) -> Result<Self, PacketError> {
+        let length = buffer.read_u32().ok_or(PacketError::InvalidPacketLength)?;
+        Ok(buffer
+            .read_bytes(length as usize)
+            .ok_or(PacketError::InvalidPacketLength)?
+            .to_vec())
+    }
+
+    fn write(&self, buffer: &mut BufferWriter) {
+        buffer.write_u32(self.len() as u32).write_bytes(self);
+    }
+}
+
+/// Fixed size, no length prefix
+impl<const N: usize> PacketField for [u8; N] {
+    fn read(buffer: &mut BufferReader) -> Result<Self, PacketError> {
+        buffer
+            .const_read_bytes::<N>()
+            .ok_or(PacketError::InvalidPacketLength)
+    }
+
+    fn write(&self, buffer: &mut BufferWriter) {
+        buffer.write_bytes(self);
+    }
+}
+
+/// Fixed size ASCII ids and tokens, one byte per character
+impl<const N: usize> PacketField for [char; N] {
+    fn read(buffer: &mut BufferReader) -> Result<Self, PacketError> {
+        Ok(<[u8; N]>::read(buffer)?.map(|byte| byte as char))
+    }
+
+    fn write(&self, buffer: &mut BufferWriter) {
+        buffer.write_bytes(&self.map(|c| c as u8));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use packet_derive::Packet;
+
+    use super::*;
+
+    #[derive(Packet, Debug, Clone, PartialEq)]
+    #[packet(id = TestPacketId)]
+    enum TestPacket {
+        Empty,
+        Fields {
+            small: u8,
+            text: String,
+            id: [char; 4],
+        },
+        #[packet(since = 3)]
+        Bytes {
+            data: Vec<u8>,
+            size: usize,
+        },
+    }
+
+    fn round_trip(packet: TestPacket) -> TestPacket {
+        let id = TestPacketId::from(&packet);
+        let mut buffer = Vec::new();
+        TestPacketId::encode(BufferWriter::new(&mut buffer), packet);
+        TestPacketId::try_from(id.id())
+            .unwrap()
+            .decode(BufferReader::new(&buffer))
+            .unwrap()
+    }
+
+    #[test]
+    fn ids_follow_declaration_order() {
+        assert_eq!(TestPacketId::Empty.id(), 0);
+        assert_eq!(TestPacketId::Bytes.id(), 2);
+        assert_eq!(TestPacketId::Fields.since_version(), 1);
+        assert_eq!(TestPacketId::Bytes.since_version(), 3);
+        assert!(matches!(
+            TestPacketId::try_from(3),
+            Err(PacketError::InvalidPacketId)
+        ));
+    }
+
+    #[test]
+    fn derived_codec_round_trips() {
+        let packets = [
+            TestPacket::Empty,
+            TestPacket::Fields {
+                small: 7,
+                text: "hello".to_string(),
+                id: ['a', 'b', 'c', 'd'],
+            },
+            TestPacket::Bytes {
+                data: vec![1, 2, 3],
+                size: 1 << 40,
+            },
+        ];
+        for packet in packets {
+            assert_eq!(round_trip(packet.clone()), packet);
+        }
+    }
+
+    #[test]
+    fn truncated_fields_are_rejected() {
+        let buffer = [7, 5, 0];
+        assert!(matches!(
+            TestPacketId::Fields.decode(BufferReader::new(&buffer)),
+            Err(PacketError::InvalidPacketLength)
+        ));
+    }
 }
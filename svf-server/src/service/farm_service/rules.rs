@@ -0,0 +1,590 @@
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
+
+use chrono::{DateTime, NaiveDate, NaiveTime};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    command::DeviceCommand,
+    settings::MAX_PUMP_PULSE,
+    telemetry::{StoredReading, Unit},
+};
+
+/// Deepest nesting of `all`, `any` and `not` a rule can use
+const MAX_DEPTH: usize = 8;
+
+/// An automation rule of a farm, the rules of a farm are stored as a JSON list in
+/// `farms.rules` and evaluated in order on every sensor report
+///
+/// While a rule matches it owns the actuators its commands drive, the commands the
+/// control policy decided for them are dropped, also once the rule reached
+/// `max_per_day`. Earlier rules take precedence over later ones.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub name: String,
+    pub when: Condition,
+    pub then: Vec<Action>,
+    /// Reports a day of the farm the rule acts on at most, unlimited when unset
+    #[serde(default)]
+    pub max_per_day: Option<u16>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum Condition {
+    All(Vec<Condition>),
+    Any(Vec<Condition>),
+    Not(Box<Condition>),
+    /// The reported value of a sensor, in the unit it was reported in, bounds excluded
+    Reading {
+        sensor: String,
+        #[serde(default)]
+        probe: u8,
+        /// Any unit the sensor reported in when unset
+        #[serde(default)]
+        unit: Option<Unit>,
+        #[serde(default)]
+        above: Option<i32>,
+        #[serde(default)]
+        below: Option<i32>,
+    },
+    /// Local time of the farm, from `after` until `before`, past midnight when
+    /// `after` is later than `before`
+    Time {
+        #[serde(default)]
+        after: Option<NaiveTime>,
+        #[serde(default)]
+        before: Option<NaiveTime>,
+    },
+    /// The state the cooler was last switched to, off when it never was
+    Cooler {
+        running: bool,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    /// Only actuator commands, sent on every report the rule acts on
+    Command(DeviceCommand),
+    /// Published as a `DeviceEvent::Alert` when the rule starts matching
+    Alert(String),
+}
+
+pub fn validate(rules: &[Rule]) -> Result<(), String> {
+    let mut names = HashSet::new();
+    for rule in rules {
+        if rule.name.is_empty() {
+            return Err("Every rule needs a name".to_string());
+        }
+        if !names.insert(&rule.name) {
+            return Err(format!("More than one rule is named {}", rule.name));
+        }
+        if rule.then.is_empty() {
+            return Err(format!("Rule {} has no actions", rule.name));
+        }
+        rule.when
+            .validate(0)
+            .map_err(|err| format!("Rule {}: {err}", rule.name))?;
+        for action in &rule.then {
+            let Action::Command(command) = action else {
+                continue;
+            };
+            match command {
+                DeviceCommand::Water { millis }
+                    if *millis == 0 || Duration::from_millis((*millis).into()) > MAX_PUMP_PULSE =>
+                {
+                    return Err(format!(
+                        "Rule {}: the pump can run for at most 60 seconds",
+                        rule.name
+                    ));
+                }
+                command if actuator(command).is_none() => {
+                    return Err(format!(
+                        "Rule {}: rules can only drive the cooler and the water pump",
+                        rule.name
+                    ));
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Actuator {
+    Cooler,
+    Pump,
+}
+
+fn actuator(command: &DeviceCommand) -> Option<Actuator> {
+    match command {
+        DeviceCommand::Cooler { .. } | DeviceCommand::CoolerDuty { .. } => Some(Actuator::Cooler),
+        DeviceCommand::WaterPulse | DeviceCommand::Water { .. } => Some(Actuator::Pump),
+        DeviceCommand::Configure(..) | DeviceCommand::Reboot | DeviceCommand::FactoryReset => None,
+    }
+}
+
+/// What a report is checked against
+#[derive(Debug, Clone, Copy)]
+pub struct RuleContext<'a> {
+    pub readings: &'a [StoredReading],
+    /// When the readings were taken, in the timezone of the farm
+    pub now: DateTime<Tz>,
+    pub cooler: Option<bool>,
+}
+
+/// How a condition evaluated, the explanation a dry run returns
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConditionTrace {
+    pub condition: String,
+    pub matched: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub children: Vec<ConditionTrace>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RuleTrace {
+    pub rule: String,
+    pub when: ConditionTrace,
+    /// What the rule does on this report, empty when it doesn't match or is held
+    pub actions: Vec<Action>,
+    /// Why a matching rule doesn't act
+    pub held: Option<String>,
+}
+
+fn bounds(above: Option<i32>, below: Option<i32>) -> String {
+    let above = above.map(|above| format!(" above {above}"));
+    let below = below.map(|below| format!(" below {below}"));
+    [above, below]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>()
+        .join(" and")
+}
+
+impl Condition {
+    fn validate(&self, depth: usize) -> Result<(), String> {
+        if depth > MAX_DEPTH {
+            return Err("conditions are nested too deep".to_string());
+        }
+        match self {
+            Self::All(conditions) | Self::Any(conditions) => conditions
+                .iter()
+                .try_for_each(|condition| condition.validate(depth + 1)),
+            Self::Not(condition) => condition.validate(depth + 1),
+            Self::Reading {
+                sensor,
+                above: None,
+                below: None,
+                ..
+            } => Err(format!(
+                "the condition on {sensor} needs `above` or `below`"
+            )),
+            Self::Time {
+                after: None,
+                before: None,
+            } => Err("a time condition needs `after` or `before`".to_string()),
+            _ => Ok(()),
+        }
+    }
+
+    fn evaluate(&self, context: &RuleContext) -> ConditionTrace {
+        let (condition, matched, children) = match self {
+            Self::All(conditions) | Self::Any(conditions) => {
+                let children = conditions
+                    .iter()
+                    .map(|condition| condition.evaluate(context))
+                    .collect::<Vec<_>>();
+                if matches!(self, Self::All(..)) {
+                    let matched = children.iter().all(|child| child.matched);
+                    ("all".to_string(), matched, children)
+                } else {
+                    let matched = children.iter().any(|child| child.matched);
+                    ("any".to_string(), matched, children)
+                }
+            }
+            Self::Not(condition) => {
+                let child = condition.evaluate(context);
+                ("not".to_string(), !child.matched, vec![child])
+            }
+            Self::Reading {
+                sensor,
+                probe,
+                unit,
+                above,
+                below,
+            } => {
+                let reading = context.readings.iter().find(|reading| {
+                    &reading.sensor == sensor
+                        && reading.index == *probe
+                        && unit.is_none_or(|unit| reading.unit == unit)
+                });
+                match reading {
+                    Some(reading) => (
+                        format!(
+                            "{sensor}[{probe}] is {} {}, wants{}",
+                            reading.value,
+                            reading.unit.name(),
+                            bounds(*above, *below)
+                        ),
+                        above.is_none_or(|above| reading.value > above)
+                            && below.is_none_or(|below| reading.value < below),
+                        Vec::new(),
+                    ),
+                    None => (
+                        format!("{sensor}[{probe}] wasn't reported"),
+                        false,
+                        Vec::new(),
+                    ),
+                }
+            }
+            Self::Time { after, before } => {
+                let time = context.now.time();
+                let matched = match (after, before) {
+                    (Some(after), Some(before)) if after <= before => {
+                        time >= *after && time < *before
+                    }
+                    (Some(after), Some(before)) => time >= *after || time < *before,
+                    (Some(after), None) => time >= *after,
+                    (None, Some(before)) => time < *before,
+                    (None, None) => true,
+                };
+                let after = after.map(|after| format!(" after {}", after.format("%H:%M:%S")));
+                let before = before.map(|before| format!(" before {}", before.format("%H:%M:%S")));
+                (
+                    format!(
+                        "time is {}, wants{}{}",
+                        time.format("%H:%M:%S"),
+                        after.unwrap_or_default(),
+                        before.unwrap_or_default()
+                    ),
+                    matched,
+                    Vec::new(),
+                )
+            }
+            Self::Cooler { running } => {
+                let state = |running| if running { "running" } else { "off" };
+                let cooler = context.cooler.unwrap_or(false);
+                (
+                    format!("cooler is {}, wants {}", state(cooler), state(*running)),
+                    cooler == *running,
+                    Vec::new(),
+                )
+            }
+        };
+        ConditionTrace {
+            condition,
+            matched,
+            children,
+        }
+    }
+}
+
+/// What the rules decided on a report
+#[derive(Debug, Default)]
+pub struct Decision {
+    commands: Vec<DeviceCommand>,
+    /// Actuators taken over from the control policy
+    owned: HashSet<Actuator>,
+    /// Rule and message of every alert raised
+    pub alerts: Vec<(String, String)>,
+}
+
+impl Decision {
+    /// The commands of the rules and those of `policy` for actuators no rule owns
+    pub fn merge(self, mut policy: Vec<DeviceCommand>) -> Vec<DeviceCommand> {
+        policy.retain(|command| actuator(command).is_none_or(|used| !self.owned.contains(&used)));
+        policy.extend(self.commands);
+        policy
+    }
+}
+
+/// The rules of one farm and what they did, kept across reconnects of its device
+#[derive(Debug, Clone, Default)]
+pub struct RuleEngine {
+    rules: Vec<Rule>,
+    /// Rules that matched the last report, alerts are raised when a rule starts matching
+    matching: HashSet<String>,
+    /// Day of the farm `acted` counts for
+    day: Option<NaiveDate>,
+    acted: HashMap<String, u16>,
+    /// Readings of the last report, what dry runs are checked against
+    latest: Vec<StoredReading>,
+}
+
+impl RuleEngine {
+    pub fn configure(&mut self, rules: Vec<Rule>) {
+        let known = |name: &String| rules.iter().any(|rule| &rule.name == name);
+        self.matching.retain(known);
+        self.acted.retain(|name, _| known(name));
+        self.rules = rules;
+    }
+
+    pub fn latest(&self) -> &[StoredReading] {
+        &self.latest
+    }
+
+    /// How `rules`, or the rules of the farm when `None`, would decide in `context`,
+    /// without acting on it
+    pub fn explain(&self, rules: Option<&[Rule]>, context: &RuleContext) -> Vec<RuleTrace> {
+        let today = context.now.date_naive();
+        rules
+            .unwrap_or(&self.rules)
+            .iter()
+            .map(|rule| {
+                let when = rule.when.evaluate(context);
+                let acted = match self.day == Some(today) {
+                    true => self.acted.get(&rule.name).copied().unwrap_or_default(),
+                    false => 0,
+                };
+                let held = match rule.max_per_day {
+                    Some(max) if when.matched && acted >= max => {
+                        Some(format!("Already acted on {acted} reports today"))
+                    }
+                    _ => None,
+                };
+                let starts = !self.matching.contains(&rule.name);
+                let actions = match when.matched && held.is_none() {
+                    true => rule
+                        .then
+                        .iter()
+                        .filter(|action| starts || matches!(action, Action::Command(..)))
+                        .cloned()
+                        .collect(),
+                    false => Vec::new(),
+                };
+                RuleTrace {
+                    rule: rule.name.clone(),
+                    when,
+                    actions,
+                    held,
+                }
+            })
+            .collect()
+    }
+
+    /// Evaluate the rules of the farm on a report
+    pub fn evaluate(&mut self, context: &RuleContext) -> Decision {
+        let traces = self.explain(None, context);
+        let today = context.now.date_naive();
+        if self.day != Some(today) {
+            self.day = Some(today);
+            self.acted.clear();
+        }
+        self.latest = context.readings.to_vec();
+        let mut decision = Decision::default();
+        for (rule, trace) in self.rules.iter().zip(traces) {
+            if !trace.when.matched {
+                self.matching.remove(&rule.name);
+                continue;
+            }
+            self.matching.insert(rule.name.clone());
+            let owned = rule
+                .then
+                .iter()
+                .filter_map(|action| match action {
+                    Action::Command(command) => actuator(command),
+                    Action::Alert(..) => None,
+                })
+                .filter(|owned| !decision.owned.contains(owned))
+                .collect::<HashSet<_>>();
+            if trace.held.is_none() {
+                *self.acted.entry(rule.name.clone()).or_default() += 1;
+            }
+            for action in trace.actions {
+                match action {
+                    Action::Command(command) => {
+                        if actuator(&command).is_some_and(|used| owned.contains(&used)) {
+                            decision.commands.push(command);
+                        }
+                    }
+                    Action::Alert(message) => decision.alerts.push((rule.name.clone(), message)),
+                }
+            }
+            decision.owned.extend(owned);
+        }
+        decision
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{NaiveDate, TimeZone};
+
+    use super::*;
+
+    fn rules(json: &str) -> Vec<Rule> {
+        let rules: Vec<Rule> = serde_json::from_str(json).unwrap();
+        validate(&rules).unwrap();
+        rules
+    }
+
+    fn reading(sensor: &str, unit: Unit, value: i32) -> StoredReading {
+        StoredReading {
+            sensor: sensor.to_string(),
+            index: 0,
+            unit,
+            value,
+        }
+    }
+
+    fn at(hour: u32, minute: u32) -> DateTime<Tz> {
+        Tz::Europe__Brussels
+            .from_local_datetime(
+                &NaiveDate::from_ymd_opt(2024, 7, 1)
+                    .unwrap()
+                    .and_hms_opt(hour, minute, 0)
+                    .unwrap(),
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn rules_take_over_the_actuators_they_drive() {
+        let mut engine = RuleEngine::default();
+        engine.configure(rules(
+            r#"[{
+                "name": "heatwave",
+                "when": {"reading": {"sensor": "air_temperature", "above": 3500}},
+                "then": [{"command": {"cooler": {"status": true}}}, {"alert": "Above 35°C"}]
+            }]"#,
+        ));
+        let policy = vec![
+            DeviceCommand::Cooler { status: false },
+            DeviceCommand::WaterPulse,
+        ];
+        let hot = [reading("air_temperature", Unit::CentiCelsius, 3600)];
+        let context = RuleContext {
+            readings: &hot,
+            now: at(12, 0),
+            cooler: None,
+        };
+        let decision = engine.evaluate(&context);
+        assert_eq!(
+            decision.alerts,
+            [("heatwave".to_string(), "Above 35°C".to_string())]
+        );
+        assert_eq!(
+            decision.merge(policy.clone()),
+            [
+                DeviceCommand::WaterPulse,
+                DeviceCommand::Cooler { status: true }
+            ]
+        );
+        // The alert is only raised when the rule starts matching
+        assert!(engine.evaluate(&context).alerts.is_empty());
+
+        let mild = [reading("air_temperature", Unit::CentiCelsius, 3000)];
+        let decision = engine.evaluate(&RuleContext {
+            readings: &mild,
+            ..context
+        });
+        assert_eq!(decision.merge(policy.clone()), policy);
+    }
+
+    #[test]
+    fn rules_can_act_once_a_day_after_a_time() {
+        let mut engine = RuleEngine::default();
+        engine.configure(rules(
+            r#"[{
+                "name": "evening",
+                "when": {"all": [
+                    {"reading": {"sensor": "light", "unit": "raw", "below": 300}},
+                    {"time": {"after": "18:00:00", "before": "06:00:00"}}
+                ]},
+                "then": [{"command": "water_pulse"}],
+                "max_per_day": 1
+            }]"#,
+        ));
+        let dark = [reading("light", Unit::Raw, 120)];
+        let context = |now| RuleContext {
+            readings: &dark,
+            now,
+            cooler: None,
+        };
+        let policy = vec![DeviceCommand::WaterPulse];
+        assert_eq!(
+            engine.evaluate(&context(at(17, 0))).merge(policy.clone()),
+            policy
+        );
+        assert_eq!(
+            engine.evaluate(&context(at(18, 30))).merge(policy.clone()),
+            policy
+        );
+        // Held for the rest of the day, and the policy doesn't water either
+        assert_eq!(
+            engine.evaluate(&context(at(19, 0))).merge(policy.clone()),
+            []
+        );
+        let trace = &engine.explain(None, &context(at(19, 30)))[0];
+        assert!(trace.when.matched);
+        assert!(trace.actions.is_empty());
+        assert_eq!(
+            trace.held.as_deref(),
+            Some("Already acted on 1 reports today")
+        );
+    }
+
+    #[test]
+    fn dry_runs_explain_every_condition() {
+        let engine = RuleEngine::default();
+        let candidate = rules(
+            r#"[{
+                "name": "dry",
+                "when": {"any": [
+                    {"reading": {"sensor": "soil_moisture", "probe": 1, "above": 600}},
+                    {"not": {"cooler": {"running": false}}}
+                ]},
+                "then": [{"alert": "Check the farm"}]
+            }]"#,
+        );
+        let readings = [reading("soil_moisture", Unit::Raw, 700)];
+        let traces = engine.explain(
+            Some(&candidate),
+            &RuleContext {
+                readings: &readings,
+                now: at(9, 0),
+                cooler: Some(true),
+            },
+        );
+        let when = &traces[0].when;
+        assert!(when.matched);
+        assert_eq!(
+            when.children[0].condition,
+            "soil_moisture[1] wasn't reported"
+        );
+        assert!(!when.children[0].matched);
+        assert_eq!(
+            when.children[1].children[0].condition,
+            "cooler is running, wants off"
+        );
+        assert_eq!(
+            traces[0].actions,
+            [Action::Alert("Check the farm".to_string())]
+        );
+        assert!(engine.matching.is_empty());
+    }
+
+    #[test]
+    fn invalid_rules_are_rejected() {
+        for json in [
+            r#"[{"name": "", "when": {"cooler": {"running": true}}, "then": [{"alert": "a"}]}]"#,
+            r#"[{"name": "a", "when": {"cooler": {"running": true}}, "then": []}]"#,
+            r#"[{"name": "a", "when": {"reading": {"sensor": "light"}}, "then": [{"alert": "a"}]}]"#,
+            r#"[{"name": "a", "when": {"time": {}}, "then": [{"alert": "a"}]}]"#,
+            r#"[{"name": "a", "when": {"cooler": {"running": true}}, "then": [{"command": "reboot"}]}]"#,
+            r#"[{"name": "a", "when": {"cooler": {"running": true}}, "then": [{"command": {"water": {"millis": 60001}}}]}]"#,
+            r#"[{"name": "a", "when": {"cooler": {"running": true}}, "then": [{"alert": "a"}]},
+                {"name": "a", "when": {"cooler": {"running": false}}, "then": [{"alert": "b"}]}]"#,
+        ] {
+            let rules: Vec<Rule> = serde_json::from_str(json).unwrap();
+            assert!(validate(&rules).is_err(), "{json}");
+        }
+    }
+}
@@ -0,0 +1,207 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::utils::{buffer_reader::BufferReader, buffer_writer::BufferWriter};
+
+use super::packet::{PacketError, PacketField};
+
+/// Layout of [`Readings`] on the wire, bumped whenever a field is added
+pub const READINGS_VERSION: u8 = 1;
+
+/// Marks a reading the device has no sensor or calibration for
+const MISSING_U16: u16 = u16::MAX;
+const MISSING_U32: u32 = u32::MAX;
+
+/// Hundredths of a degree Celsius, the resolution the DHT22 measures with
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(transparent)]
+pub struct CentiCelsius(pub i16);
+
+impl CentiCelsius {
+    pub fn from_degrees(degrees: i32) -> Self {
+        Self(
+            degrees
+                .saturating_mul(100)
+                .clamp(i16::MIN.into(), i16::MAX.into()) as i16,
+        )
+    }
+
+    /// Rounded to the nearest hundredth, out of range values saturate
+    pub fn from_degrees_f32(degrees: f32) -> Self {
+        Self((degrees * 100.0).round() as i16)
+    }
+
+    pub fn degrees(self) -> f32 {
+        f32::from(self.0) / 100.0
+    }
+}
+
+impl fmt::Display for CentiCelsius {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let value = self.0.unsigned_abs();
+        write!(f, "{sign}{}.{:02}°C", value / 100, value % 100)
+    }
+}
+
+/// Hundredths of a percent, used for relative humidity and soil water content
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(transparent)]
+pub struct CentiPercent(pub u16);
+
+impl CentiPercent {
+    /// Rounded to the nearest hundredth and clamped to 0-100%
+    pub fn from_percent_f32(percent: f32) -> Self {
+        Self((percent.clamp(0.0, 100.0) * 100.0).round() as u16)
+    }
+
+    pub fn percent(self) -> f32 {
+        f32::from(self.0) / 100.0
+    }
+}
+
+impl fmt::Display for CentiPercent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{:02}%", self.0 / 100, self.0 % 100)
+    }
+}
+
+/// An analog reading as the ADC returned it, and converted to physical units
+/// with the calibration stored on the device
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Calibrated<T> {
+    pub raw: u16,
+    /// `None` for devices that aren't calibrated
+    pub value: Option<T>,
+}
+
+impl<T> Calibrated<T> {
+    pub fn raw(raw: u16) -> Self {
+        Self { raw, value: None }
+    }
+}
+
+/// One set of sensor readings from a device
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Readings {
+    pub air_temperature: CentiCelsius,
+    /// Relative humidity, `None` for reports that predate it
+    pub humidity: Option<CentiPercent>,
+    /// 10-bit ADC reading, higher is drier, calibrated to volumetric water content
+    pub soil_moisture: Calibrated<CentiPercent>,
+    /// 10-bit ADC reading, calibrated to lux
+    pub light: Calibrated<u32>,
+}
+
+impl Readings {
+    /// Readings from `ReportSensors`, which carried whole degrees in a `u16`
+    pub fn legacy(soil_moisture: u16, air_temperature: u16, light_sensor: u16) -> Self {
+        Self {
+            air_temperature: CentiCelsius::from_degrees(air_temperature.into()),
+            humidity: None,
+            soil_moisture: Calibrated::raw(soil_moisture),
+            light: Calibrated::raw(light_sensor),
+        }
+    }
+}
+
+fn read_u16(buffer: &mut BufferReader) -> Result<Option<u16>, PacketError> {
+    Ok(Some(u16::read(buffer)?).filter(|value| *value != MISSING_U16))
+}
+
+/// Starts with [`READINGS_VERSION`], missing values are sent as all ones
+impl PacketField for Readings {
+    fn read(buffer: &mut BufferReader) -> Result<Self, PacketError> {
+        match u8::read(buffer)? {
+            1 => Ok(Self {
+                air_temperature: CentiCelsius(i16::read(buffer)?),
+                humidity: read_u16(buffer)?.map(CentiPercent),
+                soil_moisture: Calibrated {
+                    raw: u16::read(buffer)?,
+                    value: read_u16(buffer)?.map(CentiPercent),
+                },
+                light: Calibrated {
+                    raw: u16::read(buffer)?,
+                    value: Some(u32::read(buffer)?).filter(|lux| *lux != MISSING_U32),
+                },
+            }),
+            _ => Err(PacketError::InvalidPacketId),
+        }
+    }
+
+    fn write(&self, buffer: &mut BufferWriter) {
+        buffer
+            .write_u8(READINGS_VERSION)
+            .write_i16(self.air_temperature.0)
+            .write_u16(self.humidity.map_or(MISSING_U16, |humidity| humidity.0))
+            .write_u16(self.soil_moisture.raw)
+            .write_u16(
+                self.soil_moisture
+                    .value
+                    .map_or(MISSING_U16, |value| value.0),
+            )
+            .write_u16(self.light.raw)
+            .write_u32(self.light.value.unwrap_or(MISSING_U32));
+    }
+}
+
+#[cfg(test)]
+pub fn readings_strategy() -> impl proptest::strategy::Strategy<Value = Readings> {
+    use proptest::{option, prelude::*};
+
+    (
+        any::<i16>(),
+        option::of(0..MISSING_U16),
+        any::<u16>(),
+        option::of(0..MISSING_U16),
+        any::<u16>(),
+        option::of(0..MISSING_U32),
+    )
+        .prop_map(
+            |(temperature, humidity, soil_raw, soil, light_raw, lux)| Readings {
+                air_temperature: CentiCelsius(temperature),
+                humidity: humidity.map(CentiPercent),
+                soil_moisture: Calibrated {
+                    raw: soil_raw,
+                    value: soil.map(CentiPercent),
+                },
+                light: Calibrated {
+                    raw: light_raw,
+                    value: lux,
+                },
+            },
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fixed_point_keeps_sign_and_fraction() {
+        assert_eq!(CentiCelsius::from_degrees_f32(-7.25), CentiCelsius(-725));
+        assert_eq!(CentiCelsius::from_degrees_f32(23.456), CentiCelsius(2346));
+        assert_eq!(CentiCelsius::from_degrees(21), CentiCelsius(2100));
+        assert_eq!(CentiCelsius::from_degrees(1000), CentiCelsius(i16::MAX));
+        assert_eq!(CentiCelsius(-725).to_string(), "-7.25°C");
+        assert_eq!(CentiCelsius(-5).to_string(), "-0.05°C");
+        assert_eq!(CentiPercent::from_percent_f32(48.3).to_string(), "48.30%");
+        assert!(CentiCelsius(-50) < CentiCelsius(0));
+    }
+
+    #[test]
+    fn unknown_layouts_are_rejected() {
+        let mut bytes = Vec::new();
+        Readings::legacy(400, 27, 800).write(&mut BufferWriter::new(&mut bytes));
+        assert_eq!(bytes[0], READINGS_VERSION);
+        let readings = Readings::read(&mut BufferReader::new(&bytes)).unwrap();
+        assert_eq!(readings, Readings::legacy(400, 27, 800));
+        bytes[0] = 2;
+        assert!(Readings::read(&mut BufferReader::new(&bytes)).is_err());
+    }
+}
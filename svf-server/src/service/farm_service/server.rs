@@ -1,46 +1,236 @@
-use num_enum::TryFromPrimitive;
+use packet_derive::Packet;
 
 use crate::utils::{buffer_reader::BufferReader, buffer_writer::BufferWriter};
 
 use super::{
-    packet::{PacketError, PacketId},
-    ServerPacket,
+    command::DeviceCommand,
+    packet::{PacketError, PacketField, PacketId},
+    settings::DeviceSettings,
 };
 
-#[derive(Clone, Copy, TryFromPrimitive)]
-#[repr(u32)]
-pub enum ServerPacketId {
-    UpdateCooler = 0,
+/// Packets sent to devices, ids follow declaration order so new packets go at the end
+#[derive(Packet, Debug, Clone, PartialEq)]
+#[packet(id = ServerPacketId)]
+pub enum ServerPacket {
+    UpdateCooler {
+        status: bool,
+    },
     WaterPulse,
+    Welcome {
+        protocol_version: u16,
+        capabilities: u32,
+    },
+    Disconnect {
+        reason: String,
+    },
+    AuthChallenge {
+        nonce: [u8; 32],
+    },
+    Authenticated,
+    #[packet(since = 3)]
+    Ping {
+        sequence: u32,
+    },
+    #[packet(since = 3)]
+    Pong {
+        sequence: u32,
+    },
+    #[packet(since = 4)]
+    Command {
+        sequence: u32,
+        command: DeviceCommand,
+    },
+    /// Reply to `UploadStart`, chunks are sent from `offset` and at most `chunk_size` long
+    #[packet(since = 5)]
+    UploadAccepted {
+        upload_id: u32,
+        chunk_size: u16,
+        offset: u32,
+    },
+    /// A chunk was skipped, the device has to continue from `offset`
+    #[packet(since = 5)]
+    UploadResume {
+        upload_id: u32,
+        offset: u32,
+    },
+    #[packet(since = 5)]
+    UploadComplete {
+        upload_id: u32,
+    },
+    #[packet(since = 5)]
+    UploadAborted {
+        upload_id: u32,
+        reason: String,
+    },
+    /// Store and apply new settings, answered with `Ack`/`Nack` like a `Command`
+    #[packet(since = 6)]
+    Configure {
+        sequence: u32,
+        settings: DeviceSettings,
+    },
+    /// Restart after acknowledging
+    #[packet(since = 6)]
+    Reboot {
+        sequence: u32,
+    },
+    /// Drop the stored settings and restart with the firmware defaults after acknowledging
+    #[packet(since = 6)]
+    FactoryReset {
+        sequence: u32,
+    },
+    /// A new firmware image, the device pulls it with `FirmwareRequest` or declines
+    #[packet(since = 7)]
+    FirmwareOffer {
+        update_id: u32,
+        version: String,
+        size: u32,
+        sha256: [u8; 32],
+        chunk_size: u16,
+    },
+    /// Reply to `FirmwareRequest`, at most `chunk_size` bytes of the image from `offset`
+    #[packet(since = 7)]
+    FirmwareChunk {
+        update_id: u32,
+        offset: u32,
+        data: Vec<u8>,
+    },
+    /// Stop downloading and discard the partial image
+    #[packet(since = 7)]
+    FirmwareAbort {
+        update_id: u32,
+        reason: String,
+    },
+    /// Wall-clock time for stamping reports, sent after `Authenticated`, periodically
+    /// and whenever a report shows the device clock is off
+    #[packet(since = 8)]
+    TimeSync {
+        unix_millis: u64,
+    },
+    /// Run the cooler at `duty` out of 255, answered with `Ack`/`Nack` like a `Command`
+    #[packet(since = 11)]
+    SetCoolerDuty {
+        sequence: u32,
+        duty: u8,
+    },
+    /// Run the water pump for `millis`, answered with `Ack`/`Nack` like a `Command`
+    #[packet(since = 12)]
+    RunPump {
+        sequence: u32,
+        millis: u32,
+    },
 }
 
-impl From<&ServerPacket> for ServerPacketId {
-    fn from(value: &ServerPacket) -> Self {
-        match value {
-            ServerPacket::WaterPulse => Self::WaterPulse,
-            ServerPacket::UpdateCooler { .. } => Self::UpdateCooler,
+impl ServerPacket {
+    /// Sequence number of packets the device answers with `Ack`/`Nack`
+    pub fn command_sequence(&self) -> Option<u32> {
+        match self {
+            Self::Command { sequence, .. }
+            | Self::Configure { sequence, .. }
+            | Self::Reboot { sequence }
+            | Self::FactoryReset { sequence }
+            | Self::SetCoolerDuty { sequence, .. }
+            | Self::RunPump { sequence, .. } => Some(*sequence),
+            _ => None,
         }
     }
 }
 
-impl PacketId for ServerPacketId {
-    type Packet = ServerPacket;
+#[cfg(test)]
+mod tests {
+    use proptest::{collection::vec, prelude::*};
 
-    fn decode(&self, _buffer: BufferReader) -> Result<ServerPacket, PacketError> {
-        panic!("Operation not supported on the server")
+    use super::*;
+    use crate::service::farm_service::{codec::wire_round_trip, settings::settings_strategy};
+
+    fn command() -> impl Strategy<Value = DeviceCommand> {
+        prop_oneof![
+            any::<bool>().prop_map(|status| DeviceCommand::Cooler { status }),
+            Just(DeviceCommand::WaterPulse),
+            settings_strategy().prop_map(DeviceCommand::Configure),
+            Just(DeviceCommand::Reboot),
+            Just(DeviceCommand::FactoryReset),
+            any::<u8>().prop_map(|duty| DeviceCommand::CoolerDuty { duty }),
+            any::<u32>().prop_map(|millis| DeviceCommand::Water { millis }),
+        ]
     }
 
-    fn encode(&self, mut buffer: BufferWriter, packet: ServerPacket) {
-        match (self, packet) {
-            (Self::UpdateCooler, ServerPacket::UpdateCooler { status }) => {
-                buffer.write_bool(status);
-            }
-            (Self::WaterPulse, ServerPacket::WaterPulse) => {}
-            _ => panic!("Unmatch packet"),
-        }
+    fn server_packet() -> impl Strategy<Value = ServerPacket> {
+        prop_oneof![
+            any::<bool>().prop_map(|status| ServerPacket::UpdateCooler { status }),
+            Just(ServerPacket::WaterPulse),
+            (any::<u16>(), any::<u32>()).prop_map(|(protocol_version, capabilities)| {
+                ServerPacket::Welcome {
+                    protocol_version,
+                    capabilities,
+                }
+            }),
+            any::<String>().prop_map(|reason| ServerPacket::Disconnect { reason }),
+            any::<[u8; 32]>().prop_map(|nonce| ServerPacket::AuthChallenge { nonce }),
+            Just(ServerPacket::Authenticated),
+            any::<u32>().prop_map(|sequence| ServerPacket::Ping { sequence }),
+            any::<u32>().prop_map(|sequence| ServerPacket::Pong { sequence }),
+            (any::<u32>(), command())
+                .prop_map(|(sequence, command)| ServerPacket::Command { sequence, command }),
+            (any::<u32>(), any::<u16>(), any::<u32>()).prop_map(
+                |(upload_id, chunk_size, offset)| ServerPacket::UploadAccepted {
+                    upload_id,
+                    chunk_size,
+                    offset,
+                }
+            ),
+            (any::<u32>(), any::<u32>())
+                .prop_map(|(upload_id, offset)| ServerPacket::UploadResume { upload_id, offset }),
+            any::<u32>().prop_map(|upload_id| ServerPacket::UploadComplete { upload_id }),
+            (any::<u32>(), any::<String>())
+                .prop_map(|(upload_id, reason)| ServerPacket::UploadAborted { upload_id, reason }),
+            (any::<u32>(), settings_strategy())
+                .prop_map(|(sequence, settings)| ServerPacket::Configure { sequence, settings }),
+            any::<u32>().prop_map(|sequence| ServerPacket::Reboot { sequence }),
+            any::<u32>().prop_map(|sequence| ServerPacket::FactoryReset { sequence }),
+            (
+                any::<u32>(),
+                any::<String>(),
+                any::<u32>(),
+                any::<[u8; 32]>(),
+                any::<u16>()
+            )
+                .prop_map(|(update_id, version, size, sha256, chunk_size)| {
+                    ServerPacket::FirmwareOffer {
+                        update_id,
+                        version,
+                        size,
+                        sha256,
+                        chunk_size,
+                    }
+                }),
+            (any::<u32>(), any::<u32>(), vec(any::<u8>(), 0..512)).prop_map(
+                |(update_id, offset, data)| ServerPacket::FirmwareChunk {
+                    update_id,
+                    offset,
+                    data,
+                }
+            ),
+            (any::<u32>(), any::<String>())
+                .prop_map(|(update_id, reason)| ServerPacket::FirmwareAbort { update_id, reason }),
+            any::<u64>().prop_map(|unix_millis| ServerPacket::TimeSync { unix_millis }),
+            (any::<u32>(), any::<u8>())
+                .prop_map(|(sequence, duty)| ServerPacket::SetCoolerDuty { sequence, duty }),
+            (any::<u32>(), any::<u32>())
+                .prop_map(|(sequence, millis)| ServerPacket::RunPump { sequence, millis }),
+        ]
     }
 
-    fn id(&self) -> u32 {
-        *self as u32
+    proptest! {
+        #[test]
+        fn server_packets_round_trip(packet in server_packet()) {
+            prop_assert_eq!(wire_round_trip::<ServerPacketId>(packet.clone()), packet);
+        }
+
+        #[test]
+        fn commands_keep_their_sequence(sequence in any::<u32>(), command in command()) {
+            let packet = command.packet(sequence);
+            prop_assert_eq!(packet.command_sequence(), Some(sequence));
+            prop_assert_eq!(command.legacy_packet().is_some(), matches!(packet, ServerPacket::Command { .. }));
+        }
     }
 }
@@ -0,0 +1,94 @@
+use std::{
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+use super::{
+    auth::NONCE_LENGTH, clock::ClockConfig, handshake::Handshake, telemetry::SensorRecord,
+    upload::UploadConfig,
+};
+
+/// Where a device connection is in its lifetime
+///
+/// ```text
+/// Connecting --Hello--> Identified --AuthResponse--> Active
+///      \                    |                          |
+///       `-------------------+--------------------------+--> Closing
+/// ```
+pub enum SessionState {
+    /// Waiting for the device to send `Hello`
+    Connecting,
+    /// Protocol agreed, the device still has to prove which farm it belongs to
+    Identified {
+        handshake: Handshake,
+        challenge: Option<Box<Challenge>>,
+    },
+    /// Authenticated and registered with the farm service
+    Active {
+        handshake: Handshake,
+        id: Box<[char; 64]>,
+    },
+    /// The connection is being torn down
+    Closing,
+}
+
+/// An outstanding authentication challenge sent to a device
+pub struct Challenge {
+    pub id: [char; 64],
+    pub secret: [char; 64],
+    pub nonce: [u8; NONCE_LENGTH],
+}
+
+impl SessionState {
+    pub fn handshake(&self) -> Option<&Handshake> {
+        match self {
+            Self::Identified { handshake, .. } | Self::Active { handshake, .. } => Some(handshake),
+            Self::Connecting | Self::Closing => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Connecting => "connecting",
+            Self::Identified { .. } => "identified",
+            Self::Active { .. } => "active",
+            Self::Closing => "closing",
+        }
+    }
+}
+
+/// Sensor values waiting for the image they were captured with
+pub struct PendingReport {
+    pub records: Vec<SensorRecord>,
+    pub image_size: usize,
+    /// Capture time stamped by the device, or the arrival time for untimed reports
+    pub captured_at: SystemTime,
+}
+
+/// Settings applied to every device session
+#[derive(Debug, Clone)]
+pub struct SessionConfig {
+    /// How often the server pings devices that support `Ping`
+    pub heartbeat_interval: Duration,
+    /// How long a device may stay silent before its session is closed
+    pub idle_timeout: Duration,
+    /// How long a device gets to finish the TLS handshake
+    pub handshake_timeout: Duration,
+    pub upload: UploadConfig,
+    pub clock: ClockConfig,
+    /// Record every frame of every session to a capture file in this directory
+    pub capture_dir: Option<PathBuf>,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            heartbeat_interval: Duration::from_secs(15),
+            idle_timeout: Duration::from_secs(60),
+            handshake_timeout: Duration::from_secs(10),
+            upload: UploadConfig::default(),
+            clock: ClockConfig::default(),
+            capture_dir: None,
+        }
+    }
+}
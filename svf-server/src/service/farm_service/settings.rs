@@ -0,0 +1,197 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::utils::{buffer_reader::BufferReader, buffer_writer::BufferWriter};
+
+use super::packet::{PacketError, PacketField};
+
+/// Longest server address a device stores, including the port
+const MAX_SERVER_ADDRESS_LENGTH: usize = 128;
+/// Longest the firmware runs the pump for with one command, also bounds `pump_pulse`
+pub const MAX_PUMP_PULSE: Duration = Duration::from_secs(60);
+
+/// Frame sizes of the OV2640 camera on the controller
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CameraResolution {
+    /// 320x240
+    Qvga,
+    /// 640x480
+    Vga,
+    /// 800x600
+    Svga,
+    /// 1024x768
+    Xga,
+    /// 1280x1024
+    Sxga,
+    /// 1600x1200
+    Uxga,
+}
+
+impl PacketField for CameraResolution {
+    fn read(buffer: &mut BufferReader) -> Result<Self, PacketError> {
+        match u8::read(buffer)? {
+            0 => Ok(Self::Qvga),
+            1 => Ok(Self::Vga),
+            2 => Ok(Self::Svga),
+            3 => Ok(Self::Xga),
+            4 => Ok(Self::Sxga),
+            5 => Ok(Self::Uxga),
+            _ => Err(PacketError::InvalidPacketId),
+        }
+    }
+
+    fn write(&self, buffer: &mut BufferWriter) {
+        buffer.write_u8(*self as u8);
+    }
+}
+
+/// Everything about a device that used to be compiled into the firmware
+///
+/// Devices report their settings with `ReportConfig` after authenticating and
+/// are sent [`super::ServerPacket::Configure`] whenever they differ from the
+/// desired settings stored for them.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceSettings {
+    /// Seconds between sensor reports
+    pub sampling_interval: u32,
+    /// JPEG quality from 0 (best) to 63 (smallest)
+    pub camera_quality: u8,
+    pub camera_resolution: CameraResolution,
+    /// Milliseconds the pump runs for one water pulse
+    pub pump_pulse: u32,
+    /// `host:port` of the device listener
+    pub server_address: String,
+}
+
+impl PacketField for DeviceSettings {
+    fn read(buffer: &mut BufferReader) -> Result<Self, PacketError> {
+        Ok(Self {
+            sampling_interval: u32::read(buffer)?,
+            camera_quality: u8::read(buffer)?,
+            camera_resolution: CameraResolution::read(buffer)?,
+            pump_pulse: u32::read(buffer)?,
+            server_address: String::read(buffer)?,
+        })
+    }
+
+    fn write(&self, buffer: &mut BufferWriter) {
+        self.sampling_interval.write(buffer);
+        self.camera_quality.write(buffer);
+        self.camera_resolution.write(buffer);
+        self.pump_pulse.write(buffer);
+        self.server_address.write(buffer);
+    }
+}
+
+impl DeviceSettings {
+    /// Reject settings that would leave a device unreachable or stuck
+    pub fn validate(&self) -> Result<(), String> {
+        if self.sampling_interval == 0 {
+            return Err("The sampling interval must be at least 1 second".to_string());
+        }
+        if self.camera_quality > 63 {
+            return Err("The camera quality goes from 0 to 63".to_string());
+        }
+        if Duration::from_millis(self.pump_pulse.into()) > MAX_PUMP_PULSE {
+            return Err("A pump pulse can last at most 60 seconds".to_string());
+        }
+        let valid_address = self
+            .server_address
+            .rsplit_once(':')
+            .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok());
+        if !valid_address || self.server_address.len() > MAX_SERVER_ADDRESS_LENGTH {
+            return Err(format!(
+                "The server address must be host:port and at most {MAX_SERVER_ADDRESS_LENGTH} bytes"
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub fn settings_strategy() -> impl proptest::strategy::Strategy<Value = DeviceSettings> {
+    use proptest::prelude::*;
+
+    let resolution = prop_oneof![
+        Just(CameraResolution::Qvga),
+        Just(CameraResolution::Vga),
+        Just(CameraResolution::Svga),
+        Just(CameraResolution::Xga),
+        Just(CameraResolution::Sxga),
+        Just(CameraResolution::Uxga),
+    ];
+    (
+        any::<u32>(),
+        any::<u8>(),
+        resolution,
+        any::<u32>(),
+        any::<String>(),
+    )
+        .prop_map(
+            |(sampling_interval, camera_quality, camera_resolution, pump_pulse, server_address)| {
+                DeviceSettings {
+                    sampling_interval,
+                    camera_quality,
+                    camera_resolution,
+                    pump_pulse,
+                    server_address,
+                }
+            },
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> DeviceSettings {
+        DeviceSettings {
+            sampling_interval: 30,
+            camera_quality: 12,
+            camera_resolution: CameraResolution::Svga,
+            pump_pulse: 1500,
+            server_address: "farm.example.com:4000".to_string(),
+        }
+    }
+
+    #[test]
+    fn validation() {
+        assert!(settings().validate().is_ok());
+        for invalid in [
+            DeviceSettings {
+                sampling_interval: 0,
+                ..settings()
+            },
+            DeviceSettings {
+                camera_quality: 64,
+                ..settings()
+            },
+            DeviceSettings {
+                pump_pulse: 60_001,
+                ..settings()
+            },
+            DeviceSettings {
+                server_address: "farm.example.com".to_string(),
+                ..settings()
+            },
+            DeviceSettings {
+                server_address: ":4000".to_string(),
+                ..settings()
+            },
+        ] {
+            assert!(invalid.validate().is_err(), "{invalid:?}");
+        }
+    }
+
+    #[test]
+    fn stored_as_json() {
+        let json = serde_json::to_string(&settings()).unwrap();
+        assert!(json.contains(r#""camera_resolution":"svga""#));
+        assert_eq!(
+            serde_json::from_str::<DeviceSettings>(&json).unwrap(),
+            settings()
+        );
+    }
+}
@@ -0,0 +1,554 @@
+use std::{collections::HashMap, fmt};
+
+use serde::{Deserialize, Serialize};
+
+use crate::utils::{buffer_reader::BufferReader, buffer_writer::BufferWriter};
+
+use super::{
+    packet::{PacketError, PacketField},
+    sensors::Readings,
+};
+
+/// Most records a single `ReportTelemetry` may carry
+pub const MAX_RECORDS: usize = 64;
+
+/// What a telemetry value measures, ids from `0x8000` are free for site specific sensors
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct SensorType(pub u16);
+
+impl SensorType {
+    pub const AIR_TEMPERATURE: Self = Self(1);
+    pub const HUMIDITY: Self = Self(2);
+    pub const SOIL_MOISTURE: Self = Self(3);
+    pub const LIGHT: Self = Self(4);
+    pub const CO2: Self = Self(5);
+    /// Electrical conductivity of the nutrient solution
+    pub const EC: Self = Self(6);
+    pub const PH: Self = Self(7);
+    pub const WATER_LEVEL: Self = Self(8);
+    /// First id that will never be given to a built-in sensor
+    pub const CUSTOM: Self = Self(0x8000);
+}
+
+impl fmt::Display for SensorType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#06x}", self.0)
+    }
+}
+
+/// Unit of a telemetry value, fractional units are sent as scaled integers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Unit {
+    /// Straight from the ADC, meaningful only for one kind of probe
+    Raw,
+    CentiCelsius,
+    CentiPercent,
+    Lux,
+    Ppm,
+    MicroSiemensPerCm,
+    CentiPh,
+    Millimetre,
+}
+
+impl Unit {
+    /// The name used in the config file and the database
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Raw => "raw",
+            Self::CentiCelsius => "centi_celsius",
+            Self::CentiPercent => "centi_percent",
+            Self::Lux => "lux",
+            Self::Ppm => "ppm",
+            Self::MicroSiemensPerCm => "micro_siemens_per_cm",
+            Self::CentiPh => "centi_ph",
+            Self::Millimetre => "millimetre",
+        }
+    }
+}
+
+impl PacketField for Unit {
+    fn read(buffer: &mut BufferReader) -> Result<Self, PacketError> {
+        match u8::read(buffer)? {
+            0 => Ok(Self::Raw),
+            1 => Ok(Self::CentiCelsius),
+            2 => Ok(Self::CentiPercent),
+            3 => Ok(Self::Lux),
+            4 => Ok(Self::Ppm),
+            5 => Ok(Self::MicroSiemensPerCm),
+            6 => Ok(Self::CentiPh),
+            7 => Ok(Self::Millimetre),
+            _ => Err(PacketError::InvalidPacketId),
+        }
+    }
+
+    fn write(&self, buffer: &mut BufferWriter) {
+        buffer.write_u8(*self as u8);
+    }
+}
+
+/// One value of a telemetry report
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SensorRecord {
+    pub sensor: SensorType,
+    /// Which probe of that type, counted from 0
+    pub index: u8,
+    pub unit: Unit,
+    pub value: i32,
+}
+
+/// Sensor type, index and unit, then the value as a length prefixed little endian
+/// signed integer of 1, 2 or 4 bytes
+impl PacketField for SensorRecord {
+    fn read(buffer: &mut BufferReader) -> Result<Self, PacketError> {
+        let sensor = SensorType(u16::read(buffer)?);
+        let index = u8::read(buffer)?;
+        let unit = Unit::read(buffer)?;
+        let value = match u8::read(buffer)? {
+            1 => i8::read(buffer)?.into(),
+            2 => i16::read(buffer)?.into(),
+            4 => i32::read(buffer)?,
+            _ => return Err(PacketError::InvalidPacketLength),
+        };
+        Ok(Self {
+            sensor,
+            index,
+            unit,
+            value,
+        })
+    }
+
+    fn write(&self, buffer: &mut BufferWriter) {
+        buffer
+            .write_u16(self.sensor.0)
+            .write_u8(self.index)
+            .write_u8(self.unit as u8);
+        if let Ok(value) = i8::try_from(self.value) {
+            buffer.write_u8(1).write_i8(value);
+        } else if let Ok(value) = i16::try_from(self.value) {
+            buffer.write_u8(2).write_i16(value);
+        } else {
+            buffer.write_u8(4).write_i32(self.value);
+        }
+    }
+}
+
+/// A `u8` count followed by the records
+impl PacketField for Vec<SensorRecord> {
+    fn read(buffer: &mut BufferReader) -> Result<Self, PacketError> {
+        let count = usize::from(u8::read(buffer)?);
+        if count > MAX_RECORDS {
+            return Err(PacketError::InvalidPacketLength);
+        }
+        (0..count).map(|_| SensorRecord::read(buffer)).collect()
+    }
+
+    fn write(&self, buffer: &mut BufferWriter) {
+        let count = self.len().min(MAX_RECORDS);
+        buffer.write_u8(count as u8);
+        for record in &self[..count] {
+            record.write(buffer);
+        }
+    }
+}
+
+/// Look up the value of one probe in one unit
+pub fn find(records: &[SensorRecord], sensor: SensorType, index: u8, unit: Unit) -> Option<i32> {
+    records
+        .iter()
+        .find(|record| record.sensor == sensor && record.index == index && record.unit == unit)
+        .map(|record| record.value)
+}
+
+impl Readings {
+    /// The readings as telemetry records of the first probe of each sensor
+    pub fn records(&self) -> Vec<SensorRecord> {
+        let record = |sensor, unit, value| SensorRecord {
+            sensor,
+            index: 0,
+            unit,
+            value,
+        };
+        let mut records = vec![
+            record(
+                SensorType::AIR_TEMPERATURE,
+                Unit::CentiCelsius,
+                self.air_temperature.0.into(),
+            ),
+            record(
+                SensorType::SOIL_MOISTURE,
+                Unit::Raw,
+                self.soil_moisture.raw.into(),
+            ),
+            record(SensorType::LIGHT, Unit::Raw, self.light.raw.into()),
+        ];
+        if let Some(humidity) = self.humidity {
+            records.push(record(
+                SensorType::HUMIDITY,
+                Unit::CentiPercent,
+                humidity.0.into(),
+            ));
+        }
+        if let Some(soil_moisture) = self.soil_moisture.value {
+            records.push(record(
+                SensorType::SOIL_MOISTURE,
+                Unit::CentiPercent,
+                soil_moisture.0.into(),
+            ));
+        }
+        if let Some(lux) = self.light.value {
+            records.push(record(
+                SensorType::LIGHT,
+                Unit::Lux,
+                lux.try_into().unwrap_or(i32::MAX),
+            ));
+        }
+        records
+    }
+}
+
+fn one() -> u8 {
+    1
+}
+
+/// A value the server accepts for one sensor type in one unit
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SensorDefinition {
+    pub id: u16,
+    /// Stored with every reading, the same for every unit of a sensor type
+    pub name: String,
+    pub unit: Unit,
+    pub min: i32,
+    pub max: i32,
+    /// How many probes of this type a device may have
+    #[serde(default = "one")]
+    pub probes: u8,
+}
+
+/// Why a telemetry record was dropped
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecordError {
+    UnknownSensor(SensorType),
+    UnsupportedUnit { sensor: String, unit: Unit },
+    UnknownProbe { sensor: String, index: u8 },
+    OutOfRange { sensor: String, value: i32 },
+}
+
+impl fmt::Display for RecordError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownSensor(sensor) => write!(f, "Unknown sensor type {sensor}"),
+            Self::UnsupportedUnit { sensor, unit } => {
+                write!(f, "{sensor} isn't measured in {}", unit.name())
+            }
+            Self::UnknownProbe { sensor, index } => write!(f, "No {sensor} probe {index}"),
+            Self::OutOfRange { sensor, value } => write!(f, "{sensor} {value} is out of range"),
+        }
+    }
+}
+
+/// A validated record, ready to be stored
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredReading {
+    pub sensor: String,
+    pub index: u8,
+    pub unit: Unit,
+    pub value: i32,
+}
+
+/// Every sensor type the server knows how to validate and store
+#[derive(Debug, Clone)]
+pub struct SensorRegistry {
+    definitions: HashMap<(SensorType, Unit), SensorDefinition>,
+}
+
+impl Default for SensorRegistry {
+    fn default() -> Self {
+        let builtin = |sensor: SensorType, name: &str, unit, min, max, probes| SensorDefinition {
+            id: sensor.0,
+            name: name.to_string(),
+            unit,
+            min,
+            max,
+            probes,
+        };
+        let mut registry = Self {
+            definitions: HashMap::new(),
+        };
+        for definition in [
+            builtin(
+                SensorType::AIR_TEMPERATURE,
+                "air_temperature",
+                Unit::CentiCelsius,
+                -4000,
+                8000,
+                4,
+            ),
+            builtin(
+                SensorType::HUMIDITY,
+                "humidity",
+                Unit::CentiPercent,
+                0,
+                10000,
+                4,
+            ),
+            builtin(
+                SensorType::SOIL_MOISTURE,
+                "soil_moisture",
+                Unit::Raw,
+                0,
+                1023,
+                8,
+            ),
+            builtin(
+                SensorType::SOIL_MOISTURE,
+                "soil_moisture",
+                Unit::CentiPercent,
+                0,
+                10000,
+                8,
+            ),
+            builtin(SensorType::LIGHT, "light", Unit::Raw, 0, 1023, 4),
+            builtin(SensorType::LIGHT, "light", Unit::Lux, 0, 200_000, 4),
+            builtin(SensorType::CO2, "co2", Unit::Ppm, 0, 10_000, 2),
+            builtin(SensorType::EC, "ec", Unit::MicroSiemensPerCm, 0, 20_000, 2),
+            builtin(SensorType::PH, "ph", Unit::CentiPh, 0, 1400, 2),
+            builtin(
+                SensorType::WATER_LEVEL,
+                "water_level",
+                Unit::Millimetre,
+                0,
+                10_000,
+                2,
+            ),
+        ] {
+            registry.register(definition).unwrap();
+        }
+        registry
+    }
+}
+
+impl SensorRegistry {
+    /// The built-in sensors and `custom`, which must not clash with them or each other
+    pub fn new(custom: &[SensorDefinition]) -> Result<Self, String> {
+        let mut registry = Self::default();
+        for definition in custom {
+            if definition.id < SensorType::CUSTOM.0 {
+                return Err(format!(
+                    "Sensor {} must have an id of at least {}",
+                    definition.name,
+                    SensorType::CUSTOM.0
+                ));
+            }
+            registry.register(definition.clone())?;
+        }
+        Ok(registry)
+    }
+
+    fn register(&mut self, definition: SensorDefinition) -> Result<(), String> {
+        let sensor = SensorType(definition.id);
+        if definition.name.is_empty() || definition.min > definition.max || definition.probes == 0 {
+            return Err(format!(
+                "Sensor {sensor} needs a name, at least one probe and min <= max"
+            ));
+        }
+        let clash = self.definitions.values().any(|other| {
+            (other.id == definition.id) != (other.name == definition.name)
+                || (other.id == definition.id && other.unit == definition.unit)
+        });
+        if clash {
+            return Err(format!(
+                "Sensor {} {sensor} clashes with another sensor",
+                definition.name
+            ));
+        }
+        self.definitions
+            .insert((sensor, definition.unit), definition);
+        Ok(())
+    }
+
+    pub fn check(&self, record: &SensorRecord) -> Result<StoredReading, RecordError> {
+        let definition = match self.definitions.get(&(record.sensor, record.unit)) {
+            Some(definition) => definition,
+            None => {
+                return Err(match self.name(record.sensor) {
+                    Some(sensor) => RecordError::UnsupportedUnit {
+                        sensor: sensor.to_string(),
+                        unit: record.unit,
+                    },
+                    None => RecordError::UnknownSensor(record.sensor),
+                })
+            }
+        };
+        if record.index >= definition.probes {
+            return Err(RecordError::UnknownProbe {
+                sensor: definition.name.clone(),
+                index: record.index,
+            });
+        }
+        if !(definition.min..=definition.max).contains(&record.value) {
+            return Err(RecordError::OutOfRange {
+                sensor: definition.name.clone(),
+                value: record.value,
+            });
+        }
+        Ok(StoredReading {
+            sensor: definition.name.clone(),
+            index: record.index,
+            unit: record.unit,
+            value: record.value,
+        })
+    }
+
+    pub fn name(&self, sensor: SensorType) -> Option<&str> {
+        self.definitions
+            .values()
+            .find(|definition| definition.id == sensor.0)
+            .map(|definition| definition.name.as_str())
+    }
+}
+
+#[cfg(test)]
+pub fn records_strategy() -> impl proptest::strategy::Strategy<Value = Vec<SensorRecord>> {
+    use proptest::{collection::vec, prelude::*};
+
+    let unit = prop_oneof![
+        Just(Unit::Raw),
+        Just(Unit::CentiCelsius),
+        Just(Unit::CentiPercent),
+        Just(Unit::Lux),
+        Just(Unit::Ppm),
+        Just(Unit::MicroSiemensPerCm),
+        Just(Unit::CentiPh),
+        Just(Unit::Millimetre),
+    ];
+    vec(
+        (any::<u16>(), any::<u8>(), unit, any::<i32>()).prop_map(|(sensor, index, unit, value)| {
+            SensorRecord {
+                sensor: SensorType(sensor),
+                index,
+                unit,
+                value,
+            }
+        }),
+        0..=MAX_RECORDS,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::farm_service::sensors::{CentiCelsius, CentiPercent};
+
+    fn record(sensor: SensorType, index: u8, unit: Unit, value: i32) -> SensorRecord {
+        SensorRecord {
+            sensor,
+            index,
+            unit,
+            value,
+        }
+    }
+
+    #[test]
+    fn values_use_the_shortest_encoding() {
+        let mut bytes = Vec::new();
+        vec![
+            record(SensorType::PH, 0, Unit::CentiPh, 65),
+            record(SensorType::CO2, 1, Unit::Ppm, 812),
+            record(SensorType::LIGHT, 0, Unit::Lux, 120_000),
+        ]
+        .write(&mut BufferWriter::new(&mut bytes));
+        // Count, then 5 header bytes per record
+        assert_eq!(bytes.len(), 1 + 5 + 1 + 5 + 2 + 5 + 4);
+
+        bytes[1 + 4] = 3;
+        assert!(Vec::<SensorRecord>::read(&mut BufferReader::new(&bytes)).is_err());
+    }
+
+    #[test]
+    fn registry_checks_type_unit_probe_and_range() {
+        let registry = SensorRegistry::default();
+        let stored = registry
+            .check(&record(
+                SensorType::SOIL_MOISTURE,
+                3,
+                Unit::CentiPercent,
+                4200,
+            ))
+            .unwrap();
+        assert_eq!(stored.sensor, "soil_moisture");
+        assert_eq!(
+            registry.check(&record(SensorType(0x9000), 0, Unit::Raw, 1)),
+            Err(RecordError::UnknownSensor(SensorType(0x9000)))
+        );
+        assert!(matches!(
+            registry.check(&record(SensorType::PH, 0, Unit::Ppm, 7)),
+            Err(RecordError::UnsupportedUnit { .. })
+        ));
+        assert!(matches!(
+            registry.check(&record(SensorType::CO2, 2, Unit::Ppm, 400)),
+            Err(RecordError::UnknownProbe { .. })
+        ));
+        assert!(matches!(
+            registry.check(&record(SensorType::PH, 0, Unit::CentiPh, 1500)),
+            Err(RecordError::OutOfRange { .. })
+        ));
+    }
+
+    #[test]
+    fn custom_sensors_get_their_own_ids() {
+        let tank = SensorDefinition {
+            id: 0x8001,
+            name: "tank_pressure".to_string(),
+            unit: Unit::Raw,
+            min: 0,
+            max: 4095,
+            probes: 1,
+        };
+        let registry = SensorRegistry::new(std::slice::from_ref(&tank)).unwrap();
+        assert!(registry
+            .check(&record(SensorType(0x8001), 0, Unit::Raw, 4000))
+            .is_ok());
+        for invalid in [
+            SensorDefinition {
+                id: 9,
+                ..tank.clone()
+            },
+            SensorDefinition {
+                name: "ph".to_string(),
+                ..tank.clone()
+            },
+            SensorDefinition {
+                min: 10,
+                max: 0,
+                ..tank.clone()
+            },
+        ] {
+            assert!(SensorRegistry::new(&[invalid]).is_err());
+        }
+        assert!(SensorRegistry::new(&[tank.clone(), tank]).is_err());
+    }
+
+    #[test]
+    fn readings_become_records() {
+        let readings = Readings {
+            air_temperature: CentiCelsius(-350),
+            humidity: Some(CentiPercent(8000)),
+            ..Readings::legacy(610, 0, 300)
+        };
+        let records = readings.records();
+        assert_eq!(
+            find(&records, SensorType::AIR_TEMPERATURE, 0, Unit::CentiCelsius),
+            Some(-350)
+        );
+        assert_eq!(
+            find(&records, SensorType::SOIL_MOISTURE, 0, Unit::Raw),
+            Some(610)
+        );
+        assert_eq!(find(&records, SensorType::LIGHT, 0, Unit::Lux), None);
+        let registry = SensorRegistry::default();
+        assert!(records.iter().all(|record| registry.check(record).is_ok()));
+    }
+}
@@ -0,0 +1,477 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::utils::{buffer_reader::BufferReader, buffer_writer::BufferWriter};
+
+use super::{
+    packet::{PacketError, PacketField},
+    session::PendingReport,
+};
+
+/// Checksum of a whole image, announced by the device in `UploadStart`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageChecksum {
+    Crc32(u32),
+    Sha256([u8; 32]),
+}
+
+impl ImageChecksum {
+    pub fn of_crc32(data: &[u8]) -> Self {
+        Self::Crc32(crc32fast::hash(data))
+    }
+
+    pub fn of_sha256(data: &[u8]) -> Self {
+        Self::Sha256(Sha256::digest(data).into())
+    }
+
+    pub fn matches(&self, data: &[u8]) -> bool {
+        match self {
+            Self::Crc32(_) => Self::of_crc32(data) == *self,
+            Self::Sha256(_) => Self::of_sha256(data) == *self,
+        }
+    }
+}
+
+/// A `u8` algorithm followed by the checksum itself
+impl PacketField for ImageChecksum {
+    fn read(buffer: &mut BufferReader) -> Result<Self, PacketError> {
+        match u8::read(buffer)? {
+            0 => Ok(Self::Crc32(u32::read(buffer)?)),
+            1 => Ok(Self::Sha256(<[u8; 32]>::read(buffer)?)),
+            _ => Err(PacketError::InvalidPacketId),
+        }
+    }
+
+    fn write(&self, buffer: &mut BufferWriter) {
+        match self {
+            Self::Crc32(crc) => buffer.write_u8(0).write_u32(*crc),
+            Self::Sha256(digest) => buffer.write_u8(1).write_bytes(digest),
+        };
+    }
+}
+
+/// Limits applied to chunked image uploads
+#[derive(Debug, Clone, Copy)]
+pub struct UploadConfig {
+    /// Largest chunk the server agrees to, devices asking for more get this
+    pub max_chunk_size: u16,
+    pub max_image_size: u32,
+    /// Uploads without a new chunk for this long are aborted
+    pub stall_timeout: Duration,
+    /// How long an upload interrupted by a disconnect is kept for the device to resume
+    pub resume_timeout: Duration,
+}
+
+impl Default for UploadConfig {
+    fn default() -> Self {
+        Self {
+            max_chunk_size: 4096,
+            max_image_size: 2 * 1024 * 1024,
+            stall_timeout: Duration::from_secs(30),
+            resume_timeout: Duration::from_secs(10 * 60),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum UploadError {
+    Empty,
+    TooLarge { size: u32, max_size: u32 },
+    ChunkTooLarge { length: usize, chunk_size: u16 },
+    Overflow { end: u64, total_size: u32 },
+    ChecksumMismatch,
+    UnknownUpload,
+    Stalled,
+}
+
+impl Display for UploadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Empty => write!(f, "Image is empty"),
+            Self::TooLarge { size, max_size } => {
+                write!(f, "Image of {size} bytes is larger than {max_size} bytes")
+            }
+            Self::ChunkTooLarge { length, chunk_size } => write!(
+                f,
+                "Chunk of {length} bytes exceeds the negotiated {chunk_size} bytes"
+            ),
+            Self::Overflow { end, total_size } => {
+                write!(f, "Chunk ends at {end}, past the image size {total_size}")
+            }
+            Self::ChecksumMismatch => write!(f, "Image checksum mismatch"),
+            Self::UnknownUpload => write!(f, "No such upload in progress"),
+            Self::Stalled => write!(f, "Upload stalled"),
+        }
+    }
+}
+
+/// What happened to a chunk handed to [`Upload::accept`]
+#[derive(Debug, PartialEq, Eq)]
+pub enum ChunkOutcome {
+    Accepted,
+    /// Every byte of the chunk was already received, it has been ignored
+    Duplicate,
+    /// The chunk starts past the received data, the device has to resend from `expected`
+    OutOfOrder {
+        expected: u32,
+    },
+    /// The last chunk arrived and the checksum matches
+    Complete(Vec<u8>),
+}
+
+/// An image being received in chunks, each addressed by its byte offset
+#[derive(Debug)]
+pub struct Upload {
+    id: u32,
+    total_size: u32,
+    chunk_size: u16,
+    checksum: ImageChecksum,
+    data: Vec<u8>,
+    last_activity: Instant,
+}
+
+impl Upload {
+    /// Start an upload, the chunk size is capped by the server limit
+    pub fn start(
+        id: u32,
+        total_size: u32,
+        requested_chunk_size: u16,
+        checksum: ImageChecksum,
+        config: &UploadConfig,
+        now: Instant,
+    ) -> Result<Self, UploadError> {
+        // No chunk could ever complete it
+        if total_size == 0 {
+            return Err(UploadError::Empty);
+        }
+        if total_size > config.max_image_size {
+            return Err(UploadError::TooLarge {
+                size: total_size,
+                max_size: config.max_image_size,
+            });
+        }
+        let chunk_size = match requested_chunk_size {
+            0 => config.max_chunk_size,
+            size => size.min(config.max_chunk_size),
+        };
+        Ok(Self {
+            id,
+            total_size,
+            chunk_size,
+            checksum,
+            data: Vec::with_capacity(total_size as usize),
+            last_activity: now,
+        })
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn chunk_size(&self) -> u16 {
+        self.chunk_size
+    }
+
+    /// Number of contiguous bytes received so far
+    pub fn offset(&self) -> u32 {
+        self.data.len() as u32
+    }
+
+    /// Whether a repeated `UploadStart` describes this same image
+    pub fn is_same_image(&self, total_size: u32, checksum: ImageChecksum) -> bool {
+        self.total_size == total_size && self.checksum == checksum
+    }
+
+    /// Restart the stall timer, used when an upload is resumed
+    pub fn touch(&mut self, now: Instant) {
+        self.last_activity = now;
+    }
+
+    pub fn is_stalled(&self, now: Instant, config: &UploadConfig) -> bool {
+        now.duration_since(self.last_activity) >= config.stall_timeout
+    }
+
+    pub fn accept(
+        &mut self,
+        offset: u32,
+        chunk: &[u8],
+        now: Instant,
+    ) -> Result<ChunkOutcome, UploadError> {
+        if chunk.len() > self.chunk_size as usize {
+            return Err(UploadError::ChunkTooLarge {
+                length: chunk.len(),
+                chunk_size: self.chunk_size,
+            });
+        }
+        let end = offset as u64 + chunk.len() as u64;
+        if end > self.total_size as u64 {
+            return Err(UploadError::Overflow {
+                end,
+                total_size: self.total_size,
+            });
+        }
+        let received = self.offset();
+        if offset > received {
+            return Ok(ChunkOutcome::OutOfOrder { expected: received });
+        }
+        if end <= received as u64 {
+            return Ok(ChunkOutcome::Duplicate);
+        }
+        // A resent chunk may overlap what we already have, keep only the new tail
+        self.data
+            .extend_from_slice(&chunk[(received - offset) as usize..]);
+        self.last_activity = now;
+        if self.offset() < self.total_size {
+            return Ok(ChunkOutcome::Accepted);
+        }
+        if !self.checksum.matches(&self.data) {
+            return Err(UploadError::ChecksumMismatch);
+        }
+        Ok(ChunkOutcome::Complete(std::mem::take(&mut self.data)))
+    }
+}
+
+/// Device id and upload id
+type UploadKey = ([char; 64], u32);
+
+struct ParkedUpload {
+    upload: Upload,
+    report: PendingReport,
+    parked_at: Instant,
+}
+
+/// Uploads interrupted by a disconnect, keyed by device and upload id
+///
+/// Shared by every connection so a device can pick up where it left off
+/// regardless of which connection the upload started on.
+#[derive(Clone)]
+pub struct UploadStore {
+    resume_timeout: Duration,
+    parked: Arc<Mutex<HashMap<UploadKey, ParkedUpload>>>,
+}
+
+impl UploadStore {
+    pub fn new(config: &UploadConfig) -> Self {
+        Self {
+            resume_timeout: config.resume_timeout,
+            parked: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn park(&self, device: [char; 64], upload: Upload, report: PendingReport, now: Instant) {
+        let mut parked = self.parked.lock().unwrap();
+        parked.retain(|_, e| now.duration_since(e.parked_at) < self.resume_timeout);
+        parked.insert(
+            (device, upload.id()),
+            ParkedUpload {
+                upload,
+                report,
+                parked_at: now,
+            },
+        );
+    }
+
+    pub fn take(
+        &self,
+        device: [char; 64],
+        upload_id: u32,
+        now: Instant,
+    ) -> Option<(Upload, PendingReport)> {
+        let parked = self.parked.lock().unwrap().remove(&(device, upload_id))?;
+        if now.duration_since(parked.parked_at) >= self.resume_timeout {
+            return None;
+        }
+        Some((parked.upload, parked.report))
+    }
+
+    /// The most recently interrupted upload of a device
+    pub fn take_latest(&self, device: [char; 64], now: Instant) -> Option<(Upload, PendingReport)> {
+        let upload_id = self
+            .parked
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|((id, _), _)| *id == device)
+            .max_by_key(|(_, parked)| parked.parked_at)
+            .map(|((_, upload_id), _)| *upload_id)?;
+        self.take(device, upload_id, now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::farm_service::sensors::Readings;
+
+    fn image() -> Vec<u8> {
+        (0..1000u32).map(|i| (i * 7) as u8).collect()
+    }
+
+    fn start(image: &[u8], checksum: ImageChecksum) -> Upload {
+        let config = UploadConfig {
+            max_chunk_size: 256,
+            ..Default::default()
+        };
+        Upload::start(
+            1,
+            image.len() as u32,
+            1024,
+            checksum,
+            &config,
+            Instant::now(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn empty_uploads_are_rejected() {
+        let upload = Upload::start(
+            1,
+            0,
+            256,
+            ImageChecksum::of_crc32(&[]),
+            &UploadConfig::default(),
+            Instant::now(),
+        );
+        assert_eq!(upload.unwrap_err(), UploadError::Empty);
+    }
+
+    #[test]
+    fn chunk_size_is_negotiated_down() {
+        let image = image();
+        assert_eq!(
+            start(&image, ImageChecksum::of_crc32(&image)).chunk_size(),
+            256
+        );
+    }
+
+    #[test]
+    fn completes_with_matching_checksums() {
+        let image = image();
+        for checksum in [
+            ImageChecksum::of_crc32(&image),
+            ImageChecksum::of_sha256(&image),
+        ] {
+            let mut upload = start(&image, checksum);
+            let now = Instant::now();
+            let mut result = None;
+            for (index, chunk) in image.chunks(256).enumerate() {
+                result = Some(upload.accept(index as u32 * 256, chunk, now).unwrap());
+            }
+            assert_eq!(result, Some(ChunkOutcome::Complete(image.clone())));
+        }
+    }
+
+    #[test]
+    fn detects_duplicates_and_gaps() {
+        let image = image();
+        let mut upload = start(&image, ImageChecksum::of_crc32(&image));
+        let now = Instant::now();
+        assert_eq!(
+            upload.accept(0, &image[..256], now),
+            Ok(ChunkOutcome::Accepted)
+        );
+        assert_eq!(
+            upload.accept(0, &image[..256], now),
+            Ok(ChunkOutcome::Duplicate)
+        );
+        assert_eq!(
+            upload.accept(512, &image[512..768], now),
+            Ok(ChunkOutcome::OutOfOrder { expected: 256 })
+        );
+        // Overlapping resend only appends the missing tail
+        assert_eq!(
+            upload.accept(128, &image[128..384], now),
+            Ok(ChunkOutcome::Accepted)
+        );
+        assert_eq!(upload.offset(), 384);
+    }
+
+    #[test]
+    fn rejects_corrupted_image() {
+        let image = image();
+        let mut upload = start(&image, ImageChecksum::of_sha256(&image));
+        let mut corrupted = image.clone();
+        corrupted[999] ^= 1;
+        let now = Instant::now();
+        let mut result = Ok(ChunkOutcome::Accepted);
+        for (index, chunk) in corrupted.chunks(256).enumerate() {
+            result = upload.accept(index as u32 * 256, chunk, now);
+        }
+        assert_eq!(result, Err(UploadError::ChecksumMismatch));
+    }
+
+    #[test]
+    fn rejects_oversized_chunks_and_overflow() {
+        let image = image();
+        let mut upload = start(&image, ImageChecksum::of_crc32(&image));
+        let now = Instant::now();
+        assert!(matches!(
+            upload.accept(0, &[0; 257], now),
+            Err(UploadError::ChunkTooLarge { .. })
+        ));
+        assert!(matches!(
+            upload.accept(900, &[0; 200], now),
+            Err(UploadError::Overflow { .. })
+        ));
+    }
+
+    fn report() -> PendingReport {
+        PendingReport {
+            records: Readings::legacy(1, 2, 3).records(),
+            image_size: 1000,
+            captured_at: std::time::SystemTime::now(),
+        }
+    }
+
+    #[test]
+    fn parked_upload_resumes_at_offset() {
+        let image = image();
+        let config = UploadConfig::default();
+        let store = UploadStore::new(&config);
+        let now = Instant::now();
+        let mut upload = start(&image, ImageChecksum::of_crc32(&image));
+        upload.accept(0, &image[..256], now).unwrap();
+        store.park(['d'; 64], upload, report(), now);
+
+        assert!(store.take_latest(['e'; 64], now).is_none());
+        let (upload, report) = store.take_latest(['d'; 64], now).unwrap();
+        assert_eq!(upload.id(), 1);
+        assert_eq!(upload.offset(), 256);
+        assert_eq!(report.records, Readings::legacy(1, 2, 3).records());
+        assert!(store.take(['d'; 64], 1, now).is_none());
+    }
+
+    #[test]
+    fn parked_upload_expires() {
+        let image = image();
+        let config = UploadConfig::default();
+        let store = UploadStore::new(&config);
+        let now = Instant::now();
+        store.park(
+            ['d'; 64],
+            start(&image, ImageChecksum::of_crc32(&image)),
+            report(),
+            now,
+        );
+        assert!(store
+            .take(['d'; 64], 1, now + config.resume_timeout)
+            .is_none());
+    }
+
+    #[test]
+    fn stalls_after_timeout() {
+        let image = image();
+        let upload = start(&image, ImageChecksum::of_crc32(&image));
+        let config = UploadConfig::default();
+        let now = Instant::now();
+        assert!(!upload.is_stalled(now, &config));
+        assert!(upload.is_stalled(now + config.stall_timeout, &config));
+    }
+}
@@ -0,0 +1,413 @@
+use std::{
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use axum::{
+    extract::ws::{Message, WebSocket, WebSocketUpgrade},
+    response::Response,
+};
+use bytes::BytesMut;
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use tokio_util::codec::{Decoder, Encoder};
+
+use super::{
+    codec::{Frame, PacketCodec, MAX_FRAME_LENGTH},
+    listener::DeviceGateway,
+    packet::{PacketError, PacketHeader},
+};
+
+/// Carries the device protocol over a WebSocket, one frame per binary message
+///
+/// Every message holds the usual 8 byte [`PacketHeader`] and payload, so the
+/// bytes are the same as on the TCP listener. Pings are answered by axum and
+/// text messages end the session.
+pub struct WebSocketTransport {
+    socket: WebSocket,
+    codec: PacketCodec,
+}
+
+impl WebSocketTransport {
+    pub fn new(socket: WebSocket) -> Self {
+        Self {
+            socket,
+            codec: PacketCodec::new(),
+        }
+    }
+
+    fn decode(&mut self, message: Vec<u8>) -> Result<Frame, PacketError> {
+        let mut buffer = BytesMut::from(&message[..]);
+        let frame = self
+            .codec
+            .decode_eof(&mut buffer)?
+            .ok_or(PacketError::UnexpectedEof)?;
+        if !buffer.is_empty() {
+            // More than one packet in a single message
+            return Err(PacketError::InvalidPacketLength);
+        }
+        Ok(frame)
+    }
+}
+
+fn socket_error(error: axum::Error) -> PacketError {
+    PacketError::Io(io::Error::other(error))
+}
+
+impl Stream for WebSocketTransport {
+    type Item = Result<Frame, PacketError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            let message = match ready!(self.socket.poll_next_unpin(cx)) {
+                Some(Ok(message)) => message,
+                Some(Err(error)) => return Poll::Ready(Some(Err(socket_error(error)))),
+                None => return Poll::Ready(None),
+            };
+            return Poll::Ready(match message {
+                Message::Binary(message) => Some(self.decode(message)),
+                Message::Text(_) => Some(Err(PacketError::Io(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Devices must send binary messages",
+                )))),
+                Message::Close(_) => None,
+                Message::Ping(_) | Message::Pong(_) => continue,
+            });
+        }
+    }
+}
+
+impl Sink<Frame> for WebSocketTransport {
+    type Error = PacketError;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), PacketError>> {
+        self.socket.poll_ready_unpin(cx).map_err(socket_error)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, frame: Frame) -> Result<(), PacketError> {
+        let mut buffer = BytesMut::new();
+        self.codec.encode(frame, &mut buffer)?;
+        self.socket
+            .start_send_unpin(Message::Binary(buffer.to_vec()))
+            .map_err(socket_error)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), PacketError>> {
+        self.socket.poll_flush_unpin(cx).map_err(socket_error)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), PacketError>> {
+        self.socket.poll_close_unpin(cx).map_err(socket_error)
+    }
+}
+
+impl DeviceGateway {
+    /// Accept a WebSocket upgrade and run the device session over it
+    pub fn upgrade(self, upgrade: WebSocketUpgrade, addr: SocketAddr) -> Response {
+        upgrade
+            .max_message_size(PacketHeader::SIZE + MAX_FRAME_LENGTH as usize)
+            .on_upgrade(move |socket| async move {
+                println!("Incoming WebSocket connection {addr}");
+                self.serve(WebSocketTransport::new(socket), addr).await
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use axum::{extract::ConnectInfo, routing::get, Router};
+    use tokio::{
+        net::TcpListener,
+        sync::mpsc::{channel, Receiver, Sender},
+    };
+    use tokio_tungstenite::{connect_async, tungstenite, MaybeTlsStream, WebSocketStream};
+
+    use crate::service::{
+        db_service::{DBServiceError, DBServiceRequest, DBServiceResponse},
+        farm_service::{
+            auth,
+            client::{ClientPacket, ClientPacketId},
+            clock::unix_millis,
+            command::CommandOutcome,
+            handshake::PROTOCOL_VERSIONS,
+            packet::PacketId,
+            sensors::{CentiCelsius, CentiPercent, Readings},
+            server::{ServerPacket, ServerPacketId},
+            session::SessionConfig,
+            telemetry::{SensorRecord, SensorType, Unit},
+            ClientReceiverCommand,
+        },
+        ServiceHandle,
+    };
+
+    use super::*;
+
+    const DEVICE_ID: [char; 64] = ['w'; 64];
+    const DEVICE_SECRET: [char; 64] = ['s'; 64];
+
+    type Socket = WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>;
+
+    async fn start() -> (SocketAddr, Receiver<ClientReceiverCommand>) {
+        let (sender, receiver) = channel(16);
+        let (db_sender, mut db_receiver) = channel::<crate::service::ServiceRequest<_, _>>(16);
+        tokio::spawn(async move {
+            while let Some(request) = db_receiver.recv().await {
+                let response = match request.data {
+                    DBServiceRequest::GetDeviceSecret { id } if id == DEVICE_ID => {
+                        Ok(DBServiceResponse::DeviceSecret(DEVICE_SECRET))
+                    }
+                    _ => Err(DBServiceError::UnregisterdDevice),
+                };
+                request.result_sender.send(response).await.ok();
+            }
+        });
+        let gateway = DeviceGateway::new(
+            sender,
+            ServiceHandle::new(db_sender),
+            SessionConfig::default(),
+        );
+        let router = Router::new().route(
+            "/device/ws",
+            get(
+                move |upgrade: WebSocketUpgrade, ConnectInfo(addr): ConnectInfo<SocketAddr>| async move {
+                    gateway.upgrade(upgrade, addr)
+                },
+            ),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            axum::serve(
+                listener,
+                router.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .await
+            .unwrap()
+        });
+        (addr, receiver)
+    }
+
+    async fn send(socket: &mut Socket, packet: ClientPacket) {
+        let frame = Frame::from_packet::<ClientPacketId>(packet).unwrap();
+        let mut buffer = BytesMut::new();
+        PacketCodec::new().encode(frame, &mut buffer).unwrap();
+        socket
+            .send(tungstenite::Message::binary(buffer.to_vec()))
+            .await
+            .unwrap();
+    }
+
+    async fn receive(socket: &mut Socket) -> ServerPacket {
+        let message = socket.next().await.unwrap().unwrap().into_data();
+        let mut buffer = BytesMut::from(&message[..]);
+        let mut frame = PacketCodec::new().decode(&mut buffer).unwrap().unwrap();
+        assert!(buffer.is_empty(), "one packet per message");
+        frame.decode::<ServerPacketId>().unwrap()
+    }
+
+    /// Handshake and authenticate as `DEVICE_ID`, returning the sender the service got
+    async fn authenticate(
+        socket: &mut Socket,
+        receiver: &mut Receiver<ClientReceiverCommand>,
+        max_protocol_version: u16,
+    ) -> Sender<ServerPacket> {
+        send(
+            socket,
+            ClientPacket::Hello {
+                min_protocol_version: *PROTOCOL_VERSIONS.start(),
+                max_protocol_version,
+                firmware_version: "test".to_string(),
+                capabilities: 0,
+            },
+        )
+        .await;
+        assert!(matches!(
+            receive(socket).await,
+            ServerPacket::Welcome { .. }
+        ));
+        send(socket, ClientPacket::ReportId { id: DEVICE_ID }).await;
+        let ServerPacket::AuthChallenge { nonce } = receive(socket).await else {
+            panic!("expected a challenge");
+        };
+        let response = auth::sign(&DEVICE_SECRET, &nonce, &DEVICE_ID);
+        send(socket, ClientPacket::AuthResponse { response }).await;
+        assert_eq!(receive(socket).await, ServerPacket::Authenticated);
+        if max_protocol_version >= ServerPacketId::TimeSync.since_version() {
+            assert!(matches!(
+                receive(socket).await,
+                ServerPacket::TimeSync { .. }
+            ));
+        }
+        match receiver.recv().await {
+            Some(ClientReceiverCommand::ReportClient {
+                id: DEVICE_ID,
+                sender,
+                ..
+            }) => sender,
+            _ => panic!("expected the device to be reported"),
+        }
+    }
+
+    #[tokio::test]
+    async fn device_reports_over_websocket() {
+        let (addr, mut receiver) = start().await;
+        let (mut socket, _) = connect_async(format!("ws://{addr}/device/ws"))
+            .await
+            .unwrap();
+        authenticate(&mut socket, &mut receiver, *PROTOCOL_VERSIONS.end()).await;
+
+        let readings = Readings {
+            air_temperature: CentiCelsius(-1250),
+            humidity: Some(CentiPercent(6420)),
+            ..Readings::legacy(1, 0, 3)
+        };
+        send(
+            &mut socket,
+            ClientPacket::ReportReadings {
+                captured_at: unix_millis(SystemTime::now()),
+                readings,
+                image_size: 4,
+            },
+        )
+        .await;
+        send(
+            &mut socket,
+            ClientPacket::ImageChunk {
+                data: vec![9, 8, 7, 6],
+            },
+        )
+        .await;
+        match receiver.recv().await {
+            Some(ClientReceiverCommand::ReportSensors {
+                id, records, image, ..
+            }) => {
+                assert_eq!(id, DEVICE_ID);
+                assert_eq!(records, readings.records());
+                assert_eq!(image, vec![9, 8, 7, 6]);
+            }
+            _ => panic!("expected a sensor report"),
+        }
+    }
+
+    #[tokio::test]
+    async fn reports_without_an_image_are_submitted_at_once() {
+        let (addr, mut receiver) = start().await;
+        let (mut socket, _) = connect_async(format!("ws://{addr}/device/ws"))
+            .await
+            .unwrap();
+        authenticate(&mut socket, &mut receiver, *PROTOCOL_VERSIONS.end()).await;
+
+        let records = vec![SensorRecord {
+            sensor: SensorType::AIR_TEMPERATURE,
+            index: 0,
+            unit: Unit::CentiCelsius,
+            value: 2150,
+        }];
+        send(
+            &mut socket,
+            ClientPacket::ReportTelemetry {
+                captured_at: unix_millis(SystemTime::now()),
+                records: records.clone(),
+                image_size: 0,
+            },
+        )
+        .await;
+        match receiver.recv().await {
+            Some(ClientReceiverCommand::ReportSensors {
+                id,
+                records: reported,
+                image,
+                ..
+            }) => {
+                assert_eq!(id, DEVICE_ID);
+                assert_eq!(reported, records);
+                assert!(image.is_empty());
+            }
+            _ => panic!("expected a sensor report"),
+        }
+    }
+
+    #[tokio::test]
+    async fn maintenance_commands_need_protocol_6() {
+        let (addr, mut receiver) = start().await;
+        let (mut socket, _) = connect_async(format!("ws://{addr}/device/ws"))
+            .await
+            .unwrap();
+        let sender = authenticate(&mut socket, &mut receiver, 5).await;
+
+        sender
+            .send(ServerPacket::Reboot { sequence: 3 })
+            .await
+            .unwrap();
+        match receiver.recv().await {
+            Some(ClientReceiverCommand::CommandResult {
+                sequence: 3,
+                outcome: CommandOutcome::Rejected { reason },
+                ..
+            }) => assert!(reason.contains('6'), "{reason}"),
+            _ => panic!("expected the reboot to be rejected"),
+        }
+    }
+
+    #[tokio::test]
+    async fn skewed_reports_are_flagged_and_resynchronised() {
+        let (addr, mut receiver) = start().await;
+        let (mut socket, _) = connect_async(format!("ws://{addr}/device/ws"))
+            .await
+            .unwrap();
+        authenticate(&mut socket, &mut receiver, *PROTOCOL_VERSIONS.end()).await;
+
+        let now = SystemTime::now();
+        send(
+            &mut socket,
+            ClientPacket::ReportSensorsAt {
+                captured_at: unix_millis(now + Duration::from_secs(60 * 60)),
+                soil_moisture: 1,
+                air_temperature: 2,
+                light_sensor: 3,
+                image_size: 0,
+            },
+        )
+        .await;
+        assert!(matches!(
+            receiver.recv().await,
+            Some(ClientReceiverCommand::ClockSkew { millis, .. }) if millis > 59 * 60 * 1000
+        ));
+        let ServerPacket::TimeSync {
+            unix_millis: synced,
+        } = receive(&mut socket).await
+        else {
+            panic!("expected the time again");
+        };
+        assert!(synced >= unix_millis(now));
+
+        match receiver.recv().await {
+            Some(ClientReceiverCommand::ReportSensors { captured_at, .. }) => {
+                // Stamped on arrival instead of with the skewed device clock
+                assert!(captured_at < now + Duration::from_secs(60));
+            }
+            _ => panic!("expected a sensor report"),
+        }
+    }
+
+    #[tokio::test]
+    async fn text_messages_end_the_session() {
+        let (addr, _receiver) = start().await;
+        let (mut socket, _) = connect_async(format!("ws://{addr}/device/ws"))
+            .await
+            .unwrap();
+        socket
+            .send(tungstenite::Message::text("hello"))
+            .await
+            .unwrap();
+        loop {
+            match socket.next().await {
+                Some(Ok(tungstenite::Message::Close(_))) | None | Some(Err(_)) => break,
+                Some(Ok(_)) => continue,
+            }
+        }
+    }
+}
@@ -28,5 +28,5 @@ pub async fn get_google_info(
         .await
         .ok()?;
     let json = response.bytes().await.ok()?;
-    return Json::<GoogleUserInfo>::from_bytes(&json).ok().map(|e| e.0);
+    Json::<GoogleUserInfo>::from_bytes(&json).ok().map(|e| e.0)
 }
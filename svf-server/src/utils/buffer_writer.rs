@@ -1,5 +1,3 @@
-use std::u16;
-
 #[derive(Debug)]
 pub struct BufferWriter<'a> {
     buffer: &'a mut Vec<u8>,
@@ -4,6 +4,12 @@ pub struct WaitPool {
     pool: Vec<JoinHandle<()>>,
 }
 
+impl Default for WaitPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl WaitPool {
     pub fn new() -> Self {
         Self { pool: Vec::new() }
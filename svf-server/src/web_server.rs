@@ -1,45 +1,71 @@
-use std::{convert::Infallible, env, net::SocketAddr, path::PathBuf, sync::Arc};
+use std::{net::SocketAddr, sync::Arc};
 
-use axum::{serve::IncomingStream, Json, Router};
+use axum::Router;
 use axum_server::tls_rustls::RustlsConfig;
-use local_ip_address::local_ip;
 use serde::{Deserialize, Serialize};
 use tokio::net::TcpListener;
 
-use crate::{is_production, wait_pool::WaitPool, ServiceHandles};
+use crate::{
+    config::Config,
+    service::farm_service::{FirmwareInfo, RolloutStatus, RuleTrace},
+    wait_pool::WaitPool,
+    ServiceHandles,
+};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum BackendResponse {
     Ok,
     AccessToken(String),
     PasswordChallenge(String),
-    DeviceId(String),
+    Device { id: String, secret: String },
+    Firmware(Vec<FirmwareInfo>),
+    Rollout(RolloutStatus),
+    Rules(Vec<RuleTrace>),
     Error(String),
 }
 
+/// Certificate and key shared by the web server and the device listener
+pub async fn tls_config(config: &Config) -> RustlsConfig {
+    let (cert, key) = config
+        .tls_paths()
+        .expect("No certificate or key configured");
+    RustlsConfig::from_pem_chain_file(cert, key)
+        .await
+        .expect("Invalid certs or key")
+}
+
 pub fn serve(
     router: Router<Arc<ServiceHandles>>,
     handles: ServiceHandles,
+    config: &Config,
     wait_pool: &mut WaitPool,
 ) {
-    wait_pool.add(tokio::spawn(async {
-        if is_production() {
-            let config = RustlsConfig::from_pem_chain_file(
-                PathBuf::from(env::var("CERT_PATH").expect("No cert var provided")),
-                PathBuf::from(env::var("KEY_PATH").expect("No key var provided")),
-            )
-            .await
-            .expect("Invalid certs or key");
-            let addr = SocketAddr::new(local_ip().expect("Cannot get local ip"), 443);
-            axum_server::bind_rustls(addr, config)
-                .serve(router.with_state(Arc::new(handles)).into_make_service())
+    let addr = config.web_listen();
+    let config = config.clone();
+    wait_pool.add(tokio::spawn(async move {
+        if config.production {
+            let tls = handles
+                .tls
+                .clone()
+                .expect("TLS is loaded by init_services in production");
+            axum_server::bind_rustls(addr, tls)
+                .serve(
+                    router
+                        .with_state(Arc::new(handles))
+                        .into_make_service_with_connect_info::<SocketAddr>(),
+                )
                 .await
                 .unwrap();
         } else {
-            let listener = TcpListener::bind("127.0.0.1:3000").await.unwrap();
-            axum::serve(listener, router.with_state(Arc::new(handles)))
-                .await
-                .unwrap();
+            let listener = TcpListener::bind(addr).await.unwrap();
+            axum::serve(
+                listener,
+                router
+                    .with_state(Arc::new(handles))
+                    .into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .await
+            .unwrap();
         }
     }));
 }
@@ -155,11 +155,15 @@ export async function request_berry(server_addr: string): Promise<string | null>
           }),
           credentials: 'include',
         });
-        const data: { DeviceId: string } = await res.json();
-        rdata = data.DeviceId;
+        const data: { Device: { id: string, secret: string } } = await res.json();
+        rdata = data.Device.id;
+        await send_request(request_characteristic, {
+          request: "device-secret-set",
+          device_secret: data.Device.secret,
+        });
         await send_request(request_characteristic, {
           request: "device-set",
-          device_id: data.DeviceId,
+          device_id: data.Device.id,
         });
       } else {
         await send_request(request_characteristic, {